@@ -0,0 +1,60 @@
+//! A bot that joins some groups, logs everything it sees to standard
+//! output, and answers a few commands:
+//!
+//!     cargo run --example echo_bot -- 127.0.0.1:8088 echo Rust Gophers
+//!
+//! Then, in one of those groups, post `!echo hello` or `!help`.
+
+use async_chat::client::{ChatClient, Dispatcher};
+use async_chat::utils::ChatResult;
+use async_chat::FromServer;
+use async_std::task;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "Usage: echo_bot ADDRESS:PORT NICKNAME GROUP...";
+
+fn main() -> ChatResult<()> {
+  let mut args = std::env::args().skip(1);
+  let (address, nickname) = match (args.next(), args.next()) {
+    (Some(address), Some(nickname)) => (address, nickname),
+    _ => return Err(USAGE.into()),
+  };
+  let groups: Vec<String> = args.collect();
+  if groups.is_empty() {
+    return Err(USAGE.into());
+  }
+
+  let commands = Dispatcher::new("!")
+    .command("echo", "repeat what you say", |call| {
+      Some(format!("{} said: {}", call.sender, call.args))
+    })
+    .command("time", "tell the time, in seconds since 1970", |_call| {
+      let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+      Some(now.as_secs().to_string())
+    });
+
+  task::block_on(async {
+    let mut client = ChatClient::connect(&address, &nickname).await?;
+    for group in &groups {
+      client.join(group).await?;
+    }
+    println!("{} joined {}", nickname, groups.join(", "));
+
+    commands.run(&mut client, log).await?;
+    println!("server closed the connection");
+    Ok(())
+  })
+}
+
+/// Print `packet` on a line of its own.
+fn log(packet: &FromServer) {
+  match packet {
+    FromServer::Message { group_name, sender, message, .. } => {
+      println!("[{}] {}: {}", group_name, sender, message);
+    }
+    FromServer::DirectMessage { from, message, .. } => {
+      println!("[{} to us] {}", from, message);
+    }
+    other => println!("{:?}", other),
+  }
+}
@@ -0,0 +1,757 @@
+use async_std::prelude::*;
+use async_chat::codec::Codec;
+use async_chat::tls;
+use async_chat::utils::ChatResult;
+use async_std::io;
+use async_std::net;
+use async_chat::{FromClient, FromServer, Replay, Request, Role};
+use async_std::task;
+use async_std::channel;
+use futures_rustls::TlsConnector;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+mod tui;
+
+/// The commands the user can type, one per line.
+const COMMANDS: &str = "\
+  join GROUP [last N | since UNIX_TIME]
+  post GROUP MESSAGE...
+  edit GROUP ID MESSAGE...
+  delete GROUP ID
+  react GROUP ID REACTION
+  leave GROUP
+  typing GROUP
+  msg NICKNAME MESSAGE...
+  groups
+  members GROUP
+  search GROUP WORDS...
+  grant GROUP NICKNAME|* owner|member|read-only|none
+";
+
+/// Read commands from the standard input and pass them along on `commands`,
+/// until the user closes it.
+async fn read_commands(commands: channel::Sender<FromClient>) -> ChatResult<()> {
+  println!("Commands:\n{}\
+            Type Control-D (on Unix) or Control-Z (on Windows) \
+            to close the connection.", COMMANDS);
+
+  let mut command_lines = io::BufReader::new(io::stdin()).lines();
+  while let Some(command_result) = command_lines.next().await {
+    let command = command_result?;
+    let request = match parse_command(&command) {
+      Ok(request) => request,
+      Err(message) => {
+        eprintln!("{}", message);
+        continue;
+      }
+    };
+    if commands.send(request).await.is_err() {
+      break;
+    }
+  }
+  Ok(())
+}
+
+/// Send the user's commands to the server, starting with any left over
+/// from earlier connections, until the user closes the standard input.
+async fn send_commands<W>(mut to_server: W,
+                          session: &Mutex<&mut Session>,
+                          commands: &channel::Receiver<FromClient>)
+  -> ChatResult<Ended>
+where
+  W: io::Write + Unpin,
+{
+  let codec = session.lock().unwrap().codec;
+  loop {
+    let unsent = session.lock().unwrap().unsent.pop_front();
+    let body = match unsent {
+      Some(body) => body,
+      None => match commands.recv().await {
+        Ok(body) => body,
+        Err(_closed) => break,
+      },
+    };
+
+    // Count the request as awaiting an answer even before it's sent, so
+    // that it will be sent again if the connection drops first.
+    let request = session.lock().unwrap().track(body);
+    codec.send(&mut to_server, &request).await?;
+    to_server.flush().await?;
+  }
+
+  // Over TLS, this tells the server the session ended deliberately.
+  futures_lite::AsyncWriteExt::close(&mut to_server).await?;
+  Ok(Ended::Quit)
+}
+
+async fn handle_replies<R>(from_server: R,
+                           session: &Mutex<&mut Session>,
+                           screen: &Screen)
+  -> ChatResult<()>
+where
+  R: io::Read + Unpin,
+{
+  let codec = session.lock().unwrap().codec;
+  let mut buffered = io::BufReader::new(from_server);
+
+  while let Some(reply) = codec.receive(&mut buffered).await? {
+    match &reply {
+      FromServer::Ack { id } => session.lock().unwrap().answered(*id, true),
+      FromServer::Nack { id, .. } => session.lock().unwrap().answered(*id, false),
+      _ => {}
+    }
+    screen.show(reply);
+  }
+  Ok(())
+}
+
+/// Where the client shows the user what's happening.
+enum Screen {
+  /// Print each packet and status on a line of its own.
+  Lines,
+  /// Pass everything along to the terminal interface.
+  Tui(channel::Sender<tui::Update>),
+}
+
+impl Screen {
+  fn show(&self, packet: FromServer) {
+    match self {
+      Screen::Lines => print_packet(packet),
+      Screen::Tui(sender) => {
+        let _ = sender.try_send(tui::Update::Packet(packet));
+      }
+    }
+  }
+
+  /// Tell the user about the state of the connection.
+  fn status(&self, message: String) {
+    match self {
+      Screen::Lines => eprintln!("{}", message),
+      Screen::Tui(sender) => {
+        let _ = sender.try_send(tui::Update::Status(message));
+      }
+    }
+  }
+}
+
+fn print_packet(packet: FromServer) {
+  match packet {
+    FromServer::Message { group_name, sender, timestamp, message, id } => {
+      println!("[{}] {} in {} ({}): {}",
+               format_time(timestamp), sender, group_name, id, message);
+    }
+    FromServer::Edited { group_name, id, editor, message } => {
+      println!("{} edited {} in {}: {}", editor, id, group_name, message);
+    }
+    FromServer::Deleted { group_name, id, by } => {
+      println!("{} deleted {} in {}", by, id, group_name);
+    }
+    FromServer::Reacted { group_name, id, sender, reaction } => {
+      println!("{} reacted to {} in {}: {}", sender, id, group_name, reaction);
+    }
+    FromServer::Presence { group_name, members } => {
+      println!("in {}: {}", group_name, join_names(&members));
+    }
+    FromServer::Joined { group_name, nickname } => {
+      println!("{} joined {}", nickname, group_name);
+    }
+    FromServer::Left { group_name, nickname } => {
+      println!("{} left {}", nickname, group_name);
+    }
+    FromServer::Typing { group_name, nickname } => {
+      println!("{} is typing in {}", nickname, group_name);
+    }
+    FromServer::DirectMessage { from, timestamp, message } => {
+      println!("[{}] {} to you: {}", format_time(timestamp), from, message);
+    }
+    FromServer::Groups { group_names } => {
+      println!("groups: {}", join_names(&group_names));
+    }
+    FromServer::Members { group_name, members } => {
+      println!("members of {}: {}", group_name, join_names(&members));
+    }
+    FromServer::SearchResults { group_name, results } => {
+      println!("found {} in {}:", results.len(), group_name);
+      for result in results {
+        println!("  [{}] {} ({}): {}", format_time(result.timestamp),
+                 result.sender, result.id, result.message);
+      }
+    }
+    FromServer::Nack { reason: message, .. } |
+    FromServer::Error { message, .. } => {
+      println!("error from server: {}", message);
+    }
+    FromServer::Ack { .. } => {}
+    FromServer::Shutdown => {
+      println!("server is shutting down");
+    }
+  }
+}
+
+/// Join `names` into a comma-separated list.
+fn join_names(names: &[Arc<String>]) -> String {
+  let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+  names.join(", ")
+}
+
+/// Return the current time, in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Format `timestamp`, in seconds since the Unix epoch, as a UTC date and
+/// time like `2021-03-14 15:09:26`.
+fn format_time(timestamp: u64) -> String {
+  let days = (timestamp / 86400) as i64;
+  let seconds = timestamp % 86400;
+
+  // Convert days since 1970-01-01 to a civil date, using Howard Hinnant's
+  // `civil_from_days` algorithm.
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let day_of_era = z.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                     - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+                                  - year_of_era / 100);
+  let shifted_month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+  let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+          year, month, day,
+          seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// How the client identifies itself to the server.
+enum Identity {
+  /// Say hello with a nickname, on servers without accounts.
+  Hello,
+  /// Log in to an account with this password.
+  Login(String),
+  /// Create an account with this password, and then log in to it.
+  Register(String),
+}
+
+/// The first delay before trying to reconnect to the server. Each failed
+/// attempt doubles it, up to `MAX_RECONNECT_DELAY`.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// What the client remembers from one connection to the next.
+struct Session {
+  nickname: Arc<String>,
+  identity: Identity,
+  codec: Codec,
+  /// The ID to give the next request we send.
+  next_id: u64,
+  /// The groups the server has confirmed we joined, to rejoin after
+  /// reconnecting.
+  groups: BTreeSet<Arc<String>>,
+  /// Commands the user has typed that we haven't yet sent.
+  unsent: VecDeque<FromClient>,
+  /// Commands we have sent that the server hasn't yet answered, by request
+  /// ID. If the connection drops, we send these again, so the server may
+  /// see a command twice, but never miss one. The server can't tell a
+  /// resent request from a new one, so a post it received just before the
+  /// connection dropped will be delivered twice.
+  awaiting: BTreeMap<u64, FromClient>,
+}
+
+/// Why a connection to the server ended.
+enum Ended {
+  /// The user closed the standard input, or quit the terminal interface.
+  Quit,
+  /// The server closed the connection.
+  Disconnected,
+}
+
+impl Session {
+  fn request(&mut self, body: FromClient) -> Request {
+    self.next_id += 1;
+    Request { id: self.next_id, body }
+  }
+
+  /// Return `body` as a request, noting that it awaits an answer.
+  fn track(&mut self, body: FromClient) -> Request {
+    let request = self.request(body);
+    self.awaiting.insert(request.id, request.body.clone());
+    request
+  }
+
+  /// Note the server's answer to the request `id`: `accepted` if it sent
+  /// `Ack`, and not if it sent `Nack`.
+  fn answered(&mut self, id: u64, accepted: bool) {
+    match self.awaiting.remove(&id) {
+      Some(FromClient::Join { group_name, .. }) if accepted => {
+        self.groups.insert(group_name);
+      }
+      Some(FromClient::Leave { group_name }) if accepted => {
+        self.groups.remove(&group_name);
+      }
+      _ => {}
+    }
+  }
+
+  /// Return the requests that identify us to the server, to begin a new
+  /// connection. Queue up joins for our groups, and anything left
+  /// unanswered on the last connection, to follow them.
+  fn greeting(&mut self) -> Vec<Request> {
+    // Don't resend a join for a group we're rejoining anyway, or the server
+    // will refuse the second one.
+    let groups = std::mem::take(&mut self.groups);
+    let unanswered = std::mem::take(&mut self.awaiting)
+      .into_values()
+      .filter(|body| match body {
+        FromClient::Join { group_name, .. } => !groups.contains(group_name),
+        _ => true,
+      });
+    let resends: Vec<FromClient> = groups.iter()
+      .map(|group_name| FromClient::Join {
+        group_name: group_name.clone(),
+        replay: None,
+      })
+      .chain(unanswered)
+      .collect();
+    for body in resends.into_iter().rev() {
+      self.unsent.push_front(body);
+    }
+
+    let nickname = self.nickname.clone();
+    let bodies = match &self.identity {
+      Identity::Hello => vec![FromClient::Hello { nickname }],
+      Identity::Login(password) => {
+        vec![FromClient::Login { nickname, password: password.clone() }]
+      }
+      Identity::Register(password) => vec![
+        FromClient::Register { nickname: nickname.clone(),
+                               password: password.clone() },
+        FromClient::Login { nickname, password: password.clone() },
+      ],
+    };
+
+    // Only register once; after that, the account exists.
+    if let Identity::Register(password) = &self.identity {
+      self.identity = Identity::Login(password.clone());
+    }
+
+    bodies.into_iter().map(|body| self.request(body)).collect()
+  }
+}
+
+/// Identify ourselves over `socket`, rejoin our groups, and then carry on a
+/// chat session until either side closes the connection.
+async fn chat<S>(socket: S,
+                 session: &mut Session,
+                 commands: &channel::Receiver<FromClient>,
+                 screen: &Screen)
+  -> ChatResult<Ended>
+where
+  S: io::Read + io::Write + Unpin,
+{
+  let (from_server, mut to_server) = futures_lite::io::split(socket);
+  let codec = session.codec;
+
+  codec.announce(&mut to_server).await?;
+  for request in session.greeting() {
+    codec.send(&mut to_server, &request).await?;
+  }
+
+  // Sending commands and handling replies both update the session, but
+  // neither holds the lock across an `await`.
+  let session = Mutex::new(session);
+  let to_server = send_commands(to_server, &session, commands);
+  let from_server = async {
+    handle_replies(from_server, &session, screen).await?;
+    Ok(Ended::Disconnected)
+  };
+
+  // Once we're through, any error just means we've lost the connection.
+  match from_server.race(to_server).await {
+    Err(error) => {
+      screen.status(format!("Error: {}", error));
+      Ok(Ended::Disconnected)
+    }
+    ended => ended,
+  }
+}
+
+/// Connect to the server at `address`, using TLS if `connector` is given,
+/// and chat until either side closes the connection.
+async fn connect_and_chat(address: &str,
+                          connector: Option<&TlsConnector>,
+                          session: &mut Session,
+                          commands: &channel::Receiver<FromClient>,
+                          screen: &Screen)
+  -> ChatResult<Ended>
+{
+  let socket = net::TcpStream::connect(address).await?;
+  socket.set_nodelay(true)?;
+
+  match connector {
+    Some(connector) => {
+      let stream = connector.connect(tls::server_name(address)?, socket)
+        .await?;
+      chat(stream, session, commands, screen).await
+    }
+    None => chat(socket, session, commands, screen).await,
+  }
+}
+
+/// Wait `delay` before reconnecting, holding on to any commands the user
+/// types meanwhile. Return false if the user closes the standard input.
+async fn wait_to_reconnect(delay: Duration,
+                           session: &mut Session,
+                           commands: &channel::Receiver<FromClient>)
+  -> bool
+{
+  let deadline = Instant::now() + delay;
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    match commands.recv().timeout(remaining).await {
+      Ok(Ok(request)) => session.unsent.push_back(request),
+      Ok(Err(_closed)) => return false,
+      Err(_timed_out) => return true,
+    }
+  }
+}
+
+const USAGE: &str = "Usage: client ADDRESS:PORT NICKNAME [--tls-ca CA_FILE] \
+                     [--codec json|messagepack] [--login | --register] \
+                     [--tui]";
+
+/// Prompt for a password, and read it from the first line of standard input.
+fn read_password() -> ChatResult<String> {
+  use std::io::Write;
+
+  eprint!("Password: ");
+  std::io::stderr().flush()?;
+  let mut password = String::new();
+  std::io::stdin().read_line(&mut password)?;
+  Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn main() -> ChatResult<()> {
+  let mut positional = vec![];
+  let mut ca_file = None;
+  let mut codec = Codec::Json;
+  let (mut login, mut register, mut full_screen) = (false, false, false);
+
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--tls-ca" {
+      ca_file = Some(PathBuf::from(args.next().expect(USAGE)));
+    } else if arg == "--codec" {
+      codec = args.next().expect(USAGE).parse()?;
+    } else if arg == "--login" {
+      login = true;
+    } else if arg == "--register" {
+      register = true;
+    } else if arg == "--tui" {
+      full_screen = true;
+    } else {
+      positional.push(arg);
+    }
+  }
+
+  let (address, nickname) = match <[String; 2]>::try_from(positional) {
+    Ok([address, nickname]) => (address, nickname),
+    Err(_) => panic!("{}", USAGE),
+  };
+  let identity = if register {
+    Identity::Register(read_password()?)
+  } else if login {
+    Identity::Login(read_password()?)
+  } else {
+    Identity::Hello
+  };
+
+  let connector = match ca_file {
+    Some(ca_file) => Some(tls::connector(&ca_file)?),
+    None => None,
+  };
+  let nickname = Arc::new(nickname);
+  let mut session = Session {
+    nickname: nickname.clone(),
+    identity,
+    codec,
+    next_id: 0,
+    groups: BTreeSet::new(),
+    unsent: VecDeque::new(),
+    awaiting: BTreeMap::new(),
+  };
+
+  // Either way, the user's commands arrive on `commands`. The terminal
+  // interface runs on a thread of its own, since crossterm blocks waiting
+  // for input.
+  let (sender, commands) = channel::unbounded();
+  let (screen, interface) = if full_screen {
+    let (updates, received) = channel::unbounded();
+    let interface = std::thread::spawn(move || {
+      tui::run(nickname, sender, received)
+    });
+    (Screen::Tui(updates), Some(interface))
+  } else {
+    task::spawn(async {
+      if let Err(error) = read_commands(sender).await {
+        eprintln!("Error reading commands: {}", error);
+      }
+    });
+    (Screen::Lines, None)
+  };
+
+  task::block_on(async {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+    loop {
+      let ended = connect_and_chat(&address, connector.as_ref(), &mut session,
+                                   &commands, &screen).await;
+      match ended {
+        Ok(Ended::Quit) => return,
+        Ok(Ended::Disconnected) => {
+          // We did get through, so start over with short delays.
+          delay = INITIAL_RECONNECT_DELAY;
+          screen.status("Disconnected from server".to_string());
+        }
+        Err(error) => {
+          screen.status(format!("Unable to connect to server: {}", error));
+        }
+      }
+
+      screen.status(format!("Reconnecting in {:?}; commands typed meanwhile \
+                             will be sent then", delay));
+      if !wait_to_reconnect(delay, &mut session, &commands).await {
+        if !session.unsent.is_empty() {
+          eprintln!("Discarding {} unsent commands", session.unsent.len());
+        }
+        return;
+      }
+      delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+  });
+
+  if let Some(interface) = interface {
+    interface.join().expect("terminal interface panicked")?;
+  }
+  Ok(())
+}
+
+/// How many matches to ask for when searching a group.
+const SEARCH_LIMIT: usize = 20;
+
+/// Parse `line` as one of the `COMMANDS`. If it isn't one, return a message
+/// saying what's wrong, for the user.
+fn parse_command(line: &str) -> Result<FromClient, String> {
+    let (command, rest) = get_next_token(line)
+        .ok_or_else(|| "Type a command".to_string())?;
+    let usage = COMMANDS.lines()
+        .map(str::trim)
+        .find(|usage| usage.split_whitespace().next() == Some(command))
+        .ok_or_else(|| format!("Unrecognized command: {:?}", command))?;
+    parse_arguments(command, rest)
+        .ok_or_else(|| format!("Usage: {}", usage))
+}
+
+/// Parse `rest` as the arguments to `command`.
+fn parse_arguments(command: &str, rest: &str) -> Option<FromClient> {
+    if command == "post" {
+        let (group, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Post {
+            group_name: Arc::new(group.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "edit" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Edit {
+            group_name: Arc::new(group.to_string()),
+            id: Arc::new(id.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "delete" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Delete {
+            group_name: Arc::new(group.to_string()),
+            id: Arc::new(id.to_string()),
+        })
+    } else if command == "react" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        let (reaction, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::React {
+            group_name: Arc::new(group.to_string()),
+            id: Arc::new(id.to_string()),
+            reaction: Arc::new(reaction.to_string()),
+        })
+    } else if command == "join" {
+        let (group, rest) = get_next_token(rest)?;
+        let replay = parse_replay(rest)?;
+        Some(FromClient::Join {
+            group_name: Arc::new(group.to_string()),
+            replay,
+        })
+    } else if command == "msg" {
+        let (to, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::DirectMessage {
+            to: Arc::new(to.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "leave" {
+        let group_name = parse_group_only(rest)?;
+        Some(FromClient::Leave { group_name })
+    } else if command == "typing" {
+        let group_name = parse_group_only(rest)?;
+        Some(FromClient::Typing { group_name })
+    } else if command == "groups" {
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::ListGroups)
+    } else if command == "members" {
+        let group_name = parse_group_only(rest)?;
+        Some(FromClient::ListMembers { group_name })
+    } else if command == "search" {
+        let (group, rest) = get_next_token(rest)?;
+        let query = rest.trim();
+        if query.is_empty() {
+            return None;
+        }
+        Some(FromClient::Search {
+            group_name: Arc::new(group.to_string()),
+            query: Arc::new(query.to_string()),
+            limit: SEARCH_LIMIT,
+        })
+    } else if command == "grant" {
+        let (group, rest) = get_next_token(rest)?;
+        let (nickname, rest) = get_next_token(rest)?;
+        let (role, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        let nickname = match nickname {
+            "*" => None,
+            nickname => Some(Arc::new(nickname.to_string())),
+        };
+        let role = match role {
+            "owner" => Some(Role::Owner),
+            "member" => Some(Role::Member),
+            "read-only" => Some(Role::ReadOnly),
+            "none" => None,
+            _ => return None,
+        };
+        Some(FromClient::SetRole {
+            group_name: Arc::new(group.to_string()),
+            nickname,
+            role,
+        })
+    } else {
+        None
+    }
+}
+
+/// Parse `input` as a lone group name.
+fn parse_group_only(input: &str) -> Option<Arc<String>> {
+    let (group, rest) = get_next_token(input)?;
+    if !rest.trim_start().is_empty() {
+        return None;
+    }
+    Some(Arc::new(group.to_string()))
+}
+
+/// Parse the optional history request following `join GROUP`. Return
+/// `Some(None)` if there is none, and `None` if it is malformed.
+fn parse_replay(input: &str) -> Option<Option<Replay>> {
+    let (kind, rest) = match get_next_token(input) {
+        Some(token) => token,
+        None => return Some(None),
+    };
+    let (count, rest) = get_next_token(rest)?;
+    if !rest.trim_start().is_empty() {
+        return None;
+    }
+
+    let replay = match kind {
+        "last" => Replay::LastN(count.parse().ok()?),
+        "since" => Replay::Since(count.parse().ok()?),
+        _ => return None,
+    };
+    Some(Some(replay))
+}
+
+/// Given a string `input`, return `Some((token, rest))`, where `token` is the
+/// first run of non-whitespace characters in `input`, and `rest` is the rest of
+/// the string. If the string contains no non-whitespace characters, return
+/// `None`.
+fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+  input = input.trim_start();
+
+  if input.is_empty() {
+      return None;
+  }
+
+  match input.find(char::is_whitespace) {
+      Some(space) => Some((&input[0..space], &input[space..])),
+      None => Some((input, "")),
+  }
+}
+
+#[test]
+fn test_greeting_rejoins_each_group_once() {
+  let name = |name: &str| Arc::new(name.to_string());
+  let mut session = Session {
+    nickname: name("ann"),
+    identity: Identity::Hello,
+    codec: Codec::Json,
+    next_id: 0,
+    groups: BTreeSet::from([name("Crabs")]),
+    unsent: VecDeque::new(),
+    awaiting: BTreeMap::new(),
+  };
+  session.track(FromClient::Join { group_name: name("Crabs"), replay: None });
+  session.track(FromClient::Join { group_name: name("Eels"), replay: None });
+
+  let greeting = session.greeting();
+  assert_eq!(greeting.len(), 1);
+  assert!(matches!(greeting[0].body, FromClient::Hello { .. }));
+  let joins: Vec<_> = session.unsent.iter()
+    .map(|body| match body {
+      FromClient::Join { group_name, .. } => group_name.as_str(),
+      other => panic!("expected a join, got {:?}", other),
+    })
+    .collect();
+  assert_eq!(joins, ["Crabs", "Eels"]);
+}
+
+#[test]
+fn test_parse_command() {
+  assert!(matches!(parse_command("join Crabs last 5"),
+                   Ok(FromClient::Join { replay: Some(Replay::LastN(5)), .. })));
+  assert_eq!(parse_command("leave Crabs Eels").unwrap_err(),
+             "Usage: leave GROUP");
+  assert_eq!(parse_command("typing Crabs"),
+             Ok(FromClient::Typing { group_name: Arc::new("Crabs".to_string()) }));
+  assert_eq!(parse_command("dance wildly").unwrap_err(),
+             "Unrecognized command: \"dance\"");
+}
+
+#[test]
+fn test_format_time() {
+  assert_eq!(format_time(0), "1970-01-01 00:00:00");
+  assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+  assert_eq!(format_time(1615734566), "2021-03-14 15:09:26");
+}
@@ -0,0 +1,651 @@
+//! A full-screen terminal interface for the client.
+//!
+//! The sidebar lists the conversations: the server's own pane, each group
+//! we've joined, and each user we've exchanged direct messages with. The
+//! selected conversation's messages fill the rest of the screen, above an
+//! input line. Text typed there is posted to the selected conversation;
+//! lines starting with '/' are commands, as in the plain client. While the
+//! user types in a group, its other members are told so, and the bottom of
+//! the pane shows who else is typing there.
+
+use async_chat::{FromClient, FromServer};
+use async_std::channel;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{format_time, join_names, parse_command, unix_time, COMMANDS};
+
+/// Something for the interface to show, other than what the user types.
+pub enum Update {
+  /// A packet from the server.
+  Packet(FromServer),
+  /// News about the connection itself.
+  Status(String),
+}
+
+/// How long to wait for a key press before checking for updates.
+const TICK: Duration = Duration::from_millis(50);
+
+/// The most lines each pane keeps; older ones are forgotten.
+const MAX_LINES: usize = 1000;
+
+/// The width of the sidebar, including its border.
+const SIDEBAR_WIDTH: u16 = 24;
+
+/// How often to tell a group that the user is still typing. The server
+/// passes these along no more often than this anyway.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long to go on showing that someone is typing after we last heard so.
+const TYPING_SHOWN: Duration = Duration::from_secs(5);
+
+/// Take over the terminal, and run the interface until the user quits. Send
+/// the requests they enter on `commands`, and show whatever arrives on
+/// `updates`.
+pub fn run(nickname: Arc<String>,
+           commands: channel::Sender<FromClient>,
+           updates: channel::Receiver<Update>)
+  -> io::Result<()>
+{
+  let mut terminal = ratatui::try_init()?;
+  let result = App::new(nickname).run(&mut terminal, &commands, &updates);
+  ratatui::restore();
+  result
+}
+
+/// Who a pane's messages are exchanged with.
+#[derive(Clone, Debug, PartialEq)]
+enum Peer {
+  /// The server itself: help, errors, and connection news.
+  Server,
+  Group(Arc<String>),
+  User(Arc<String>),
+}
+
+/// One conversation, as listed in the sidebar.
+struct Pane {
+  peer: Peer,
+  lines: Vec<String>,
+  /// How many lines have arrived since the pane was last selected.
+  unread: usize,
+  /// How many lines up from the bottom the user has scrolled.
+  scroll: usize,
+  /// Who is typing here, and when we last heard so.
+  typing: Vec<(Arc<String>, Instant)>,
+  /// When we last told the group that the user is typing.
+  told_typing: Option<Instant>,
+}
+
+impl Pane {
+  fn new(peer: Peer) -> Pane {
+    Pane {
+      peer,
+      lines: vec![],
+      unread: 0,
+      scroll: 0,
+      typing: vec![],
+      told_typing: None,
+    }
+  }
+
+  fn title(&self) -> String {
+    match &self.peer {
+      Peer::Server => "server".to_string(),
+      Peer::Group(group_name) => group_name.to_string(),
+      Peer::User(nickname) => format!("@{}", nickname),
+    }
+  }
+
+  /// Return who has been typing here lately.
+  fn typists(&self) -> Vec<Arc<String>> {
+    self.typing.iter()
+      .filter(|(_, heard)| heard.elapsed() < TYPING_SHOWN)
+      .map(|(nickname, _)| nickname.clone())
+      .collect()
+  }
+
+  /// Stop showing that `nickname` is typing, since they posted or left.
+  fn stopped_typing(&mut self, nickname: &Arc<String>) {
+    self.typing.retain(|(typist, _)| typist != nickname);
+  }
+}
+
+struct App {
+  nickname: Arc<String>,
+  /// The server's pane comes first, followed by the others in the order
+  /// they were opened.
+  panes: Vec<Pane>,
+  selected: usize,
+  input: String,
+  /// Lines the user has entered, oldest first.
+  history: Vec<String>,
+  /// Which entry of `history` the input line shows, while the user is
+  /// browsing it with Up and Down.
+  browsing: Option<usize>,
+  /// The number of lines the message pane showed when last drawn, which is
+  /// how far Page Up and Page Down scroll.
+  page: usize,
+  quit: bool,
+}
+
+impl App {
+  fn new(nickname: Arc<String>) -> App {
+    let mut server = Pane::new(Peer::Server);
+    server.lines.push("Type a message to post it to the selected \
+                       conversation, or a command starting with '/':"
+                      .to_string());
+    server.lines.extend(COMMANDS.lines().map(|line| format!("  /{}", line)));
+    server.lines.push("Tab and Shift-Tab switch conversations, Page Up and \
+                       Page Down scroll, and Up and Down recall earlier \
+                       input. Esc or Control-C quits.".to_string());
+    App {
+      nickname,
+      panes: vec![server],
+      selected: 0,
+      input: String::new(),
+      history: vec![],
+      browsing: None,
+      page: 10,
+      quit: false,
+    }
+  }
+
+  fn run(&mut self,
+         terminal: &mut DefaultTerminal,
+         commands: &channel::Sender<FromClient>,
+         updates: &channel::Receiver<Update>)
+    -> io::Result<()>
+  {
+    while !self.quit {
+      while let Ok(update) = updates.try_recv() {
+        self.update(update);
+      }
+      terminal.draw(|frame| self.draw(frame))?;
+
+      if !event::poll(TICK)? {
+        continue;
+      }
+      if let Event::Key(key) = event::read()? {
+        if key.kind != KeyEventKind::Press {
+          continue;
+        }
+        if let Some(request) = self.key(key) {
+          if commands.send_blocking(request).is_err() {
+            break;
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Show `update` in the appropriate pane.
+  fn update(&mut self, update: Update) {
+    match update {
+      Update::Packet(FromServer::Message { group_name, sender, timestamp,
+                                           message, .. }) => {
+        let line = format!("[{}] {}: {}", format_time(timestamp), sender,
+                           message);
+        self.stopped_typing(&group_name, &sender);
+        self.add_line(Peer::Group(group_name), line);
+      }
+      Update::Packet(FromServer::Edited { group_name, id, editor, message }) => {
+        let line = format!("{} edited {}: {}", editor, id, message);
+        self.add_line(Peer::Group(group_name), line);
+      }
+      Update::Packet(FromServer::Deleted { group_name, id, by }) => {
+        let line = format!("{} deleted {}", by, id);
+        self.add_line(Peer::Group(group_name), line);
+      }
+      Update::Packet(FromServer::Reacted { group_name, id, sender,
+                                           reaction }) => {
+        let line = format!("{} reacted to {}: {}", sender, id, reaction);
+        self.add_line(Peer::Group(group_name), line);
+      }
+      Update::Packet(FromServer::Presence { group_name, members }) => {
+        let line = format!("here: {}", join_names(&members));
+        self.add_line(Peer::Group(group_name), line);
+      }
+      Update::Packet(FromServer::Joined { group_name, nickname }) => {
+        self.add_line(Peer::Group(group_name), format!("{} joined", nickname));
+      }
+      Update::Packet(FromServer::Left { group_name, nickname }) => {
+        self.stopped_typing(&group_name, &nickname);
+        self.add_line(Peer::Group(group_name), format!("{} left", nickname));
+      }
+      // A line per keystroke would drown out the conversation, so this is
+      // shown beneath it instead.
+      Update::Packet(FromServer::Typing { group_name, nickname }) => {
+        let peer = Peer::Group(group_name);
+        if let Some(pane) = self.panes.iter_mut().find(|pane| pane.peer == peer) {
+          pane.stopped_typing(&nickname);
+          pane.typing.push((nickname, Instant::now()));
+        }
+      }
+      Update::Packet(FromServer::DirectMessage { from, timestamp, message }) => {
+        let line = format!("[{}] {}: {}", format_time(timestamp), from,
+                           message);
+        self.add_line(Peer::User(from), line);
+      }
+      Update::Packet(FromServer::Groups { group_names }) => {
+        self.notice(format!("groups: {}", join_names(&group_names)));
+      }
+      Update::Packet(FromServer::Members { group_name, members }) => {
+        self.notice(format!("members of {}: {}", group_name,
+                            join_names(&members)));
+      }
+      Update::Packet(FromServer::SearchResults { group_name, results }) => {
+        self.notice(format!("found {} in {}:", results.len(), group_name));
+        for result in results {
+          self.notice(format!("[{}] {} ({}): {}",
+                              format_time(result.timestamp), result.sender,
+                              result.id, result.message));
+        }
+      }
+      Update::Packet(FromServer::Nack { reason: message, .. }) |
+      Update::Packet(FromServer::Error { message, .. }) => {
+        self.notice(format!("error from server: {}", message));
+      }
+      Update::Packet(FromServer::Ack { .. }) => {}
+      Update::Packet(FromServer::Shutdown) => {
+        self.notice("server is shutting down".to_string());
+      }
+      Update::Status(message) => self.notice(message),
+    }
+  }
+
+  /// Stop showing that `nickname` is typing in `group_name`.
+  fn stopped_typing(&mut self, group_name: &Arc<String>, nickname: &Arc<String>) {
+    let peer = Peer::Group(group_name.clone());
+    if let Some(pane) = self.panes.iter_mut().find(|pane| pane.peer == peer) {
+      pane.stopped_typing(nickname);
+    }
+  }
+
+  /// Show `line` in the pane for `peer`, opening one if necessary.
+  fn add_line(&mut self, peer: Peer, line: String) {
+    let index = self.open(peer);
+    let pane = &mut self.panes[index];
+    pane.lines.push(line);
+    if pane.lines.len() > MAX_LINES {
+      pane.lines.remove(0);
+    } else if pane.scroll > 0 {
+      // Keep the lines the user scrolled back to in view.
+      pane.scroll += 1;
+    }
+    if index != self.selected {
+      pane.unread += 1;
+    }
+  }
+
+  /// Show `line` where the user is looking, since it's about something
+  /// they just did, or about the connection as a whole.
+  fn notice(&mut self, line: String) {
+    let peer = self.panes[self.selected].peer.clone();
+    self.add_line(peer, line);
+  }
+
+  /// Return the index of the pane for `peer`, opening one if necessary.
+  fn open(&mut self, peer: Peer) -> usize {
+    match self.panes.iter().position(|pane| pane.peer == peer) {
+      Some(index) => index,
+      None => {
+        self.panes.push(Pane::new(peer));
+        self.panes.len() - 1
+      }
+    }
+  }
+
+  fn select(&mut self, index: usize) {
+    self.selected = index;
+    self.panes[index].unread = 0;
+  }
+
+  /// Handle a key press, returning the request it completes, if any.
+  fn key(&mut self, key: KeyEvent) -> Option<FromClient> {
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+      KeyCode::Esc => self.quit = true,
+      KeyCode::Char('c') if control => self.quit = true,
+      KeyCode::Char('d') if control && self.input.is_empty() => {
+        self.quit = true;
+      }
+      KeyCode::Char(ch) if !control => {
+        self.input.push(ch);
+        self.browsing = None;
+        return self.typing();
+      }
+      KeyCode::Backspace => {
+        self.input.pop();
+        self.browsing = None;
+      }
+      KeyCode::Enter => return self.submit(),
+      KeyCode::Up => self.browse_history(-1),
+      KeyCode::Down => self.browse_history(1),
+      KeyCode::Tab => {
+        self.select((self.selected + 1) % self.panes.len());
+      }
+      KeyCode::BackTab => {
+        let count = self.panes.len();
+        self.select((self.selected + count - 1) % count);
+      }
+      KeyCode::PageUp => {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = (pane.scroll + self.page)
+          .min(pane.lines.len().saturating_sub(1));
+      }
+      KeyCode::PageDown => {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = pane.scroll.saturating_sub(self.page);
+      }
+      _ => {}
+    }
+    None
+  }
+
+  /// Return a `Typing` request for the selected group, if the user is
+  /// typing a post there and we haven't said so lately.
+  fn typing(&mut self) -> Option<FromClient> {
+    if self.input.starts_with('/') {
+      return None;
+    }
+    let pane = &mut self.panes[self.selected];
+    let group_name = match &pane.peer {
+      Peer::Group(group_name) => group_name.clone(),
+      _ => return None,
+    };
+    if pane.told_typing.is_some_and(|told| told.elapsed() < TYPING_INTERVAL) {
+      return None;
+    }
+    pane.told_typing = Some(Instant::now());
+    Some(FromClient::Typing { group_name })
+  }
+
+  /// Move `step` entries through the input history.
+  fn browse_history(&mut self, step: isize) {
+    let next = match self.browsing {
+      Some(index) => index.checked_add_signed(step),
+      None if step < 0 => self.history.len().checked_sub(1),
+      None => return,
+    };
+    match next {
+      Some(index) if index < self.history.len() => {
+        self.browsing = Some(index);
+        self.input = self.history[index].clone();
+      }
+      Some(_) => {
+        // Down past the newest entry returns to an empty line.
+        self.browsing = None;
+        self.input.clear();
+      }
+      None => {}
+    }
+  }
+
+  /// Take the input line, and return the request it makes, if any.
+  fn submit(&mut self) -> Option<FromClient> {
+    let line = std::mem::take(&mut self.input);
+    self.browsing = None;
+    if line.trim().is_empty() {
+      return None;
+    }
+    self.history.push(line.clone());
+
+    let request = match line.strip_prefix('/') {
+      Some(command) => match parse_command(command) {
+        Ok(request) => request,
+        Err(message) => {
+          self.notice(message);
+          return None;
+        }
+      },
+      None => match &self.panes[self.selected].peer {
+        Peer::Group(group_name) => FromClient::Post {
+          group_name: group_name.clone(),
+          message: Arc::new(line),
+        },
+        Peer::User(nickname) => FromClient::DirectMessage {
+          to: nickname.clone(),
+          message: Arc::new(line),
+        },
+        Peer::Server => {
+          self.notice("Select a conversation with Tab, or join a group with \
+                       /join GROUP".to_string());
+          return None;
+        }
+      },
+    };
+
+    // Follow the user to wherever their request leads.
+    match &request {
+      FromClient::Join { group_name, .. } => {
+        let index = self.open(Peer::Group(group_name.clone()));
+        self.select(index);
+      }
+      FromClient::Leave { group_name } => {
+        let peer = Peer::Group(group_name.clone());
+        if let Some(index) = self.panes.iter().position(|pane| pane.peer == peer) {
+          self.panes.remove(index);
+          self.select(0);
+        }
+      }
+      FromClient::DirectMessage { to, message } => {
+        // The server doesn't echo direct messages, so record our own.
+        let line = format!("[{}] {}: {}", format_time(unix_time()),
+                           self.nickname, message);
+        let index = self.open(Peer::User(to.clone()));
+        self.select(index);
+        self.add_line(Peer::User(to.clone()), line);
+      }
+      _ => {}
+    }
+    Some(request)
+  }
+
+  fn draw(&mut self, frame: &mut Frame) {
+    let [sidebar, main] = Layout::horizontal([
+      Constraint::Length(SIDEBAR_WIDTH),
+      Constraint::Min(0),
+    ]).areas(frame.area());
+    let [messages, input] = Layout::vertical([
+      Constraint::Min(0),
+      Constraint::Length(3),
+    ]).areas(main);
+
+    let items: Vec<ListItem> = self.panes.iter()
+      .map(|pane| match pane.unread {
+        0 => ListItem::new(pane.title()),
+        unread => ListItem::new(format!("{} ({})", pane.title(), unread)),
+      })
+      .collect();
+    let list = List::new(items)
+      .block(Block::bordered().title("Conversations"))
+      .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(Some(self.selected));
+    frame.render_stateful_widget(list, sidebar, &mut state);
+
+    self.draw_messages(frame, messages);
+
+    let width = input.width.saturating_sub(2) as usize;
+    let typed = self.input.chars().count();
+    let shown: String = self.input.chars().skip(typed.saturating_sub(width))
+      .collect();
+    let cursor_x = input.x + 1 + shown.chars().count() as u16;
+    frame.render_widget(Paragraph::new(shown).block(Block::bordered()), input);
+    frame.set_cursor_position(Position::new(cursor_x, input.y + 1));
+  }
+
+  fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+    let pane = &self.panes[self.selected];
+    let mut block = Block::bordered().title(pane.title());
+    let typists = pane.typists();
+    if !typists.is_empty() {
+      let verb = if typists.len() == 1 { "is" } else { "are" };
+      block = block.title_bottom(format!("{} {} typing", join_names(&typists),
+                                         verb));
+    }
+    let inner = block.inner(area);
+    let (width, height) = (inner.width as usize, inner.height as usize);
+    self.page = height.max(1);
+
+    let pane = &mut self.panes[self.selected];
+    let wrapped: Vec<String> = pane.lines.iter()
+      .flat_map(|line| wrap(line, width))
+      .collect();
+    pane.scroll = pane.scroll.min(wrapped.len().saturating_sub(height));
+    let end = wrapped.len() - pane.scroll;
+    let start = end.saturating_sub(height);
+    let lines: Vec<Line> = wrapped[start..end].iter()
+      .map(|line| Line::raw(line.as_str()))
+      .collect();
+
+    frame.render_widget(block, area);
+    frame.render_widget(Paragraph::new(lines), inner);
+  }
+}
+
+/// Break `line` into pieces at most `width` characters long.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+  let chars: Vec<char> = line.chars().collect();
+  if chars.is_empty() || width == 0 {
+    return vec![line.to_string()];
+  }
+  chars.chunks(width).map(|chunk| chunk.iter().collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+  }
+
+  fn message(group_name: &str, message: &str) -> Update {
+    Update::Packet(FromServer::Message {
+      group_name: name(group_name),
+      sender: name("bob"),
+      timestamp: 0,
+      message: name(message),
+      id: name("1"),
+    })
+  }
+
+  fn type_line(app: &mut App, line: &str) -> Option<FromClient> {
+    for ch in line.chars() {
+      app.key(KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE));
+    }
+    app.key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE))
+  }
+
+  fn unread(app: &App) -> Vec<(String, usize)> {
+    app.panes.iter().map(|pane| (pane.title(), pane.unread)).collect()
+  }
+
+  #[test]
+  fn messages_are_counted_until_read() {
+    let mut app = App::new(name("ann"));
+    assert_eq!(type_line(&mut app, "/join Crabs"), Some(FromClient::Join {
+      group_name: name("Crabs"),
+      replay: None,
+    }));
+
+    app.update(message("Crabs", "in view"));
+    app.update(message("Dogs", "elsewhere"));
+    app.update(message("Dogs", "elsewhere again"));
+    assert_eq!(unread(&app), [("server".to_string(), 0),
+                              ("Crabs".to_string(), 0),
+                              ("Dogs".to_string(), 2)]);
+
+    app.key(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE));
+    assert_eq!(app.panes[app.selected].title(), "Dogs");
+    assert_eq!(app.panes[2].unread, 0);
+    assert_eq!(app.panes[2].lines, ["[1970-01-01 00:00:00] bob: elsewhere",
+                                    "[1970-01-01 00:00:00] bob: elsewhere again"]);
+  }
+
+  #[test]
+  fn plain_text_goes_to_selected_conversation() {
+    let mut app = App::new(name("ann"));
+    assert_eq!(type_line(&mut app, "hello?"), None);
+
+    type_line(&mut app, "/join Crabs");
+    assert_eq!(type_line(&mut app, "hello, crabs"), Some(FromClient::Post {
+      group_name: name("Crabs"),
+      message: name("hello, crabs"),
+    }));
+
+    type_line(&mut app, "/msg bob hi");
+    assert_eq!(app.panes[app.selected].title(), "@bob");
+    assert_eq!(type_line(&mut app, "still there?"),
+               Some(FromClient::DirectMessage {
+                 to: name("bob"),
+                 message: name("still there?"),
+               }));
+    assert_eq!(app.panes[app.selected].lines.len(), 2);
+  }
+
+  #[test]
+  fn input_history_is_recalled() {
+    let mut app = App::new(name("ann"));
+    type_line(&mut app, "/join Crabs");
+    type_line(&mut app, "first");
+    type_line(&mut app, "second");
+
+    let up = KeyEvent::new(KeyCode::Up, KeyModifiers::NONE);
+    let down = KeyEvent::new(KeyCode::Down, KeyModifiers::NONE);
+    app.key(up);
+    assert_eq!(app.input, "second");
+    app.key(up);
+    app.key(up);
+    app.key(up);
+    assert_eq!(app.input, "/join Crabs");
+    app.key(down);
+    assert_eq!(app.input, "first");
+    app.key(down);
+    app.key(down);
+    assert_eq!(app.input, "");
+  }
+
+  #[test]
+  fn typing_is_sent_now_and_then_and_shown() {
+    let mut app = App::new(name("ann"));
+    let key = |ch| KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE);
+    // Neither commands nor the server's pane are worth telling anyone about.
+    assert_eq!(app.key(key('h')), None);
+    app.input.clear();
+    type_line(&mut app, "/join Crabs");
+
+    assert_eq!(app.key(key('h')), Some(FromClient::Typing {
+      group_name: name("Crabs"),
+    }));
+    assert_eq!(app.key(key('i')), None);
+
+    let typing = |nickname: &str| Update::Packet(FromServer::Typing {
+      group_name: name("Crabs"),
+      nickname: name(nickname),
+    });
+    app.update(typing("bob"));
+    app.update(typing("cat"));
+    app.update(typing("bob"));
+    assert_eq!(app.panes[1].typists(), [name("cat"), name("bob")]);
+    assert!(app.panes[1].lines.is_empty());
+
+    // Posting a message means bob is done typing it.
+    app.update(message("Crabs", "hi"));
+    assert_eq!(app.panes[1].typists(), [name("cat")]);
+  }
+
+  #[test]
+  fn long_lines_are_wrapped() {
+    assert_eq!(wrap("abcdefg", 3), ["abc", "def", "g"]);
+    assert_eq!(wrap("", 3), [""]);
+  }
+}
@@ -0,0 +1,672 @@
+//! The server's configuration, read from an optional TOML file and then
+//! adjusted by command-line options.
+//!
+//! A config file looks like this; every setting is optional, and the
+//! defaults are those in `Config::default`.
+//!
+//! ```toml
+//! history_directory = "/var/lib/async-chat"
+//! log_level = "info"
+//! log_format = "json"
+//! max_message_size = 65536
+//! max_frame_size = 1048576
+//! max_group_name_length = 64
+//! max_groups = 1000
+//! admin_listen = "127.0.0.1:9090"
+//!
+//! [[listen]]
+//! address = "0.0.0.0:8088"
+//!
+//! [[listen]]
+//! address = "0.0.0.0:8443"
+//! tls = true
+//!
+//! [[listen]]
+//! address = "0.0.0.0:8080"
+//! websocket = true
+//!
+//! [tls]
+//! certificate = "cert.pem"
+//! key = "key.pem"
+//!
+//! [accounts]
+//! file = "/var/lib/async-chat/accounts.json"
+//! allow_registration = false
+//!
+//! [groups]
+//! access_file = "/var/lib/async-chat/access.json"
+//! capacity = 1000
+//! slow_consumers = "drop-oldest"
+//! policies = { Announcements = "block" }
+//! rate = { per_second = 50.0, burst = 100 }
+//!
+//! [connections]
+//! rate = { per_second = 10.0, burst = 20 }
+//! max_strikes = 50
+//!
+//! [federation]
+//! name = "chat-east"
+//! secret = "shared by every peer"
+//! peers = ["chat-west.example.com:8443"]
+//! peer_ca = "peers-ca.pem"
+//! ```
+
+use async_chat::server::federation::Federation;
+use async_chat::server::group::SlowConsumerPolicy;
+use async_chat::server::group_table::GroupSettings;
+use async_chat::server::logging::{Format, Level};
+use async_chat::server::rate_limit::{Limits, Rate};
+use async_chat::utils::ChatResult;
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Serve chat groups to async-chat clients.
+#[derive(Parser, Debug)]
+#[command(name = "server")]
+pub struct Cli {
+  /// Addresses to accept plain connections on, as HOST:PORT. These replace
+  /// any listeners in the config file.
+  #[arg(value_name = "ADDRESS")]
+  pub listen: Vec<String>,
+
+  /// Read settings from this TOML file. Options given on the command line
+  /// take precedence over it.
+  #[arg(short, long, value_name = "FILE")]
+  pub config: Option<PathBuf>,
+
+  /// An address to accept TLS connections on. May be repeated.
+  #[arg(long, value_name = "ADDRESS")]
+  pub tls_listen: Vec<String>,
+
+  /// An address to accept WebSocket connections on, for browser clients.
+  /// May be repeated.
+  #[arg(long, value_name = "ADDRESS")]
+  pub ws_listen: Vec<String>,
+
+  /// The certificate chain to present to TLS clients, in PEM format.
+  #[arg(long, value_name = "FILE", requires = "tls_key")]
+  pub tls_cert: Option<PathBuf>,
+
+  /// The private key for `--tls-cert`, in PEM format.
+  #[arg(long, value_name = "FILE", requires = "tls_cert")]
+  pub tls_key: Option<PathBuf>,
+
+  /// Keep each group's history in a file in this directory, so that it
+  /// survives restarts.
+  #[arg(long, value_name = "DIRECTORY")]
+  pub history_directory: Option<PathBuf>,
+
+  /// How many messages a group member may fall behind before the group's
+  /// slow-consumer policy applies.
+  #[arg(long, value_name = "N")]
+  pub capacity: Option<usize>,
+
+  /// What to do about slow group members: drop-oldest, disconnect or block.
+  #[arg(long, value_name = "POLICY")]
+  pub slow_consumers: Option<SlowConsumerPolicy>,
+
+  /// Use a different slow-consumer policy for one group. May be repeated.
+  #[arg(long, value_name = "GROUP=POLICY", value_parser = parse_group_policy)]
+  pub group_policy: Vec<(String, SlowConsumerPolicy)>,
+
+  /// The longest message a client may post or send, in bytes.
+  #[arg(long, value_name = "BYTES")]
+  pub max_message_size: Option<usize>,
+
+  /// The longest request a client may send, in bytes. Clients that send
+  /// longer ones are disconnected.
+  #[arg(long, value_name = "BYTES")]
+  pub max_frame_size: Option<usize>,
+
+  /// The longest group name a client may use, in characters.
+  #[arg(long, value_name = "N")]
+  pub max_group_name_length: Option<usize>,
+
+  /// The most groups the server will hold at once.
+  #[arg(long, value_name = "N")]
+  pub max_groups: Option<usize>,
+
+  /// How much to log: error, warn, info or debug.
+  #[arg(long, value_name = "LEVEL")]
+  pub log_level: Option<Level>,
+
+  /// How to write log lines: text, or json for log pipelines.
+  #[arg(long, value_name = "FORMAT")]
+  pub log_format: Option<Format>,
+
+  /// Require clients to log in to an account saved in this file.
+  #[arg(long, value_name = "FILE")]
+  pub accounts: Option<PathBuf>,
+
+  /// Let clients create their own accounts.
+  #[arg(long, requires = "accounts")]
+  pub allow_registration: bool,
+
+  /// Create an account with a password read from standard input, and exit
+  /// instead of serving.
+  #[arg(long, value_name = "NICKNAME")]
+  pub add_account: Option<String>,
+
+  /// Keep each group's owners and members in this file, so that they
+  /// survive restarts.
+  #[arg(long, value_name = "FILE")]
+  pub access_file: Option<PathBuf>,
+
+  /// Serve metrics and admin commands over HTTP on this address. Anyone
+  /// who can reach it can kick users, so keep it private.
+  #[arg(long, value_name = "ADDRESS")]
+  pub admin_listen: Option<String>,
+
+  /// This server's name among its peers, which must be unique.
+  #[arg(long, value_name = "NAME")]
+  pub server_name: Option<String>,
+
+  /// Link to the peer server at this address, and relay posts to and from
+  /// it. May be repeated. The peer secret comes from the config file.
+  #[arg(long, value_name = "ADDRESS")]
+  pub peer: Vec<String>,
+
+  /// Link to peers over TLS, trusting the certificate authorities in this
+  /// PEM file. Without it, links are plain TCP.
+  #[arg(long, value_name = "FILE")]
+  pub peer_ca: Option<PathBuf>,
+}
+
+fn parse_group_policy(setting: &str)
+  -> Result<(String, SlowConsumerPolicy), String>
+{
+  let (group, policy) = setting.rsplit_once('=')
+    .ok_or_else(|| format!("expected GROUP=POLICY, not '{}'", setting))?;
+  Ok((group.to_string(), policy.parse()?))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// The addresses to accept connections on.
+  pub listen: Vec<Listener>,
+  /// The address to serve the admin endpoint on, if any.
+  pub admin_listen: Option<String>,
+  /// The certificate and key for listeners that use TLS.
+  pub tls: Option<TlsFiles>,
+  /// Where to keep group history, if it should outlive the server.
+  pub history_directory: Option<PathBuf>,
+  pub log_level: Level,
+  pub log_format: Format,
+  /// The longest message a client may post or send, in bytes.
+  pub max_message_size: usize,
+  /// The longest request a client may send, in bytes.
+  pub max_frame_size: usize,
+  /// The longest group name a client may use, in characters.
+  pub max_group_name_length: usize,
+  /// The most groups the server will hold at once, if limited.
+  pub max_groups: Option<usize>,
+  /// How many direct messages to hold for a user who is offline.
+  pub offline_queue_limit: usize,
+  /// The accounts clients must log in to. Without them, any client may
+  /// use any nickname not already in use.
+  pub accounts: Option<AccountConfig>,
+  pub groups: GroupConfig,
+  pub connections: ConnectionConfig,
+  pub federation: FederationConfig,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+  pub address: String,
+  #[serde(default)]
+  pub tls: bool,
+  /// Whether clients speak WebSocket, rather than the plain protocol.
+  #[serde(default)]
+  pub websocket: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+  pub certificate: PathBuf,
+  pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+  pub file: PathBuf,
+  /// Whether clients may create their own accounts.
+  #[serde(default)]
+  pub allow_registration: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+  /// The number of past messages each group keeps for replay.
+  pub history_limit: usize,
+  /// How long a group with no members lingers before it is dropped, in
+  /// seconds.
+  pub idle_timeout: u64,
+  /// How many messages a member may fall behind before the slow-consumer
+  /// policy applies.
+  pub capacity: usize,
+  pub slow_consumers: SlowConsumerPolicy,
+  /// Exceptions to `slow_consumers`, by group name.
+  pub policies: HashMap<String, SlowConsumerPolicy>,
+  /// How fast messages may be posted to any one group.
+  pub rate: Option<Rate>,
+  /// Where to keep each group's access list, if it should outlive the
+  /// server.
+  pub access_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+  /// How fast a single connection may send requests.
+  pub rate: Option<Rate>,
+  /// How many throttled requests in a row we tolerate before hanging up.
+  pub max_strikes: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+  /// This server's name among its peers, which begins every message ID it
+  /// assigns.
+  pub name: Option<String>,
+  /// The secret peers present when they link to us, and we present when we
+  /// link to them. Without it, we neither accept nor make links.
+  pub secret: Option<String>,
+  /// The addresses of the peers to link to. Links are symmetrical, so only
+  /// one server of each pair needs to name the other.
+  pub peers: Vec<String>,
+  /// The certificate authorities to trust when linking to peers. With this,
+  /// links use TLS, so each peer's address must be a TLS listener. Without
+  /// it, links are plain TCP, which exposes the secret and every relayed
+  /// post to the network in between; only do that on a private network or
+  /// over an encrypted tunnel.
+  pub peer_ca: Option<PathBuf>,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      listen: vec![],
+      admin_listen: None,
+      tls: None,
+      history_directory: None,
+      log_level: Level::Info,
+      log_format: Format::Text,
+      max_message_size: 64 * 1024,
+      max_frame_size: 1024 * 1024,
+      max_group_name_length: 64,
+      max_groups: None,
+      offline_queue_limit: 100,
+      accounts: None,
+      groups: GroupConfig::default(),
+      connections: ConnectionConfig::default(),
+      federation: FederationConfig::default(),
+    }
+  }
+}
+
+impl Default for GroupConfig {
+  fn default() -> GroupConfig {
+    let settings = GroupSettings::default();
+    GroupConfig {
+      history_limit: settings.history_limit,
+      idle_timeout: settings.idle_timeout.as_secs(),
+      capacity: settings.capacity,
+      slow_consumers: settings.policy,
+      policies: HashMap::new(),
+      rate: Some(Rate { per_second: 50.0, burst: 100 }),
+      access_file: None,
+    }
+  }
+}
+
+impl Default for ConnectionConfig {
+  fn default() -> ConnectionConfig {
+    ConnectionConfig {
+      rate: Some(Rate { per_second: 10.0, burst: 20 }),
+      max_strikes: 50,
+    }
+  }
+}
+
+impl Config {
+  /// Read the config file named on the command line, if any, apply the
+  /// command line's overrides, and check that the result makes sense.
+  pub fn from_cli(cli: Cli) -> ChatResult<Config> {
+    let config = Config::read(cli)?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Like `from_cli`, but without checking that the result is enough to
+  /// run a server with.
+  pub fn read(cli: Cli) -> ChatResult<Config> {
+    let mut config = match &cli.config {
+      Some(path) => Config::load(path)?,
+      None => Config::default(),
+    };
+    config.apply(cli);
+    Ok(config)
+  }
+
+  pub fn load(path: &Path) -> ChatResult<Config> {
+    let text = std::fs::read_to_string(path)
+      .map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+    let config = toml::from_str(&text)
+      .map_err(|error| format!("error in {}: {}", path.display(), error))?;
+    Ok(config)
+  }
+
+  fn apply(&mut self, cli: Cli) {
+    if !cli.listen.is_empty() || !cli.tls_listen.is_empty()
+      || !cli.ws_listen.is_empty()
+    {
+      let listener = |tls, websocket| {
+        move |address| Listener { address, tls, websocket }
+      };
+      self.listen = cli.listen.into_iter().map(listener(false, false))
+        .chain(cli.tls_listen.into_iter().map(listener(true, false)))
+        .chain(cli.ws_listen.into_iter().map(listener(false, true)))
+        .collect();
+    }
+    if cli.admin_listen.is_some() {
+      self.admin_listen = cli.admin_listen;
+    }
+    if let (Some(certificate), Some(key)) = (cli.tls_cert, cli.tls_key) {
+      self.tls = Some(TlsFiles { certificate, key });
+    }
+    if cli.history_directory.is_some() {
+      self.history_directory = cli.history_directory;
+    }
+    if let Some(capacity) = cli.capacity {
+      self.groups.capacity = capacity;
+    }
+    if let Some(policy) = cli.slow_consumers {
+      self.groups.slow_consumers = policy;
+    }
+    self.groups.policies.extend(cli.group_policy);
+    if let Some(size) = cli.max_message_size {
+      self.max_message_size = size;
+    }
+    if let Some(size) = cli.max_frame_size {
+      self.max_frame_size = size;
+    }
+    if let Some(length) = cli.max_group_name_length {
+      self.max_group_name_length = length;
+    }
+    if cli.max_groups.is_some() {
+      self.max_groups = cli.max_groups;
+    }
+    if let Some(level) = cli.log_level {
+      self.log_level = level;
+    }
+    if let Some(format) = cli.log_format {
+      self.log_format = format;
+    }
+    if let Some(file) = cli.accounts {
+      let allow_registration = cli.allow_registration
+        || self.accounts.as_ref().is_some_and(|accounts| {
+          accounts.allow_registration
+        });
+      self.accounts = Some(AccountConfig { file, allow_registration });
+    }
+    if cli.access_file.is_some() {
+      self.groups.access_file = cli.access_file;
+    }
+    if cli.server_name.is_some() {
+      self.federation.name = cli.server_name;
+    }
+    if !cli.peer.is_empty() {
+      self.federation.peers = cli.peer;
+    }
+    if cli.peer_ca.is_some() {
+      self.federation.peer_ca = cli.peer_ca;
+    }
+  }
+
+  /// Check for settings that are individually well-formed but can't work.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.listen.is_empty() {
+      return Err("no addresses to listen on; give one on the command line \
+                  or in the config file".to_string());
+    }
+    let uses_tls = self.listen.iter().any(|listener| listener.tls);
+    if uses_tls && self.tls.is_none() {
+      return Err("listening with TLS requires a certificate and key".to_string());
+    }
+    if !uses_tls && self.tls.is_some() {
+      return Err("a TLS certificate was given, but no listener uses TLS"
+                 .to_string());
+    }
+    if self.groups.capacity == 0 {
+      return Err("group capacity must be at least 1".to_string());
+    }
+    if self.max_message_size == 0 {
+      return Err("max_message_size must be at least 1".to_string());
+    }
+    if self.max_frame_size < self.max_message_size {
+      return Err("max_frame_size must be at least max_message_size, or the \
+                  longest messages could never arrive".to_string());
+    }
+    if self.max_group_name_length == 0 {
+      return Err("max_group_name_length must be at least 1".to_string());
+    }
+    if self.max_groups == Some(0) {
+      return Err("max_groups must be at least 1".to_string());
+    }
+    if self.federation.secret.is_none() && !self.federation.peers.is_empty() {
+      return Err("linking to peers requires a peer secret".to_string());
+    }
+    if self.federation.secret.is_some() && self.federation.name.is_none() {
+      return Err("accepting peers requires a server name, to tell this \
+                  server's messages from theirs".to_string());
+    }
+    for (what, rate) in [("group", &self.groups.rate),
+                         ("connection", &self.connections.rate)] {
+      if let Some(rate) = rate {
+        if !(rate.per_second > 0.0 && rate.per_second.is_finite())
+          || rate.burst == 0
+        {
+          return Err(format!("{} rate must be positive, with a burst of at \
+                              least 1", what));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn group_settings(&self) -> GroupSettings {
+    GroupSettings {
+      history_directory: self.history_directory.clone(),
+      history_limit: self.groups.history_limit,
+      idle_timeout: Duration::from_secs(self.groups.idle_timeout),
+      rate: self.groups.rate,
+      capacity: self.groups.capacity,
+      policy: self.groups.slow_consumers,
+      policies: self.groups.policies.clone(),
+      max_groups: self.max_groups,
+    }
+  }
+
+  pub fn federation(&self) -> Federation {
+    let name = self.federation.name.as_deref().unwrap_or("local");
+    Federation::new(name, self.federation.secret.clone())
+  }
+
+  pub fn limits(&self) -> Limits {
+    Limits {
+      connection: self.connections.rate,
+      max_strikes: self.connections.max_strikes,
+      max_message_size: self.max_message_size,
+      max_frame_size: self.max_frame_size,
+      max_group_name_length: self.max_group_name_length,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(text: &str) -> ChatResult<Config> {
+    Ok(toml::from_str(text)?)
+  }
+
+  fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_from(std::iter::once("server").chain(args.iter().copied()))
+      .unwrap()
+  }
+
+  #[test]
+  fn config_file_settings() {
+    let config = parse(r#"
+      max_message_size = 1024
+      max_groups = 10
+
+      [[listen]]
+      address = "127.0.0.1:8088"
+
+      [[listen]]
+      address = "127.0.0.1:8443"
+      tls = true
+      websocket = true
+
+      [tls]
+      certificate = "cert.pem"
+      key = "key.pem"
+
+      [groups]
+      capacity = 16
+      slow_consumers = "disconnect"
+      policies = { Announcements = "block" }
+    "#).unwrap();
+    assert!(config.validate().is_ok());
+
+    assert_eq!(config.listen, [
+      Listener {
+        address: "127.0.0.1:8088".to_string(),
+        tls: false,
+        websocket: false,
+      },
+      Listener {
+        address: "127.0.0.1:8443".to_string(),
+        tls: true,
+        websocket: true,
+      },
+    ]);
+    let settings = config.group_settings();
+    assert_eq!(settings.capacity, 16);
+    assert_eq!(settings.max_groups, Some(10));
+    assert_eq!(settings.policy_for("Rust"), SlowConsumerPolicy::Disconnect);
+    assert_eq!(settings.policy_for("Announcements"), SlowConsumerPolicy::Block);
+    assert_eq!(config.limits().max_message_size, 1024);
+
+    // Settings left out keep their defaults.
+    assert_eq!(config.log_level, Level::Info);
+    assert_eq!(config.log_format, Format::Text);
+    assert_eq!(config.groups.history_limit, 1000);
+  }
+
+  #[test]
+  fn config_file_mistakes_are_reported() {
+    assert!(parse("listen_on = \"127.0.0.1:8088\"").is_err());
+    assert!(parse("[groups]\nslow_consumers = \"ignore\"").is_err());
+    assert!(parse("log_level = \"loud\"").is_err());
+    assert!(parse("log_format = \"xml\"").is_err());
+
+    let no_listeners = parse("").unwrap();
+    assert!(no_listeners.validate().is_err());
+
+    let tls_without_certificate = parse(r#"
+      listen = [{ address = "127.0.0.1:8443", tls = true }]
+    "#).unwrap();
+    assert!(tls_without_certificate.validate().is_err());
+
+    let zero_capacity = parse(r#"
+      listen = [{ address = "127.0.0.1:8088" }]
+      groups = { capacity = 0 }
+    "#).unwrap();
+    assert!(zero_capacity.validate().is_err());
+
+    let small_frames = parse(r#"
+      listen = [{ address = "127.0.0.1:8088" }]
+      max_message_size = 2048
+      max_frame_size = 1024
+    "#).unwrap();
+    assert!(small_frames.validate().is_err());
+
+    let peers_without_secret = parse(r#"
+      listen = [{ address = "127.0.0.1:8088" }]
+      federation = { name = "east", peers = ["127.0.0.1:9088"] }
+    "#).unwrap();
+    assert!(peers_without_secret.validate().is_err());
+
+    let secret_without_name = parse(r#"
+      listen = [{ address = "127.0.0.1:8088" }]
+      federation = { secret = "hush" }
+    "#).unwrap();
+    assert!(secret_without_name.validate().is_err());
+  }
+
+  #[test]
+  fn command_line_overrides_config_file() {
+    let mut config = parse(r#"
+      log_level = "debug"
+      listen = [{ address = "127.0.0.1:8088" }]
+      groups = { capacity = 16 }
+    "#).unwrap();
+    config.apply(cli(&["127.0.0.1:9000", "--ws-listen", "127.0.0.1:9001",
+                       "--capacity", "32", "--group-policy", "a=b=block",
+                       "--accounts", "accounts.json",
+                       "--allow-registration", "--log-format", "json",
+                       "--server-name", "east", "--peer", "127.0.0.1:9088",
+                       "--peer-ca", "peers-ca.pem"]));
+    assert!(config.validate().is_err(), "peers need a secret");
+    config.federation.secret = Some("hush".to_string());
+    assert!(config.validate().is_ok());
+
+    assert_eq!(config.listen, [
+      Listener {
+        address: "127.0.0.1:9000".to_string(),
+        tls: false,
+        websocket: false,
+      },
+      Listener {
+        address: "127.0.0.1:9001".to_string(),
+        tls: false,
+        websocket: true,
+      },
+    ]);
+    assert_eq!(config.groups.capacity, 32);
+    assert_eq!(config.groups.policies["a=b"], SlowConsumerPolicy::Block);
+    assert_eq!(config.log_level, Level::Debug);
+    assert_eq!(config.log_format, Format::Json);
+    let accounts = config.accounts.unwrap();
+    assert_eq!(accounts.file, Path::new("accounts.json"));
+    assert!(accounts.allow_registration);
+    assert_eq!(config.federation.name.as_deref(), Some("east"));
+    assert_eq!(config.federation.peers, ["127.0.0.1:9088"]);
+    assert_eq!(config.federation.peer_ca.as_deref(),
+               Some(Path::new("peers-ca.pem")));
+  }
+
+  #[test]
+  fn command_line_mistakes_are_reported() {
+    let args = |args: &[&str]| {
+      Cli::try_parse_from(std::iter::once("server").chain(args.iter().copied()))
+    };
+    assert!(args(&["--capacity", "lots"]).is_err());
+    assert!(args(&["--slow-consumers", "ignore"]).is_err());
+    assert!(args(&["--group-policy", "Rust"]).is_err());
+    assert!(args(&["--tls-cert", "cert.pem"]).is_err());
+    assert!(args(&["--allow-registration"]).is_err());
+  }
+}
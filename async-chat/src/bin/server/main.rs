@@ -1,37 +1,214 @@
-use async_std::prelude::*;
+use async_chat::server::{self, admin, connection, federation, logging, Server};
+use async_chat::server::access::AccessTable;
+use async_chat::server::accounts::AccountStore;
+use async_chat::server::connection_table::ConnectionTable;
+use async_chat::server::group_table::GroupTable;
+use async_chat::server::user_table::UserTable;
 use async_chat::utils::ChatResult;
+use async_std::channel;
+use async_std::net::TcpListener;
+use async_std::task;
+use clap::Parser;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::future::Future;
 use std::sync::Arc;
+use tracing::{error, info, warn};
 
-mod connection;
-mod group;
-mod group_table;
+mod config;
 
-use connection::serve;
+use config::{Cli, Config};
 
-fn main() -> ChatResult<()> {
-  let address = std::env::args().nth(1).expect("Usage: server ADDRESS");
+fn main() {
+  let cli = Cli::parse();
+  if let Err(error) = run(cli) {
+    eprintln!("Error: {}", error);
+    std::process::exit(1);
+  }
+}
+
+fn run(mut cli: Cli) -> ChatResult<()> {
+  if let Some(nickname) = cli.add_account.take() {
+    let config = Config::read(cli)?;
+    logging::init(config.log_level, config.log_format);
+    return add_account(config, nickname);
+  }
+
+  let config = Config::from_cli(cli)?;
+  logging::init(config.log_level, config.log_format);
+
+  if let Some(directory) = &config.history_directory {
+    std::fs::create_dir_all(directory)?;
+  }
 
-  let chat_group_table = Arc::new(group_table::GroupTable::new());
+  let acceptor = match &config.tls {
+    Some(files) => Some(async_chat::tls::acceptor(&files.certificate, &files.key)?),
+    None => None,
+  };
 
-  async_std::task::block_on(async {
-    use async_std::{net, task};
+  let peer_connector = match &config.federation.peer_ca {
+    Some(ca_file) => Some(async_chat::tls::connector(ca_file)?),
+    None => {
+      if !config.federation.peers.is_empty() {
+        warn!("links to peers will carry the peer secret and relayed posts \
+               unencrypted");
+      }
+      None
+    }
+  };
+
+  let accounts = match &config.accounts {
+    Some(accounts) => {
+      for listener in config.listen.iter().filter(|listener| !listener.tls) {
+        warn!("clients on {} will send their passwords unencrypted",
+              listener.address);
+      }
+      Some(open_accounts(&accounts.file, accounts.allow_registration)?)
+    }
+    None => None,
+  };
+  let access = match &config.groups.access_file {
+    Some(path) => AccessTable::open(path).map_err(|error| {
+      format!("unable to read {}: {}", path.display(), error)
+    })?,
+    None => AccessTable::in_memory(),
+  };
+
+  let server = Arc::new(Server {
+    groups: Arc::new(GroupTable::new(config.group_settings(), access)),
+    users: UserTable::new(config.offline_queue_limit),
+    connections: Arc::new(ConnectionTable::new()),
+    limits: config.limits(),
+    accounts,
+    federation: Arc::new(config.federation()),
+  });
+  let stop = stop_on_signal()?;
 
-    let listener = net::TcpListener::bind(address).await?;
+  task::block_on(async {
+    // Bind every address before serving any, so that a typo in one is
+    // reported right away.
+    let mut listeners = vec![];
+    for listener in &config.listen {
+      let socket = TcpListener::bind(&listener.address).await
+        .map_err(|error| {
+          format!("unable to listen on {}: {}", listener.address, error)
+        })?;
+      let acceptor = if listener.tls { acceptor.clone() } else { None };
+      info!("Listening on {}{}{}", listener.address,
+            if listener.websocket { " for WebSocket clients" } else { "" },
+            if listener.tls { " with TLS" } else { "" });
+      listeners.push((socket, acceptor, listener.websocket));
+    }
+    let admin_listener = match &config.admin_listen {
+      Some(address) => {
+        let socket = TcpListener::bind(address).await
+          .map_err(|error| format!("unable to listen on {}: {}", address, error))?;
+        info!("Admin endpoint on {}", address);
+        Some(socket)
+      }
+      None => None,
+    };
+    let peer_links: Vec<_> = config.federation.peers.iter()
+      .map(|address| {
+        info!("Linking to peer {}{}", address,
+              if peer_connector.is_some() { " with TLS" } else { "" });
+        task::spawn(federation::link_to_peer(address.clone(),
+                                             peer_connector.clone(),
+                                             server.clone(), stop.clone()))
+      })
+      .collect();
+    let admin_loop = admin_listener.map(|socket| {
+      spawn_loop(admin::accept_loop(socket, server.clone(), stop.clone()),
+                 &stop)
+    });
+
+    let accept_loops: Vec<_> = listeners.into_iter()
+      .map(|(socket, acceptor, websocket)| {
+        spawn_loop(server::accept_loop(socket, acceptor, websocket,
+                                       server.clone(), stop.clone()),
+                   &stop)
+      })
+      .collect();
+    let mut failure = None;
+    for accept_loop in accept_loops.into_iter().chain(admin_loop) {
+      if let Err(error) = accept_loop.await {
+        failure.get_or_insert(error);
+      }
+    }
+    for peer_link in peer_links {
+      peer_link.await;
+    }
 
-    let mut new_connections = listener.incoming();
-    while let Some(socket_result) = new_connections.next().await {
-      let socket = socket_result?;
-      let groups = chat_group_table.clone();
-      task::spawn(async {
-        log_error(serve(socket, groups).await);
-      });
+    info!("Shutting down; {} connections open",
+          server.connections.outbounds().len());
+    server::shut_down(&server.connections).await;
+    server.groups.sync_to_disk().await;
+    match failure {
+      Some(error) => Err(error),
+      None => Ok(()),
     }
-    Ok(())
   })
 }
 
-fn log_error(result: ChatResult<()>) {
-  if let Err(error) = result {
-    eprintln!("Error: {}", error);
-  }
-}
\ No newline at end of file
+/// Spawn a task to run `accept_loop`. If it fails, log the error and close
+/// `stop`, so that the server shuts down as it would for a signal, rather
+/// than leaving its clients and histories behind.
+fn spawn_loop<F>(accept_loop: F, stop: &channel::Receiver<()>)
+  -> task::JoinHandle<ChatResult<()>>
+where
+  F: Future<Output = ChatResult<()>> + Send + 'static,
+{
+  let stop = stop.clone();
+  task::spawn(async move {
+    let result = accept_loop.await;
+    if let Err(error) = &result {
+      error!(%error, "unable to accept connections");
+      stop.close();
+    }
+    result
+  })
+}
+
+fn open_accounts(path: &std::path::Path, allow_registration: bool)
+  -> ChatResult<AccountStore>
+{
+  AccountStore::open(path, allow_registration)
+    .map_err(|error| format!("unable to read {}: {}", path.display(), error).into())
+}
+
+/// Create an account for `nickname` in the configured account file, with a
+/// password read from standard input.
+fn add_account(config: Config, nickname: String) -> ChatResult<()> {
+  let file = match &config.accounts {
+    Some(accounts) => &accounts.file,
+    None => return Err("--add-account needs an account file; use --accounts"
+                       .into()),
+  };
+  connection::validate_nickname(&nickname)?;
+  let accounts = open_accounts(file, false)?;
+
+  let mut password = String::new();
+  std::io::stdin().read_line(&mut password)?;
+  let password = password.trim_end_matches(['\r', '\n']).to_string();
+
+  task::block_on(accounts.create(&nickname, password))?;
+  info!("Created account '{}'", nickname);
+  Ok(())
+}
+
+/// Return a channel that is closed when the server receives SIGINT or
+/// SIGTERM. A second signal exits immediately, without cleaning up.
+fn stop_on_signal() -> ChatResult<channel::Receiver<()>> {
+  let (stop, stopped) = channel::bounded(1);
+  let mut signals = Signals::new([SIGINT, SIGTERM])?;
+  std::thread::spawn(move || {
+    let mut signals = signals.forever();
+    if signals.next().is_some() {
+      stop.close();
+    }
+    if signals.next().is_some() {
+      std::process::exit(1);
+    }
+  });
+  Ok(stopped)
+}
@@ -0,0 +1,388 @@
+//! A client for programs that chat, like bots.
+//!
+//! A `ChatClient` sends requests and waits for the server's answer to each,
+//! so that failures show up as errors where they happen. Everything else
+//! the server sends, like group messages, comes out of the client as a
+//! `Stream` of `FromServer` packets. A `Dispatcher` turns messages like
+//! `!roll 2d6` into calls to the handlers a bot registers.
+//!
+//! ```no_run
+//! # use async_chat::client::{ChatClient, Dispatcher};
+//! # async fn bot() -> async_chat::utils::ChatResult<()> {
+//! let mut client = ChatClient::connect("127.0.0.1:8088", "echo").await?;
+//! client.join("Rust").await?;
+//! let commands = Dispatcher::new("!")
+//!   .command("echo", "repeat what you say", |call| Some(call.args.clone()));
+//! commands.run(&mut client, |_event| {}).await
+//! # }
+//! ```
+
+use crate::codec::Codec;
+use crate::utils::ChatResult;
+use crate::{FromClient, FromServer, Request, SearchResult};
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+type Packets = Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>;
+
+/// A connection to a chat server, signed in under some nickname.
+pub struct ChatClient {
+  nickname: Arc<String>,
+  codec: Codec,
+  to_server: Box<dyn Write + Send + Unpin>,
+  from_server: Packets,
+  next_id: u64,
+  /// Packets that arrived while a request awaited its answer, for the
+  /// stream to return first.
+  pending: VecDeque<FromServer>,
+}
+
+/// The server's reason for refusing a request.
+#[derive(Debug)]
+pub struct Rejected {
+  pub reason: String,
+}
+
+impl fmt::Display for Rejected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "request refused: {}", self.reason)
+  }
+}
+
+impl std::error::Error for Rejected {}
+
+impl ChatClient {
+  /// Connect to the server at `address` over plain TCP, speaking JSON, and
+  /// say hello as `nickname`.
+  pub async fn connect(address: &str, nickname: &str)
+    -> ChatResult<ChatClient>
+  {
+    let socket = TcpStream::connect(address).await?;
+    socket.set_nodelay(true)?;
+    let mut client = ChatClient::over(socket, Codec::Json).await?;
+    client.hello(nickname).await?;
+    Ok(client)
+  }
+
+  /// Start a session over `socket`, which may be a TLS stream or anything
+  /// else carrying the protocol, in `codec`. The client must then say hello
+  /// or log in.
+  pub async fn over<S>(socket: S, codec: Codec) -> ChatResult<ChatClient>
+  where
+    S: Read + Write + Send + Unpin + 'static,
+  {
+    let (from_server, mut to_server) = futures_lite::io::split(socket);
+    codec.announce(&mut to_server).await?;
+    Ok(ChatClient {
+      nickname: Arc::new(String::new()),
+      codec,
+      to_server: Box::new(to_server),
+      from_server: Box::pin(codec.packets(BufReader::new(from_server))),
+      next_id: 0,
+      pending: VecDeque::new(),
+    })
+  }
+
+  /// The nickname we're signed in as, or the empty string if we aren't.
+  pub fn nickname(&self) -> &Arc<String> {
+    &self.nickname
+  }
+
+  pub async fn hello(&mut self, nickname: &str) -> ChatResult<()> {
+    let nickname = Arc::new(nickname.to_string());
+    self.request(FromClient::Hello { nickname: nickname.clone() }).await?;
+    self.nickname = nickname;
+    Ok(())
+  }
+
+  pub async fn login(&mut self, nickname: &str, password: &str)
+    -> ChatResult<()>
+  {
+    let nickname = Arc::new(nickname.to_string());
+    self.request(FromClient::Login {
+      nickname: nickname.clone(),
+      password: password.to_string(),
+    }).await?;
+    self.nickname = nickname;
+    Ok(())
+  }
+
+  /// Join `group_name`, without replaying any of its history.
+  pub async fn join(&mut self, group_name: &str) -> ChatResult<()> {
+    let group_name = Arc::new(group_name.to_string());
+    self.request(FromClient::Join { group_name, replay: None }).await?;
+    Ok(())
+  }
+
+  pub async fn leave(&mut self, group_name: &str) -> ChatResult<()> {
+    let group_name = Arc::new(group_name.to_string());
+    self.request(FromClient::Leave { group_name }).await?;
+    Ok(())
+  }
+
+  pub async fn post(&mut self, group_name: &str, message: &str)
+    -> ChatResult<()>
+  {
+    self.request(FromClient::Post {
+      group_name: Arc::new(group_name.to_string()),
+      message: Arc::new(message.to_string()),
+    }).await?;
+    Ok(())
+  }
+
+  /// Tell the other members of `group_name` that we're typing. The server
+  /// ignores all but one of these every few seconds, so it's fine to call
+  /// this on every keystroke.
+  pub async fn typing(&mut self, group_name: &str) -> ChatResult<()> {
+    let group_name = Arc::new(group_name.to_string());
+    self.request(FromClient::Typing { group_name }).await?;
+    Ok(())
+  }
+
+  /// Send `message` to the user `to` alone.
+  pub async fn send_direct(&mut self, to: &str, message: &str)
+    -> ChatResult<()>
+  {
+    self.request(FromClient::DirectMessage {
+      to: Arc::new(to.to_string()),
+      message: Arc::new(message.to_string()),
+    }).await?;
+    Ok(())
+  }
+
+  /// Return up to `limit` messages in `group_name` that contain every word
+  /// of `query`, newest first. We must have joined the group.
+  pub async fn search(&mut self, group_name: &str, query: &str, limit: usize)
+    -> ChatResult<Vec<SearchResult>>
+  {
+    let reply = self.request(FromClient::Search {
+      group_name: Arc::new(group_name.to_string()),
+      query: Arc::new(query.to_string()),
+      limit,
+    }).await?;
+    match reply {
+      Some(FromServer::SearchResults { results, .. }) => Ok(results),
+      _ => Err("server sent no search results".into()),
+    }
+  }
+
+  /// Send `body`, and wait for the server's answer. Return the reply that
+  /// came with the `Ack`, if any, like `Groups` for `ListGroups`. If the
+  /// server sends a `Nack`, return a `Rejected` error.
+  pub async fn request(&mut self, body: FromClient)
+    -> ChatResult<Option<FromServer>>
+  {
+    self.next_id += 1;
+    let id = self.next_id;
+    self.codec.send(&mut self.to_server, &Request { id, body }).await?;
+    self.to_server.flush().await?;
+
+    let mut reply = None;
+    loop {
+      match self.from_server.next().await.transpose()? {
+        Some(FromServer::Ack { id: answered }) if answered == id => {
+          return Ok(reply);
+        }
+        Some(FromServer::Nack { id: answered, reason }) if answered == id => {
+          return Err(Rejected { reason }.into());
+        }
+        Some(packet @ FromServer::Groups { .. }) |
+        Some(packet @ FromServer::Members { .. }) |
+        Some(packet @ FromServer::SearchResults { .. }) => {
+          reply = Some(packet)
+        }
+        Some(packet) => self.pending.push_back(packet),
+        None => return Err("server closed the connection".into()),
+      }
+    }
+  }
+}
+
+/// The packets the server sends that aren't answers to our requests. The
+/// stream ends when the server closes the connection.
+impl Stream for ChatClient {
+  type Item = ChatResult<FromServer>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)
+    -> Poll<Option<Self::Item>>
+  {
+    if let Some(packet) = self.pending.pop_front() {
+      return Poll::Ready(Some(Ok(packet)));
+    }
+    loop {
+      match ready!(self.from_server.as_mut().poll_next(cx)) {
+        Some(Ok(FromServer::Ack { .. })) => continue,
+        other => return Poll::Ready(other),
+      }
+    }
+  }
+}
+
+/// A command posted to a group for a bot, like `!roll 2d6`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+  pub group_name: Arc<String>,
+  pub sender: Arc<String>,
+  /// The command's name, without the prefix: `roll`.
+  pub name: String,
+  /// Whatever followed the name, trimmed: `2d6`.
+  pub args: String,
+}
+
+type Handler = Box<dyn Fn(&Invocation) -> Option<String> + Send + Sync>;
+
+/// A bot's commands, and the handlers that answer them. A handler returns
+/// the reply to post to the group the command came from, if any.
+///
+/// Unless the bot registers a `help` command of its own, `help` lists the
+/// others.
+pub struct Dispatcher {
+  prefix: String,
+  commands: BTreeMap<String, (String, Handler)>,
+}
+
+impl Dispatcher {
+  /// Return a dispatcher for commands that begin with `prefix`, like `!`.
+  pub fn new(prefix: &str) -> Dispatcher {
+    Dispatcher { prefix: prefix.to_string(), commands: BTreeMap::new() }
+  }
+
+  /// Answer the command `name` with `handler`. `summary` describes the
+  /// command for `help`.
+  pub fn command<F>(mut self, name: &str, summary: &str, handler: F)
+    -> Dispatcher
+  where
+    F: Fn(&Invocation) -> Option<String> + Send + Sync + 'static,
+  {
+    self.commands.insert(name.to_string(),
+                         (summary.to_string(), Box::new(handler)));
+    self
+  }
+
+  /// If `packet` is a group message that begins with our prefix, return
+  /// the command it invokes.
+  pub fn parse(&self, packet: &FromServer) -> Option<Invocation> {
+    let (group_name, sender, message) = match packet {
+      FromServer::Message { group_name, sender, message, .. } => {
+        (group_name, sender, message)
+      }
+      _ => return None,
+    };
+    let command = message.strip_prefix(&self.prefix)?;
+    let (name, args) = command.split_once(char::is_whitespace)
+      .unwrap_or((command, ""));
+    if name.is_empty() {
+      return None;
+    }
+    Some(Invocation {
+      group_name: group_name.clone(),
+      sender: sender.clone(),
+      name: name.to_string(),
+      args: args.trim().to_string(),
+    })
+  }
+
+  /// Run the handler for the command `invocation` names, and return its
+  /// reply. Commands we don't know get no reply.
+  pub fn dispatch(&self, invocation: &Invocation) -> Option<String> {
+    match self.commands.get(&invocation.name) {
+      Some((_summary, handler)) => handler(invocation),
+      None if invocation.name == "help" => Some(self.help()),
+      None => None,
+    }
+  }
+
+  fn help(&self) -> String {
+    let commands: Vec<String> = self.commands.iter()
+      .map(|(name, (summary, _handler))| {
+        format!("{}{}: {}", self.prefix, name, summary)
+      })
+      .collect();
+    commands.join("; ")
+  }
+
+  /// Answer commands arriving on `client` until the server closes the
+  /// connection, passing every packet to `observe` first. Commands the
+  /// client itself posts are ignored, so that a bot can't talk to itself.
+  ///
+  /// If the server refuses a reply, say because it's empty or too long,
+  /// `observe` is passed the `Nack`, and the bot carries on. Only trouble
+  /// with the connection itself ends the run with an error.
+  pub async fn run<F>(&self, client: &mut ChatClient, mut observe: F)
+    -> ChatResult<()>
+  where
+    F: FnMut(&FromServer),
+  {
+    while let Some(packet) = client.next().await {
+      let packet = packet?;
+      observe(&packet);
+      let invocation = match self.parse(&packet) {
+        Some(invocation) if invocation.sender != *client.nickname() => {
+          invocation
+        }
+        _ => continue,
+      };
+      let reply = match self.dispatch(&invocation) {
+        Some(reply) => reply,
+        None => continue,
+      };
+      if let Err(error) = client.post(&invocation.group_name, &reply).await {
+        let rejected = error.downcast::<Rejected>()?;
+        observe(&FromServer::Nack {
+          id: client.next_id,
+          reason: rejected.reason,
+        });
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(text: &str) -> FromServer {
+    FromServer::Message {
+      group_name: Arc::new("Crabs".to_string()),
+      sender: Arc::new("alice".to_string()),
+      timestamp: 0,
+      message: Arc::new(text.to_string()),
+      id: Arc::new("1".to_string()),
+    }
+  }
+
+  #[test]
+  fn commands_are_parsed_and_dispatched() {
+    let commands = Dispatcher::new("!")
+      .command("echo", "repeat", |call| Some(call.args.clone()))
+      .command("quiet", "say nothing", |_call| None);
+
+    let call = commands.parse(&message("!echo  hello there ")).unwrap();
+    assert_eq!(call.name, "echo");
+    assert_eq!(call.args, "hello there");
+    assert_eq!(*call.sender, "alice");
+    assert_eq!(commands.dispatch(&call).as_deref(), Some("hello there"));
+
+    let call = commands.parse(&message("!quiet")).unwrap();
+    assert_eq!(call.args, "");
+    assert_eq!(commands.dispatch(&call), None);
+
+    let call = commands.parse(&message("!help")).unwrap();
+    assert_eq!(commands.dispatch(&call).as_deref(),
+               Some("!echo: repeat; !quiet: say nothing"));
+
+    let call = commands.parse(&message("!unknown")).unwrap();
+    assert_eq!(commands.dispatch(&call), None);
+
+    assert_eq!(commands.parse(&message("echo hello")), None);
+    assert_eq!(commands.parse(&message("! echo")), None);
+    assert_eq!(commands.parse(&FromServer::Shutdown), None);
+  }
+}
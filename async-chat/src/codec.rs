@@ -0,0 +1,361 @@
+//! Wire formats for chat packets.
+//!
+//! Every connection starts out speaking newline-delimited JSON, which is easy
+//! to read and type by hand. A client that would rather use the compact
+//! MessagePack framing announces so with a preamble before its first packet:
+//! a zero byte, the codec's name, and a newline. JSON never begins with a
+//! zero byte, so the server can tell the two apart from the first byte, and
+//! then answers in whichever codec the client chose.
+
+use crate::utils::{self, ChatError, ChatResult};
+use async_std::io::{BufRead, Write};
+use async_std::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::marker::Unpin;
+use std::str::FromStr;
+
+/// The largest frame we're willing to buffer by default, in bytes: a JSON
+/// line, not counting its newline, or a MessagePack body.
+pub const MAX_FRAME_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// The byte that begins a codec preamble.
+const PREAMBLE_START: u8 = 0;
+
+/// The longest preamble we'll read before giving up on it.
+const MAX_PREAMBLE_LENGTH: u64 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+  /// One JSON document per line.
+  Json,
+  /// Each packet is a big-endian `u32` byte count, followed by that many
+  /// bytes of MessagePack, with structs encoded as maps.
+  MessagePack,
+}
+
+impl Codec {
+  /// Read the preamble, if any, at the start of a connection, and return
+  /// the codec the client asked for. Clients that send no preamble get
+  /// JSON.
+  pub async fn negotiate<R>(inbound: &mut R) -> ChatResult<Codec>
+  where
+    R: BufRead + Unpin,
+  {
+    // `async_std::io::BufReadExt` has no `fill_buf`, so borrow futures-lite's.
+    let buffer = futures_lite::AsyncBufReadExt::fill_buf(inbound).await?;
+    if buffer.first() != Some(&PREAMBLE_START) {
+      return Ok(Codec::Json);
+    }
+
+    let mut preamble = Vec::new();
+    (&mut *inbound).take(MAX_PREAMBLE_LENGTH)
+      .read_until(b'\n', &mut preamble)
+      .await?;
+    if preamble.last() != Some(&b'\n') {
+      return Err("unterminated codec preamble".into());
+    }
+
+    let name = std::str::from_utf8(&preamble[1..preamble.len() - 1])?;
+    name.parse()
+  }
+
+  /// Write the preamble requesting this codec, if it needs one.
+  pub async fn announce<W>(self, outbound: &mut W) -> ChatResult<()>
+  where
+    W: Write + Unpin,
+  {
+    if self == Codec::Json {
+      return Ok(());
+    }
+
+    let preamble = format!("\0{}\n", self);
+    outbound.write_all(preamble.as_bytes()).await?;
+    Ok(())
+  }
+
+  /// Write `packet` to `outbound`. As with `utils::send_as_json`, the
+  /// caller is responsible for flushing.
+  pub async fn send<W, P>(self, outbound: &mut W, packet: &P) -> ChatResult<()>
+  where
+    W: Write + Unpin,
+    P: Serialize,
+  {
+    match self {
+      Codec::Json => utils::send_as_json(outbound, packet).await,
+      Codec::MessagePack => {
+        let body = rmp_serde::to_vec_named(packet)?;
+        let length = u32::try_from(body.len())
+          .ok()
+          .filter(|&length| length <= MAX_FRAME_LENGTH)
+          .ok_or("packet too large to send")?;
+
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(&body);
+        outbound.write_all(&frame).await?;
+        Ok(())
+      }
+    }
+  }
+
+  /// Return a stream of the packets read from `inbound`. The stream ends
+  /// when `inbound` does, or just after the first error, since we can't
+  /// find the next packet's boundary after that.
+  pub fn packets<R, P>(self, inbound: R) -> impl Stream<Item = ChatResult<P>>
+  where
+    R: BufRead + Unpin,
+    P: DeserializeOwned,
+  {
+    self.packets_within(inbound, MAX_FRAME_LENGTH as usize)
+  }
+
+  /// Like `packets`, but treat any frame longer than `max_length` bytes as
+  /// an error, rather than `MAX_FRAME_LENGTH`.
+  pub fn packets_within<R, P>(self, inbound: R, max_length: usize)
+    -> impl Stream<Item = ChatResult<P>>
+  where
+    R: BufRead + Unpin,
+    P: DeserializeOwned,
+  {
+    futures_lite::stream::unfold(Some(inbound), move |inbound| async move {
+      let mut inbound = inbound?;
+      match self.receive_within(&mut inbound, max_length).await {
+        Ok(Some(packet)) => Some((Ok(packet), Some(inbound))),
+        Ok(None) => None,
+        Err(error) => Some((Err(error), None)),
+      }
+    })
+  }
+
+  /// Read the next packet from `inbound`, or return `None` if the stream
+  /// ended cleanly between packets.
+  pub async fn receive<R, P>(self, inbound: &mut R) -> ChatResult<Option<P>>
+  where
+    R: BufRead + Unpin,
+    P: DeserializeOwned,
+  {
+    self.receive_within(inbound, MAX_FRAME_LENGTH as usize).await
+  }
+
+  /// Like `receive`, but treat any frame longer than `max_length` bytes as
+  /// an error, rather than `MAX_FRAME_LENGTH`. We never buffer more than
+  /// that, however long the frame claims to be.
+  pub async fn receive_within<R, P>(self, inbound: &mut R, max_length: usize)
+    -> ChatResult<Option<P>>
+  where
+    R: BufRead + Unpin,
+    P: DeserializeOwned,
+  {
+    if futures_lite::AsyncBufReadExt::fill_buf(inbound).await?.is_empty() {
+      return Ok(None);
+    }
+
+    match self {
+      Codec::Json => {
+        // Read at most one byte past the limit: enough to see the newline
+        // that ends a line of exactly `max_length` bytes.
+        let mut line = Vec::new();
+        (&mut *inbound).take(max_length as u64 + 1)
+          .read_until(b'\n', &mut line)
+          .await?;
+        if line.last() != Some(&b'\n') && line.len() > max_length {
+          return Err(FrameTooLong { max_length }.into());
+        }
+        Ok(Some(serde_json::from_slice(&line)?))
+      }
+      Codec::MessagePack => {
+        let mut length = [0; 4];
+        inbound.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length);
+        if length as usize > max_length {
+          return Err(FrameTooLong { max_length }.into());
+        }
+
+        let mut body = vec![0; length as usize];
+        inbound.read_exact(&mut body).await?;
+        Ok(Some(rmp_serde::from_slice(&body)?))
+      }
+    }
+  }
+}
+
+/// The error for a frame longer than the receiver allows. The connection is
+/// unusable afterwards, since the rest of the frame is still unread.
+#[derive(Debug)]
+pub struct FrameTooLong {
+  pub max_length: usize,
+}
+
+impl fmt::Display for FrameTooLong {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "frame is longer than the limit of {} bytes", self.max_length)
+  }
+}
+
+impl std::error::Error for FrameTooLong {}
+
+impl fmt::Display for Codec {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Codec::Json => "json",
+      Codec::MessagePack => "messagepack",
+    })
+  }
+}
+
+impl FromStr for Codec {
+  type Err = ChatError;
+
+  fn from_str(name: &str) -> ChatResult<Codec> {
+    match name {
+      "json" => Ok(Codec::Json),
+      "messagepack" => Ok(Codec::MessagePack),
+      _ => Err(format!("unknown codec '{}'", name).into()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{FromClient, FromServer, Replay, Request};
+  use async_std::io::{BufReader, Cursor};
+  use async_std::task;
+  use std::sync::Arc;
+
+  fn packets() -> Vec<Request> {
+    let bodies = vec![
+      FromClient::Hello { nickname: Arc::new("ferris".to_string()) },
+      FromClient::Join {
+        group_name: Arc::new("Crabs".to_string()),
+        replay: Some(Replay::LastN(10)),
+      },
+      FromClient::Post {
+        group_name: Arc::new("Crabs".to_string()),
+        message: Arc::new("line one\nline two".to_string()),
+      },
+      FromClient::ListGroups,
+    ];
+    bodies.into_iter()
+      .zip(1..)
+      .map(|(body, id)| Request { id, body })
+      .collect()
+  }
+
+  /// Announce `codec`, send `packets()` in it, and check that the server
+  /// side negotiates the same codec and reads the same packets back.
+  fn round_trip(codec: Codec) {
+    task::block_on(async {
+      let mut wire = Vec::new();
+      codec.announce(&mut wire).await.unwrap();
+      for packet in packets() {
+        codec.send(&mut wire, &packet).await.unwrap();
+      }
+
+      let mut inbound = BufReader::new(Cursor::new(wire));
+      assert_eq!(Codec::negotiate(&mut inbound).await.unwrap(), codec);
+      let received: Vec<Request> = codec.packets(inbound)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+      assert_eq!(received, packets());
+    });
+  }
+
+  #[test]
+  fn test_json_round_trip() {
+    round_trip(Codec::Json);
+  }
+
+  #[test]
+  fn test_messagepack_round_trip() {
+    round_trip(Codec::MessagePack);
+  }
+
+  #[test]
+  fn test_messagepack_is_smaller() {
+    task::block_on(async {
+      let packet = FromServer::Message {
+        group_name: Arc::new("Crabs".to_string()),
+        sender: Arc::new("ferris".to_string()),
+        timestamp: 1615734566,
+        message: Arc::new("hello".to_string()),
+        id: Arc::new("east:18e2b7c4a10:1".to_string()),
+      };
+      let mut json = Vec::new();
+      Codec::Json.send(&mut json, &packet).await.unwrap();
+      let mut messagepack = Vec::new();
+      Codec::MessagePack.send(&mut messagepack, &packet).await.unwrap();
+      assert!(messagepack.len() < json.len());
+    });
+  }
+
+  #[test]
+  fn test_bad_preambles() {
+    task::block_on(async {
+      let mut unknown = BufReader::new(Cursor::new(b"\0bincode\n".to_vec()));
+      assert!(Codec::negotiate(&mut unknown).await.is_err());
+
+      let endless = [&b"\0"[..], &[b'x'; 100]].concat();
+      let mut endless = BufReader::new(Cursor::new(endless));
+      assert!(Codec::negotiate(&mut endless).await.is_err());
+    });
+  }
+
+  #[test]
+  fn test_oversized_frame_is_rejected() {
+    task::block_on(async {
+      let frame = (MAX_FRAME_LENGTH + 1).to_be_bytes().to_vec();
+      let mut inbound = BufReader::new(Cursor::new(frame));
+      let result = Codec::MessagePack.receive::<_, FromClient>(&mut inbound);
+      assert!(result.await.is_err());
+    });
+  }
+
+  #[test]
+  fn test_long_json_line_is_rejected() {
+    task::block_on(async {
+      let packet = FromClient::Hello { nickname: Arc::new("ferris".to_string()) };
+      let mut wire = Vec::new();
+      Codec::Json.send(&mut wire, &packet).await.unwrap();
+      let length = wire.len() - 1;
+
+      // A line exactly at the limit is fine...
+      let mut inbound = BufReader::new(Cursor::new(wire.clone()));
+      let received = Codec::Json.receive_within(&mut inbound, length).await;
+      assert_eq!(received.unwrap(), Some(packet));
+
+      // ...but one byte over is not, even without a newline to find.
+      let mut inbound = BufReader::new(Cursor::new(wire));
+      let received = Codec::Json
+        .receive_within::<_, FromClient>(&mut inbound, length - 1)
+        .await;
+      assert!(received.unwrap_err().is::<FrameTooLong>());
+
+      let endless = vec![b' '; 1000];
+      let mut inbound = BufReader::new(Cursor::new(endless));
+      let received = Codec::Json
+        .receive_within::<_, FromClient>(&mut inbound, 100)
+        .await;
+      assert!(received.unwrap_err().is::<FrameTooLong>());
+    });
+  }
+
+  #[test]
+  fn test_long_messagepack_frame_is_rejected() {
+    task::block_on(async {
+      let packet = FromClient::Hello { nickname: Arc::new("ferris".to_string()) };
+      let mut wire = Vec::new();
+      Codec::MessagePack.send(&mut wire, &packet).await.unwrap();
+      let length = wire.len() - 4;
+
+      let mut inbound = BufReader::new(Cursor::new(wire));
+      let received = Codec::MessagePack
+        .receive_within::<_, FromClient>(&mut inbound, length - 1)
+        .await;
+      assert!(received.unwrap_err().is::<FrameTooLong>());
+    });
+  }
+}
@@ -1,24 +1,251 @@
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+pub mod client;
+pub mod codec;
+pub mod server;
+pub mod tls;
 pub mod utils;
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+/// A request from a client, tagged with an ID of the client's choosing. The
+/// server answers every request with an `Ack` or `Nack` bearing the same ID.
+/// IDs only identify requests within a connection: a client that resends
+/// unanswered requests after reconnecting may have a post delivered twice.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Request {
+    pub id: u64,
+    pub body: FromClient,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromClient {
-    Join { group_name: Arc<String> },
+    /// Introduce ourselves. On servers without accounts, this must precede
+    /// any other request.
+    Hello { nickname: Arc<String> },
+    /// Sign in to an account. On servers with accounts, this takes the
+    /// place of `Hello`.
+    Login {
+        nickname: Arc<String>,
+        password: String,
+    },
+    /// Create an account, if the server allows it. This doesn't log in.
+    Register {
+        nickname: Arc<String>,
+        password: String,
+    },
+    Join {
+        group_name: Arc<String>,
+        #[serde(default)]
+        replay: Option<Replay>,
+    },
     Post {
         group_name: Arc<String>,
         message: Arc<String>,
-    }
+    },
+    /// Change the text of the message `id` in `group_name` to `message`.
+    /// Only the message's author, or an owner of the group, may do this;
+    /// only owners may change messages relayed from other servers.
+    Edit {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        message: Arc<String>,
+    },
+    /// Remove the message `id` from `group_name`, on the same terms as
+    /// `Edit`.
+    Delete {
+        group_name: Arc<String>,
+        id: Arc<String>,
+    },
+    /// Respond to the message `id` in `group_name` with `reaction`, a short
+    /// string like an emoji.
+    React {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        reaction: Arc<String>,
+    },
+    Leave { group_name: Arc<String> },
+    /// Tell the other members of `group_name` that we're typing. The server
+    /// passes this along at most once every few seconds for each member,
+    /// and quietly ignores the rest.
+    Typing { group_name: Arc<String> },
+    /// Send `message` to the user named `to` alone.
+    DirectMessage {
+        to: Arc<String>,
+        message: Arc<String>,
+    },
+    /// Look for messages in the history of `group_name`, which we must have
+    /// joined, that contain every word of `query`, ignoring case and
+    /// punctuation. The server replies with `SearchResults` holding at most
+    /// `limit` of them, newest first, and may return fewer.
+    Search {
+        group_name: Arc<String>,
+        query: Arc<String>,
+        limit: usize,
+    },
+    ListGroups,
+    /// Ask who is in `group_name`, which we must have joined.
+    ListMembers { group_name: Arc<String> },
+    /// Change what a user may do in a group. Only the group's owners may do
+    /// this. A `nickname` of `None` sets the role of everyone without one of
+    /// their own, and a `role` of `None` keeps them out of the group,
+    /// ending the subscriptions of any who are members now.
+    SetRole {
+        group_name: Arc<String>,
+        nickname: Option<Arc<String>>,
+        role: Option<Role>,
+    },
+    /// Introduce ourselves as another server, to exchange posts in groups
+    /// with the same names. This takes the place of `Hello`, and requires
+    /// the secret the servers share.
+    Peer {
+        server_name: Arc<String>,
+        secret: String,
+    },
+    /// Pass along a message posted to `group_name` on some server. Only
+    /// peers may send these; see `FromServer::Message` for the fields.
+    Relay {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        sender: Arc<String>,
+        timestamp: u64,
+        message: Arc<String>,
+    },
+}
+
+/// What a user may do in a group. Whoever creates a group is its owner.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Role {
+    /// May post, and change other users' roles.
+    Owner,
+    /// May post.
+    Member,
+    /// May join and read, but not post.
+    ReadOnly,
+}
+
+/// Which of a group's past messages to send a client when it joins, before
+/// any new ones.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Replay {
+    /// Messages posted at or after the given time, in seconds since the
+    /// Unix epoch.
+    Since(u64),
+    /// The given number of most recent messages.
+    LastN(usize),
+}
+
+/// A message found by `FromClient::Search`, with the same fields as
+/// `FromServer::Message`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SearchResult {
+    pub id: Arc<String>,
+    pub sender: Arc<String>,
+    pub timestamp: u64,
+    pub message: Arc<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromServer {
     Message {
         group_name: Arc<String>,
+        sender: Arc<String>,
+        /// When the server received the message, in seconds since the Unix
+        /// epoch.
+        timestamp: u64,
         message: Arc<String>,
+        /// Identifies this message on every server it reaches.
+        id: Arc<String>,
     },
-    Error(String),
+    /// The message `id` in `group_name` now reads `message`, as changed by
+    /// `editor`. Members who join later are sent the changed text.
+    Edited {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        editor: Arc<String>,
+        message: Arc<String>,
+    },
+    /// The message `id` in `group_name` was removed by `by`.
+    Deleted {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        by: Arc<String>,
+    },
+    /// `sender` responded to the message `id` in `group_name` with
+    /// `reaction`.
+    Reacted {
+        group_name: Arc<String>,
+        id: Arc<String>,
+        sender: Arc<String>,
+        reaction: Arc<String>,
+    },
+    /// Who is in `group_name`, sent when we join it, after any history we
+    /// asked for. `Joined` and `Left` packets keep it up to date.
+    Presence {
+        group_name: Arc<String>,
+        members: Vec<Arc<String>>,
+    },
+    /// `nickname` joined `group_name`. A user who joins on several
+    /// connections at once counts only once.
+    Joined {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// `nickname` left `group_name`, or lost their connection. If
+    /// `nickname` is our own, an owner has taken away our role there, and
+    /// we will receive nothing more from the group.
+    Left {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// `nickname` is typing in `group_name`.
+    Typing {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// A message sent to us alone. If we were offline when it was sent,
+    /// it is delivered when we next say hello.
+    DirectMessage {
+        from: Arc<String>,
+        timestamp: u64,
+        message: Arc<String>,
+    },
+    /// The names of every group on the server, in reply to `ListGroups`.
+    Groups { group_names: Vec<Arc<String>> },
+    /// The nicknames of everyone in a group, in reply to `ListMembers`.
+    Members {
+        group_name: Arc<String>,
+        members: Vec<Arc<String>>,
+    },
+    /// The messages matching a `Search` of `group_name`, newest first.
+    SearchResults {
+        group_name: Arc<String>,
+        results: Vec<SearchResult>,
+    },
+    /// The request with the given ID succeeded. Any other reply it calls
+    /// for, like `Groups`, comes first. For a `Post`, this means the server
+    /// has accepted the message and passed it along to the group.
+    Ack { id: u64 },
+    /// The request with the given ID failed, and had no effect.
+    Nack { id: u64, reason: String },
+    /// Something went wrong other than a request failing. If a particular
+    /// request brought it on, `id` identifies it.
+    Error {
+        id: Option<u64>,
+        message: String,
+    },
+    /// The server is shutting down, and will close the connection once
+    /// this packet has been sent.
+    Shutdown,
+}
+
+impl FromServer {
+    /// Return true if this packet only says who is in a group or typing
+    /// there, which clients that don't show such things may ignore.
+    pub fn is_presence(&self) -> bool {
+        matches!(self,
+                 FromServer::Presence { .. } | FromServer::Joined { .. } |
+                 FromServer::Left { .. } | FromServer::Typing { .. })
+    }
 }
 
 #[test]
@@ -32,8 +259,31 @@ fn test_fromclient_json() {
 
     let json = serde_json::to_string(&from_client).unwrap();
     assert_eq!(json,
-                r#"{"Post":{"group_name":"Dogs","message":"Samoyeds rock!"})"#);
+                r#"{"Post":{"group_name":"Dogs","message":"Samoyeds rock!"}}"#);
     
     assert_eq!(serde_json::from_str::<FromClient>(&json).unwrap(),
                 from_client);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_request_json() {
+    let request = Request { id: 7, body: FromClient::ListGroups };
+    let json = serde_json::to_string(&request).unwrap();
+    assert_eq!(json, r#"{"id":7,"body":"ListGroups"}"#);
+    assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+
+    let nack = FromServer::Nack { id: 7, reason: "No".to_string() };
+    assert_eq!(serde_json::to_string(&nack).unwrap(),
+               r#"{"Nack":{"id":7,"reason":"No"}}"#);
+}
+
+#[test]
+fn test_join_replay_is_optional() {
+    let join = serde_json::from_str::<FromClient>(r#"{"Join":{"group_name":"Dogs"}}"#)
+        .unwrap();
+    assert_eq!(join,
+               FromClient::Join {
+                   group_name: Arc::new("Dogs".to_string()),
+                   replay: None,
+               });
+}
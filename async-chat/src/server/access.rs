@@ -0,0 +1,306 @@
+use crate::Role;
+use async_std::channel;
+use async_std::task;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+/// Who may do what in each group.
+///
+/// A group's access list is created along with the group, making its creator
+/// the owner, and lasts until the group table tells us to `forget` it. The
+/// table keeps the lists of groups whose history is kept on disk, so that if
+/// such a group is dropped and later recreated, its old owners still control
+/// who may read that history. With a file, access lists survive restarts
+/// too. Changes are made under the group table's lock, so the file is
+/// written by a separate task, like a history's log; use `sync` to wait for
+/// it.
+pub struct AccessTable {
+  lists: Mutex<HashMap<Arc<String>, AccessList>>,
+  /// Our end of the task saving the lists to our file, if we have one.
+  saves: Option<channel::Sender<Save>>,
+}
+
+/// A request for `write_lists`.
+enum Save {
+  /// Replace the file's contents with these lists.
+  Lists(HashMap<Arc<String>, AccessList>),
+  /// Say when everything queued so far has been written.
+  Sync(channel::Sender<()>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct AccessList {
+  /// The roles of particular users.
+  roles: HashMap<Arc<String>, Role>,
+  /// The role of everyone not in `roles`, or `None` if they may not join.
+  public: Option<Role>,
+}
+
+impl AccessList {
+  /// Return the list for a new group owned by `owner`, which anyone may
+  /// join and post to.
+  fn new(owner: Arc<String>) -> AccessList {
+    AccessList {
+      roles: HashMap::from([(owner, Role::Owner)]),
+      public: Some(Role::Member),
+    }
+  }
+
+  fn role(&self, nickname: &Arc<String>) -> Option<Role> {
+    self.roles.get(nickname).copied().or(self.public)
+  }
+}
+
+impl AccessTable {
+  /// Return a table that forgets everything when the server exits.
+  pub fn in_memory() -> AccessTable {
+    AccessTable { lists: Mutex::new(HashMap::new()), saves: None }
+  }
+
+  /// Load the access lists saved in `path`. If the file doesn't exist yet,
+  /// start with none; it is created when the first group is.
+  pub fn open(path: &Path) -> io::Result<AccessTable> {
+    let lists = match std::fs::read(path) {
+      Ok(json) => serde_json::from_slice(&json)?,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+      Err(error) => return Err(error),
+    };
+    let (saves, queued) = channel::unbounded();
+    task::spawn(write_lists(path.to_owned(), queued));
+    Ok(AccessTable { lists: Mutex::new(lists), saves: Some(saves) })
+  }
+
+  /// Check that `nickname` may join `group_name`. If the group has no
+  /// access list, because it has never existed or we forgot it, they may.
+  pub fn check_join(&self, group_name: &Arc<String>, nickname: &Arc<String>)
+    -> Result<(), String>
+  {
+    let lists = self.lists.lock().unwrap();
+    match lists.get(group_name) {
+      Some(list) => match list.role(nickname) {
+        Some(_) => Ok(()),
+        None => Err(format!("You may not join '{}'", group_name)),
+      },
+      None => Ok(()),
+    }
+  }
+
+  /// Make `owner` the owner of `group_name`, which they have just created,
+  /// unless it already has an access list.
+  pub fn claim(&self, group_name: &Arc<String>, owner: &Arc<String>) {
+    let mut lists = self.lists.lock().unwrap();
+    if !lists.contains_key(group_name) {
+      lists.insert(group_name.clone(), AccessList::new(owner.clone()));
+      self.save(&lists);
+    }
+  }
+
+  /// Drop `group_name`'s access list, along with the group. Whoever creates
+  /// the group next will own it.
+  pub fn forget(&self, group_name: &Arc<String>) {
+    let mut lists = self.lists.lock().unwrap();
+    if lists.remove(group_name).is_some() {
+      self.save(&lists);
+    }
+  }
+
+  /// Check that `nickname` may post to `group_name`.
+  pub fn check_post(&self, group_name: &Arc<String>, nickname: &Arc<String>)
+    -> Result<(), String>
+  {
+    let role = self.lists.lock()
+      .unwrap()
+      .get(group_name)
+      .and_then(|list| list.role(nickname));
+    match role {
+      Some(Role::Owner) | Some(Role::Member) => Ok(()),
+      Some(Role::ReadOnly) | None => {
+        Err(format!("You may not post to '{}'", group_name))
+      }
+    }
+  }
+
+  /// Return true if `nickname` is an owner of `group_name`.
+  pub fn is_owner(&self, group_name: &Arc<String>, nickname: &Arc<String>)
+    -> bool
+  {
+    self.lists.lock()
+      .unwrap()
+      .get(group_name)
+      .and_then(|list| list.role(nickname))
+      == Some(Role::Owner)
+  }
+
+  /// On behalf of `by`, give `nickname` the role `role` in `group_name`.
+  /// See `FromClient::SetRole`.
+  pub fn set_role(&self,
+                  group_name: &Arc<String>,
+                  by: &Arc<String>,
+                  nickname: Option<Arc<String>>,
+                  role: Option<Role>)
+    -> Result<(), String>
+  {
+    let mut lists = self.lists.lock().unwrap();
+    let list = match lists.get_mut(group_name) {
+      Some(list) => list,
+      None => return Err(format!("Group '{}' does not exist", group_name)),
+    };
+    if list.roles.get(by) != Some(&Role::Owner) {
+      return Err(format!("Only owners may change roles in '{}'", group_name));
+    }
+
+    let mut changed = list.clone();
+    match nickname {
+      Some(nickname) => match role {
+        Some(role) => { changed.roles.insert(nickname, role); }
+        None => { changed.roles.remove(&nickname); }
+      },
+      None => changed.public = role,
+    }
+    if !changed.roles.values().any(|&role| role == Role::Owner) {
+      return Err(format!("'{}' must keep at least one owner", group_name));
+    }
+
+    *list = changed;
+    self.save(&lists);
+    Ok(())
+  }
+
+  /// Queue `lists` to be saved to our file, if we have one.
+  fn save(&self, lists: &HashMap<Arc<String>, AccessList>) {
+    if let Some(saves) = &self.saves {
+      let _ = saves.try_send(Save::Lists(lists.clone()));
+    }
+  }
+
+  /// Return a future that resolves once every change made so far has been
+  /// saved, or has failed to be and been logged.
+  pub fn sync(&self) -> impl Future<Output = ()> + 'static {
+    let reply = self.saves.as_ref().map(|saves| {
+      let (reply, synced) = channel::bounded(1);
+      let _ = saves.try_send(Save::Sync(reply));
+      synced
+    });
+    async move {
+      if let Some(synced) = reply {
+        let _ = synced.recv().await;
+      }
+    }
+  }
+}
+
+/// Save the lists queued on `saves` to `path`, until the table is dropped.
+/// When several have queued up, only the newest is written, on a thread
+/// where blocking is allowed.
+async fn write_lists(path: PathBuf, saves: channel::Receiver<Save>) {
+  while let Ok(save) = saves.recv().await {
+    let mut batch = vec![save];
+    while let Ok(save) = saves.try_recv() {
+      batch.push(save);
+    }
+    let mut newest = None;
+    let mut replies = vec![];
+    for save in batch {
+      match save {
+        Save::Lists(lists) => newest = Some(lists),
+        Save::Sync(reply) => replies.push(reply),
+      }
+    }
+    if let Some(lists) = newest {
+      let path = path.clone();
+      task::spawn_blocking(move || write_file(&path, &lists)).await;
+    }
+    for reply in replies {
+      let _ = reply.try_send(());
+    }
+  }
+}
+
+/// Replace the contents of `path` with `lists`. Failure to save isn't any
+/// client's fault, so we just log it; changes still take effect until the
+/// server restarts.
+fn write_file(path: &Path, lists: &HashMap<Arc<String>, AccessList>) {
+  let temporary = path.with_extension("tmp");
+  let saved = serde_json::to_vec_pretty(lists)
+    .map_err(io::Error::from)
+    .and_then(|json| std::fs::write(&temporary, json))
+    .and_then(|()| std::fs::rename(&temporary, path));
+  if let Err(error) = saved {
+    error!(path = %path.display(), %error, "failed to save access lists");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+  }
+
+  #[test]
+  fn creator_owns_group() {
+    let access = AccessTable::in_memory();
+    let (crabs, ann, bob) = (name("Crabs"), name("ann"), name("bob"));
+
+    access.check_join(&crabs, &ann).unwrap();
+    access.claim(&crabs, &ann);
+    access.check_join(&crabs, &bob).unwrap();
+    access.claim(&crabs, &bob);
+    access.check_post(&crabs, &bob).unwrap();
+
+    // Only owners may change roles, and there must always be one.
+    assert!(access.set_role(&crabs, &bob, Some(bob.clone()), Some(Role::Owner))
+              .is_err());
+    assert!(access.set_role(&crabs, &ann, Some(ann.clone()), None).is_err());
+    access.set_role(&crabs, &ann, Some(bob.clone()), Some(Role::Owner)).unwrap();
+    access.set_role(&crabs, &ann, Some(ann.clone()), None).unwrap();
+    assert!(access.set_role(&crabs, &ann, None, None).is_err());
+
+    // Once forgotten, the next to create the group owns it.
+    access.forget(&crabs);
+    access.claim(&crabs, &ann);
+    assert!(access.is_owner(&crabs, &ann));
+    assert!(!access.is_owner(&crabs, &bob));
+  }
+
+  #[test]
+  fn roles_limit_joining_and_posting() {
+    let access = AccessTable::in_memory();
+    let (crabs, ann, bob, cat) = (name("Crabs"), name("ann"), name("bob"),
+                                  name("cat"));
+    access.claim(&crabs, &ann);
+
+    access.set_role(&crabs, &ann, None, Some(Role::ReadOnly)).unwrap();
+    access.check_join(&crabs, &bob).unwrap();
+    assert!(access.check_post(&crabs, &bob).is_err());
+
+    access.set_role(&crabs, &ann, Some(bob.clone()), Some(Role::Member)).unwrap();
+    access.check_post(&crabs, &bob).unwrap();
+
+    access.set_role(&crabs, &ann, None, None).unwrap();
+    assert!(access.check_join(&crabs, &cat).is_err());
+    access.check_join(&crabs, &bob).unwrap();
+  }
+
+  #[test]
+  fn access_lists_are_saved() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("access.json");
+    let (crabs, ann, bob) = (name("Crabs"), name("ann"), name("bob"));
+
+    let access = AccessTable::open(&path).unwrap();
+    access.claim(&crabs, &ann);
+    access.set_role(&crabs, &ann, None, None).unwrap();
+    task::block_on(access.sync());
+
+    let reopened = AccessTable::open(&path).unwrap();
+    assert!(reopened.check_join(&crabs, &bob).is_err());
+    reopened.check_join(&crabs, &ann).unwrap();
+  }
+}
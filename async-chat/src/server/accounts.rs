@@ -0,0 +1,141 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_std::task;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The accounts clients log in to, saved as a JSON object mapping each
+/// nickname to an argon2 hash of its password.
+pub struct AccountStore {
+  path: Arc<PathBuf>,
+  hashes: Mutex<HashMap<String, String>>,
+  /// Held while creating an account, so that each save of the file
+  /// includes the accounts created before it.
+  creating: async_std::sync::Mutex<()>,
+  /// A hash of no one's password, to check unknown nicknames against.
+  dummy_hash: String,
+  /// Whether clients may create their own accounts with `Register`.
+  allow_registration: bool,
+}
+
+/// The shortest password we'll accept for a new account.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+impl AccountStore {
+  /// Load the accounts saved in `path`. If the file doesn't exist yet, start
+  /// with none; it is created when the first account is. This reads the file
+  /// and hashes a password, so call it where blocking is allowed.
+  pub fn open(path: &Path, allow_registration: bool) -> io::Result<AccountStore> {
+    let hashes = match std::fs::read(path) {
+      Ok(json) => serde_json::from_slice(&json)?,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+      Err(error) => return Err(error),
+    };
+    let dummy_hash = hash_password("not anyone's password")
+      .map_err(|error| io::Error::other(error.to_string()))?;
+    Ok(AccountStore {
+      path: Arc::new(path.to_owned()),
+      hashes: Mutex::new(hashes),
+      creating: async_std::sync::Mutex::new(()),
+      dummy_hash,
+      allow_registration,
+    })
+  }
+
+  pub fn allows_registration(&self) -> bool {
+    self.allow_registration
+  }
+
+  /// Return true if `password` is correct for the account `nickname`.
+  pub async fn verify(&self, nickname: &str, password: String) -> bool {
+    // Check unknown nicknames against a dummy hash, so that they take as
+    // long to reject as wrong passwords do.
+    let hash = self.hashes.lock().unwrap().get(nickname).cloned();
+    let known = hash.is_some();
+    let hash = hash.unwrap_or_else(|| self.dummy_hash.clone());
+
+    let correct = task::spawn_blocking(move || {
+      PasswordHash::new(&hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+    }).await;
+    known && correct
+  }
+
+  /// Create an account for `nickname` with `password`, and save it.
+  pub async fn create(&self, nickname: &str, password: String)
+    -> Result<(), String>
+  {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+      return Err(format!("Password must be at least {} characters long",
+                         MIN_PASSWORD_LENGTH));
+    }
+    let taken = || format!("Nickname '{}' is already taken", nickname);
+    if self.hashes.lock().unwrap().contains_key(nickname) {
+      return Err(taken());
+    }
+
+    let hash = task::spawn_blocking(move || hash_password(&password)).await
+      .map_err(|error| format!("Unable to hash password: {}", error))?;
+
+    // Save the new account before anyone can log in to it, without holding
+    // up logins while the file is written.
+    let _creating = self.creating.lock().await;
+    let mut hashes = self.hashes.lock().unwrap().clone();
+    if hashes.contains_key(nickname) {
+      return Err(taken());
+    }
+    hashes.insert(nickname.to_string(), hash.clone());
+    let path = self.path.clone();
+    task::spawn_blocking(move || save(&path, &hashes)).await
+      .map_err(|error| format!("Unable to save account: {}", error))?;
+    self.hashes.lock().unwrap().insert(nickname.to_string(), hash);
+    Ok(())
+  }
+}
+
+fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+  Ok(hash.to_string())
+}
+
+/// Write `hashes` to `path`, replacing its contents all at once, so that a
+/// crash never leaves a half-written file behind.
+fn save(path: &Path, hashes: &HashMap<String, String>) -> io::Result<()> {
+  let temporary = path.with_extension("tmp");
+  std::fs::write(&temporary, serde_json::to_vec_pretty(hashes)?)?;
+  std::fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accounts_are_verified_and_saved() {
+    task::block_on(async {
+      let directory = tempfile::tempdir().unwrap();
+      let path = directory.path().join("accounts.json");
+
+      let accounts = AccountStore::open(&path, true).unwrap();
+      accounts.create("ferris", "correct horse".to_string()).await.unwrap();
+      assert!(accounts.create("ferris", "another one".to_string()).await.is_err());
+      assert!(accounts.create("crab", "short".to_string()).await.is_err());
+
+      assert!(accounts.verify("ferris", "correct horse".to_string()).await);
+      assert!(!accounts.verify("ferris", "wrong horse".to_string()).await);
+      assert!(!accounts.verify("crab", "correct horse".to_string()).await);
+
+      // The file holds hashes, not passwords.
+      let saved = std::fs::read_to_string(&path).unwrap();
+      assert!(!saved.contains("correct horse"));
+
+      let reopened = AccountStore::open(&path, false).unwrap();
+      assert!(reopened.verify("ferris", "correct horse".to_string()).await);
+    });
+  }
+}
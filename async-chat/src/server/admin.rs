@@ -0,0 +1,234 @@
+//! The admin endpoint: a tiny HTTP server for the people running the chat
+//! server, on a port of its own.
+//!
+//! - `GET /metrics` reports the counters in `metrics`, in Prometheus's text
+//!   format.
+//! - `GET /connections` lists the open connections, one per line.
+//! - `POST /kick/NICKNAME` disconnects everyone signed in as NICKNAME.
+//!
+//! There is no authentication, so it should listen only on a loopback or
+//! otherwise private address.
+
+use crate::FromServer;
+use crate::utils::ChatResult;
+use async_std::channel;
+use async_std::io::{BufReader, ReadExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::server::connection::Server;
+use crate::server::history::unix_time;
+use crate::server::metrics::{self, Gauges, METRICS};
+use crate::server::survive_accept_error;
+
+/// The most we'll read of a request: plenty for a request line and the
+/// headers a browser or `curl` sends.
+const MAX_REQUEST_LENGTH: u64 = 16 * 1024;
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answer admin requests on `listener` until `stop` is closed.
+pub async fn accept_loop(listener: TcpListener,
+                         server: Arc<Server>,
+                         stop: channel::Receiver<()>)
+  -> ChatResult<()>
+{
+  let mut new_connections = listener.incoming();
+  loop {
+    let accepted = new_connections.next();
+    let stopped = async {
+      let _ = stop.recv().await;
+      None
+    };
+    let socket = match accepted.race(stopped).await {
+      Some(Ok(socket)) => socket,
+      Some(Err(error)) => {
+        survive_accept_error(error).await?;
+        continue;
+      }
+      None => break,
+    };
+    let server = server.clone();
+    task::spawn(async move {
+      let answered = handle(socket, &server).timeout(REQUEST_TIMEOUT).await;
+      match answered {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => debug!(%error, "admin request failed"),
+        Err(_) => debug!("admin request timed out"),
+      }
+    });
+  }
+  Ok(())
+}
+
+/// Read one HTTP request from `socket`, and answer it.
+async fn handle(socket: TcpStream, server: &Server) -> ChatResult<()> {
+  let mut reader = BufReader::new((&socket).take(MAX_REQUEST_LENGTH));
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line).await?;
+
+  // Skip the headers; nothing we serve depends on them.
+  loop {
+    let mut header = String::new();
+    if reader.read_line(&mut header).await? == 0 {
+      return Err("request ended in the headers".into());
+    }
+    if header.trim_end().is_empty() {
+      break;
+    }
+  }
+
+  let mut words = request_line.split_whitespace();
+  let (status, body) = match (words.next(), words.next()) {
+    (Some(method), Some(path)) => respond(method, path, server).await,
+    _ => ("400 Bad Request", "Bad request\n".to_string()),
+  };
+
+  let response = format!("HTTP/1.1 {}\r\n\
+                          Content-Type: text/plain; version=0.0.4\r\n\
+                          Content-Length: {}\r\n\
+                          Connection: close\r\n\
+                          \r\n\
+                          {}",
+                         status, body.len(), body);
+  (&socket).write_all(response.as_bytes()).await?;
+  Ok(())
+}
+
+/// Return the status and body of the response to `method` on `path`.
+async fn respond(method: &str, path: &str, server: &Server)
+  -> (&'static str, String)
+{
+  match (method, path) {
+    ("GET", "/metrics") => {
+      let connections = server.connections.list();
+      let gauges = Gauges {
+        connections: connections.len(),
+        signed_in: connections.iter()
+          .filter(|connection| connection.nickname.is_some())
+          .count(),
+        groups: server.groups.count(),
+      };
+      ("200 OK", metrics::render(&METRICS, &gauges))
+    }
+    ("GET", "/connections") => ("200 OK", list_connections(server)),
+    ("POST", path) if path.starts_with("/kick/") => {
+      kick(server, &path["/kick/".len()..]).await
+    }
+    (_, "/metrics") | (_, "/connections") => {
+      ("405 Method Not Allowed", "Method not allowed\n".to_string())
+    }
+    _ => ("404 Not Found", "Not found\n".to_string()),
+  }
+}
+
+/// Describe every open connection, one per line.
+fn list_connections(server: &Server) -> String {
+  let now = unix_time();
+  let mut text = "ID\tPEER\tNICKNAME\tCONNECTED\n".to_string();
+  for connection in server.connections.list() {
+    let peer = connection.peer
+      .map_or("-".to_string(), |peer| peer.to_string());
+    let nickname = connection.nickname
+      .map_or("-".to_string(), |nickname| nickname.to_string());
+    let _ = writeln!(text, "{}\t{}\t{}\t{}s ago", connection.id, peer,
+                     nickname, now.saturating_sub(connection.connected_at));
+  }
+  text
+}
+
+/// Disconnect everyone signed in as `nickname`.
+async fn kick(server: &Server, nickname: &str) -> (&'static str, String) {
+  let outbounds = server.connections.signed_in_as(nickname);
+  if outbounds.is_empty() {
+    return ("404 Not Found", format!("No one is signed in as '{}'\n", nickname));
+  }
+
+  for outbound in &outbounds {
+    let goodbye = FromServer::Error {
+      id: None,
+      message: "Disconnected by the server's administrator".to_string(),
+    };
+    outbound.hang_up(goodbye).await;
+  }
+  info!(user = nickname, connections = outbounds.len(), "kicked user");
+  ("200 OK", format!("Disconnected {} connections\n", outbounds.len()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::access::AccessTable;
+  use crate::server::connection::Outbound;
+  use crate::server::connection_table::ConnectionTable;
+  use crate::server::federation::Federation;
+  use crate::server::group_table::{GroupSettings, GroupTable};
+  use crate::server::rate_limit::Limits;
+  use crate::server::user_table::UserTable;
+  use crate::codec::Codec;
+
+  /// Send `request` to the admin endpoint at `address`, and return the
+  /// response's status line and body.
+  async fn fetch(address: &str, request: &str) -> (String, String) {
+    let mut socket = TcpStream::connect(address).await.unwrap();
+    socket.write_all(format!("{}\r\nHost: localhost\r\n\r\n", request)
+                       .as_bytes())
+      .await
+      .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+  }
+
+  #[test]
+  fn admin_lists_and_kicks_connections() {
+    task::block_on(async {
+      let server = Arc::new(Server {
+        groups: Arc::new(GroupTable::new(GroupSettings::default(),
+                                         AccessTable::in_memory())),
+        users: UserTable::new(0),
+        connections: Arc::new(ConnectionTable::new()),
+        limits: Limits {
+          connection: None,
+          max_strikes: 0,
+          max_message_size: 10,
+          max_frame_size: 100,
+          max_group_name_length: 10,
+        },
+        accounts: None,
+        federation: Arc::new(Federation::new("test", None)),
+      });
+      let outbound = Arc::new(Outbound::new(Vec::new(), Codec::Json));
+      let registration = server.connections.register(outbound.clone(), None);
+      registration.signed_in(Arc::new("ferris".to_string()));
+
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap().to_string();
+      let (_stop, stopped) = channel::bounded(1);
+      task::spawn(accept_loop(listener, server, stopped));
+
+      let (status, body) = fetch(&address, "GET /connections HTTP/1.1").await;
+      assert_eq!(status, "HTTP/1.1 200 OK");
+      assert!(body.lines().nth(1).unwrap().starts_with("0\t-\tferris\t"));
+
+      let (_, body) = fetch(&address, "GET /metrics HTTP/1.1").await;
+      assert!(body.lines().any(|line| line == "async_chat_signed_in 1"));
+
+      let (status, _) = fetch(&address, "POST /kick/crab HTTP/1.1").await;
+      assert_eq!(status, "HTTP/1.1 404 Not Found");
+      let (status, _) = fetch(&address, "POST /kick/ferris HTTP/1.1").await;
+      assert_eq!(status, "HTTP/1.1 200 OK");
+      assert!(outbound.is_disconnected());
+
+      let (status, _) = fetch(&address, "DELETE /metrics HTTP/1.1").await;
+      assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    });
+  }
+}
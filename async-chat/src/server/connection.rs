@@ -0,0 +1,942 @@
+use crate::{FromClient, FromServer, Request};
+use crate::codec::Codec;
+use crate::utils::ChatResult;
+use async_std::prelude::*;
+use async_std::io::{BufReader, Read, Write};
+use async_std::sync::Arc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use async_std::task;
+use tracing::{debug, info, Instrument};
+
+use crate::server::accounts::AccountStore;
+use crate::server::connection_table::{ConnectionTable, Registration};
+use crate::server::federation::{self, Federation, PeerLink};
+use crate::server::group::{Group, Subscription};
+use crate::server::group_table::GroupTable;
+use crate::server::history::{self, unix_time};
+use crate::server::metrics::METRICS;
+use crate::server::rate_limit::{Limits, TokenBucket};
+use crate::server::search::MAX_SEARCH_RESULTS;
+use crate::server::user_table::{Delivery, UserTable};
+use crate::server::websocket;
+
+/// The tables and settings that every connection shares.
+pub struct Server {
+  pub groups: Arc<GroupTable>,
+  pub users: UserTable,
+  pub connections: Arc<ConnectionTable>,
+  pub limits: Limits,
+  /// The accounts clients must log in to, if the server has them. If it
+  /// doesn't, clients just say hello with any nickname not in use.
+  pub accounts: Option<AccountStore>,
+  /// The peer servers we relay posts to and from.
+  pub federation: Arc<Federation>,
+}
+
+/// What we know about the client on the other end of a connection.
+struct Session {
+  /// The nickname the client said hello or logged in with, once it has.
+  nickname: Option<Arc<String>>,
+  /// The subscription to each group the client has joined.
+  subscriptions: HashMap<Arc<String>, Subscription>,
+  /// Throttles the client's requests, if limited.
+  limiter: Option<TokenBucket>,
+  /// How many requests in a row we have throttled.
+  strikes: u32,
+  /// Our entry in the server's `ConnectionTable`.
+  registration: Registration,
+  /// If the other end is a peer server, rather than a client, its link.
+  peer: Option<PeerLink>,
+}
+
+impl Session {
+  fn new(limits: &Limits, registration: Registration) -> Session {
+    Session {
+      nickname: None,
+      subscriptions: HashMap::new(),
+      limiter: limits.connection.map(TokenBucket::new),
+      strikes: 0,
+      registration,
+      peer: None,
+    }
+  }
+}
+
+/// Serve a client at `peer` connected via `socket`, which may be a plain
+/// `TcpStream` or a TLS stream wrapping one. The client chooses the codec;
+/// see `Codec::negotiate`.
+pub async fn serve<S>(socket: S, peer: Option<SocketAddr>, server: Arc<Server>)
+  -> ChatResult<()>
+where
+  S: Read + Write + Send + Unpin + 'static,
+{
+  let (from_client, to_client) = futures_lite::io::split(socket);
+  let mut inbound = BufReader::new(from_client);
+  let codec = Codec::negotiate(&mut inbound).await?;
+
+  let outbound = Arc::new(Outbound::new(to_client, codec));
+  let requests = codec.packets_within(inbound, server.limits.max_frame_size);
+  serve_requests(requests, outbound, peer, &server).await
+}
+
+/// Serve the client at `peer` whose requests arrive on `requests`, and which
+/// we can reach via `outbound`. This is everything about serving a client
+/// that doesn't depend on how it is connected.
+pub async fn serve_requests<R>(requests: R,
+                               outbound: Arc<Outbound>,
+                               peer: Option<SocketAddr>,
+                               server: &Server)
+  -> ChatResult<()>
+where
+  R: Stream<Item = ChatResult<Request>>,
+{
+  let registration = server.connections.register(outbound.clone(), peer);
+  let mut session = Session::new(&server.limits, registration);
+
+  let requests = std::pin::pin!(requests);
+  let result = handle_requests(requests, server, &outbound, &mut session)
+    .await;
+
+  // Leave every group right away, rather than waiting for each subscriber
+  // to notice the connection is gone the next time it has something to send.
+  for (_group_name, subscription) in session.subscriptions {
+    subscription.cancel().await;
+  }
+  if let Some(nickname) = &session.nickname {
+    server.users.sign_out(nickname, &outbound);
+  }
+
+  result
+}
+
+async fn handle_requests<R>(mut requests: R,
+                            server: &Server,
+                            outbound: &Arc<Outbound>,
+                            session: &mut Session)
+  -> ChatResult<()>
+where
+  R: Stream<Item = ChatResult<Request>> + Unpin,
+{
+  let Server { groups, users, limits, accounts, federation, .. } = server;
+  let accounts = accounts.as_ref();
+  let subscriptions = &mut session.subscriptions;
+  loop {
+    let received = requests.next();
+    let hung_up = async {
+      outbound.disconnected().await;
+      None
+    };
+    let Request { id, body: request } = match received.race(hung_up).await {
+      Some(Ok(request)) => request,
+      Some(Err(error)) => {
+        // We can't find where the next request starts, so tell the client
+        // why we're hanging up, if it's still listening.
+        let report = FromServer::Error {
+          id: None,
+          message: format!("Unreadable request: {}; disconnecting", error),
+        };
+        let _ = outbound.send(report).await;
+        return Err(error);
+      }
+      None => break,
+    };
+    METRICS.requests.increment();
+
+    if let Some(limiter) = &mut session.limiter {
+      if !limiter.try_take() {
+        session.strikes += 1;
+        if session.strikes > limits.max_strikes {
+          let report = FromServer::Error {
+            id: Some(id),
+            message: "Too many requests; disconnecting".to_string(),
+          };
+          outbound.send(report).await?;
+          return Err("client kept exceeding the rate limit".into());
+        }
+
+        reject(outbound, id, "Too many requests; request ignored".to_string())
+          .await?;
+        continue;
+      }
+    }
+    session.strikes = 0;
+
+    if let Err(reason) = validate_request(&request, limits) {
+      reject(outbound, id, reason).await?;
+      continue;
+    }
+
+    let result = match (request, &session.nickname) {
+      (FromClient::Hello { nickname: requested }, None) => {
+        let identified = match accounts {
+          Some(_) => Err("This server requires you to log in".to_string()),
+          None => Ok(requested),
+        };
+        sign_in(identified, users, outbound, &mut session.nickname,
+                &session.registration).await?
+      }
+
+      (FromClient::Login { nickname: requested, password }, None) => {
+        let identified = match accounts {
+          Some(accounts) => {
+            if accounts.verify(&requested, password).await {
+              Ok(requested)
+            } else {
+              Err("Incorrect nickname or password".to_string())
+            }
+          }
+          None => {
+            Err("This server has no accounts; say hello instead".to_string())
+          }
+        };
+        sign_in(identified, users, outbound, &mut session.nickname,
+                &session.registration).await?
+      }
+
+      (FromClient::Hello { .. }, Some(current)) |
+      (FromClient::Login { .. }, Some(current)) => {
+        Err(format!("Already signed in as '{}'", current))
+      }
+
+      (FromClient::Register { nickname, password }, _) => {
+        match accounts {
+          Some(accounts) if accounts.allows_registration() => {
+            match validate_nickname(&nickname) {
+              Ok(()) => accounts.create(&nickname, password).await
+                .map(|()| None),
+              Err(message) => Err(message),
+            }
+          }
+          _ => Err("This server does not allow registration".to_string()),
+        }
+      }
+
+      (FromClient::Peer { server_name, secret }, None)
+        if session.peer.is_none() =>
+      {
+        federation.check_secret(&secret).map(|()| {
+          let (link, relays) = federation.add_peer(server_name.clone());
+          let sending = federation::send_relays(relays, outbound.clone());
+          task::spawn(sending.in_current_span());
+          info!(peer = %server_name, "peer linked");
+          session.peer = Some(link);
+          // Peers relay whatever their own clients post; the servers those
+          // clients are connected to have already limited them.
+          session.limiter = None;
+          None
+        })
+      }
+
+      (FromClient::Peer { .. }, _) => {
+        Err("Already signed in or linked".to_string())
+      }
+
+      (FromClient::Relay { group_name, id: message_id, sender, timestamp,
+                           message }, _) => {
+        match &session.peer {
+          Some(link) => {
+            let entry = history::Entry {
+              timestamp,
+              sender,
+              message,
+              id: message_id,
+              relayed_from: Some(link.name().clone()),
+            };
+            federation.receive(link, groups, group_name, Arc::new(entry));
+            Ok(None)
+          }
+          None => Err("Only peer servers may relay messages".to_string()),
+        }
+      }
+
+      (_, None) => {
+        if accounts.is_some() {
+          Err("Log in first".to_string())
+        } else {
+          Err("Say hello with a nickname first".to_string())
+        }
+      }
+
+      (FromClient::Join { group_name, replay }, Some(nickname)) => {
+        // A subscription that ended by itself, as when we're expelled,
+        // doesn't stop us joining again, if we're allowed.
+        let active = subscriptions.get(&group_name)
+          .is_some_and(|subscription| subscription.is_active());
+        if active {
+          Err(format!("Already a member of '{}'", group_name))
+        } else {
+          match groups.join(group_name.clone(), nickname.clone(),
+                            outbound.clone(), replay).await {
+            Ok(subscription) => {
+              info!(group = %group_name, "joined group");
+              subscriptions.insert(group_name, subscription);
+              Ok(None)
+            }
+            Err(message) => Err(message),
+          }
+        }
+      }
+
+      (FromClient::Post { group_name, message }, Some(sender)) => {
+        let bytes = message.len();
+        let message_id = federation.next_id();
+        let entry = history::Entry::new(message_id, sender.clone(), message);
+        let entry = Arc::new(entry);
+        groups.post(&group_name, entry.clone()).await
+          .map(|()| {
+            debug!(group = %group_name, bytes, "posted message");
+            federation.relay(&group_name, entry);
+            None
+          })
+      }
+
+      (FromClient::Edit { group_name, id: message_id, message }, Some(by)) => {
+        groups.edit(&group_name, message_id, by, message).await.map(|()| None)
+      }
+
+      (FromClient::Delete { group_name, id: message_id }, Some(by)) => {
+        groups.delete(&group_name, message_id, by).await.map(|()| None)
+      }
+
+      (FromClient::React { group_name, id: message_id, reaction },
+       Some(sender)) => {
+        groups.react(&group_name, message_id, sender, reaction).await
+          .map(|()| None)
+      }
+
+      (FromClient::Typing { group_name }, Some(nickname)) => {
+        joined_group(groups, subscriptions, &group_name)
+          .map(|group| {
+            group.typing(nickname);
+            None
+          })
+      }
+
+      (FromClient::DirectMessage { to, message }, Some(from)) => {
+        let packet = FromServer::DirectMessage {
+          from: from.clone(),
+          timestamp: unix_time(),
+          message,
+        };
+        match users.deliver(&to, packet) {
+          Ok(Delivery::Now(recipient, packet)) => {
+            match recipient.send(packet).await {
+              Ok(()) => Ok(None),
+              Err(_) => Err(format!("Unable to deliver message to '{}'", to)),
+            }
+          }
+          Ok(Delivery::Queued) => Ok(None),
+          Err(message) => Err(message),
+        }
+      }
+
+      (FromClient::Leave { group_name }, Some(_)) => {
+        match subscriptions.remove(&group_name) {
+          Some(subscription) => {
+            subscription.cancel().await;
+            info!(group = %group_name, "left group");
+            Ok(None)
+          }
+          None => {
+            Err(format!("Not a member of '{}'", group_name))
+          }
+        }
+      }
+
+      (FromClient::Search { group_name, query, limit }, Some(_)) => {
+        match joined_group(groups, subscriptions, &group_name) {
+          Ok(group) => {
+            let limit = limit.min(MAX_SEARCH_RESULTS);
+            let results: Vec<_> = group.search(&query, limit)
+              .iter()
+              .map(|entry| entry.to_search_result())
+              .collect();
+            debug!(group = %group_name, results = results.len(), "searched");
+            Ok(Some(FromServer::SearchResults { group_name, results }))
+          }
+          Err(message) => Err(message),
+        }
+      }
+
+      (FromClient::ListGroups, Some(_)) => {
+        Ok(Some(FromServer::Groups { group_names: groups.names() }))
+      }
+
+      (FromClient::ListMembers { group_name }, Some(_)) => {
+        joined_group(groups, subscriptions, &group_name)
+          .map(|group| {
+            Some(FromServer::Members {
+              members: group.members(),
+              group_name,
+            })
+          })
+      }
+
+      (FromClient::SetRole { group_name, nickname, role }, Some(by)) => {
+        groups.set_role(&group_name, by, nickname, role).map(|()| None)
+      }
+    };
+
+    match result {
+      Ok(reply) => {
+        if let Some(packet) = reply {
+          outbound.send(packet).await?;
+        }
+        outbound.send(FromServer::Ack { id }).await?;
+      }
+      Err(reason) => reject(outbound, id, reason).await?,
+    }
+  }
+  Ok(())
+}
+
+/// Return the group called `group_name`, if we're a member of it. A
+/// subscription that ended because we were expelled doesn't count.
+fn joined_group(groups: &GroupTable,
+                subscriptions: &HashMap<Arc<String>, Subscription>,
+                group_name: &Arc<String>)
+  -> Result<Arc<Group>, String>
+{
+  let active = subscriptions.get(group_name)
+    .is_some_and(|subscription| subscription.is_active());
+  match groups.get(group_name) {
+    Some(group) if active => Ok(group),
+    _ => Err(format!("Not a member of '{}'", group_name)),
+  }
+}
+
+/// Answer request `id` with a `Nack` giving `reason`.
+async fn reject(outbound: &Outbound, id: u64, reason: String) -> ChatResult<()> {
+  METRICS.rejected_requests.increment();
+  debug!(id, %reason, "rejected request");
+  outbound.send(FromServer::Nack { id, reason }).await
+}
+
+/// Check the group names and messages in `request` against `limits`. These
+/// don't depend on who is asking, or on the state of the server.
+fn validate_request(request: &FromClient, limits: &Limits)
+  -> Result<(), String>
+{
+  match request {
+    FromClient::Join { group_name, .. } => {
+      validate_group_name(group_name, limits.max_group_name_length)
+    }
+    FromClient::Post { group_name, message } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_message(message, limits.max_message_size)
+    }
+    FromClient::Edit { group_name, id, message } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_message_id(id)?;
+      validate_message(message, limits.max_message_size)
+    }
+    FromClient::Delete { group_name, id } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_message_id(id)
+    }
+    FromClient::React { group_name, id, reaction } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_message_id(id)?;
+      validate_reaction(reaction)
+    }
+    FromClient::Typing { group_name } => {
+      validate_group_name(group_name, limits.max_group_name_length)
+    }
+    FromClient::Search { group_name, query, .. } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_query(query, limits.max_message_size)
+    }
+    FromClient::DirectMessage { message, .. } => {
+      validate_message(message, limits.max_message_size)
+    }
+    FromClient::Relay { group_name, id, message, .. } => {
+      validate_group_name(group_name, limits.max_group_name_length)?;
+      validate_message_id(id)?;
+      validate_message(message, limits.max_message_size)
+    }
+    FromClient::Peer { server_name, .. } => {
+      if server_name.is_empty() {
+        return Err("Server name must not be empty".to_string());
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Check that `name` is a reasonable name for a group: not empty, at most
+/// `max_length` characters, free of control characters, and without
+/// whitespace at either end, which would make it easy to confuse with a
+/// different group.
+pub fn validate_group_name(name: &str, max_length: usize) -> Result<(), String> {
+  if name.is_empty() {
+    return Err("Group name must not be empty".to_string());
+  }
+  let length = name.chars().count();
+  if length > max_length {
+    return Err(format!("Group name is {} characters long; the limit is {}",
+                       length, max_length));
+  }
+  if name.contains(char::is_control) {
+    return Err("Group name must not contain control characters".to_string());
+  }
+  if name.trim() != name {
+    return Err(format!("Group name '{}' must not begin or end with whitespace",
+                       name));
+  }
+  Ok(())
+}
+
+fn validate_message(message: &str, max_size: usize) -> Result<(), String> {
+  if message.is_empty() {
+    return Err("Message must not be empty".to_string());
+  }
+  if message.len() > max_size {
+    return Err(format!("Message is {} bytes long; the limit is {}",
+                       message.len(), max_size));
+  }
+  Ok(())
+}
+
+/// The longest message ID a client or peer may send, in characters. Ours
+/// are the server's name, its start time and a count.
+const MAX_MESSAGE_ID_LENGTH: usize = 128;
+
+fn validate_message_id(id: &str) -> Result<(), String> {
+  if id.is_empty() {
+    return Err("Message ID must not be empty".to_string());
+  }
+  let length = id.chars().count();
+  if length > MAX_MESSAGE_ID_LENGTH {
+    return Err(format!("Message ID is {} characters long; the limit is {}",
+                       length, MAX_MESSAGE_ID_LENGTH));
+  }
+  Ok(())
+}
+
+/// Check that `query` has something to look for, and is no longer than a
+/// message could be, since a longer one couldn't match anything.
+fn validate_query(query: &str, max_size: usize) -> Result<(), String> {
+  if query.trim().is_empty() {
+    return Err("Search query must not be empty".to_string());
+  }
+  if query.len() > max_size {
+    return Err(format!("Search query is {} bytes long; the limit is {}",
+                       query.len(), max_size));
+  }
+  Ok(())
+}
+
+/// The longest reaction a client may send, in characters. Enough for an
+/// emoji built from several code points, or a short word.
+const MAX_REACTION_LENGTH: usize = 16;
+
+fn validate_reaction(reaction: &str) -> Result<(), String> {
+  if reaction.is_empty() {
+    return Err("Reaction must not be empty".to_string());
+  }
+  if reaction.chars().count() > MAX_REACTION_LENGTH {
+    return Err(format!("Reactions may be at most {} characters long",
+                       MAX_REACTION_LENGTH));
+  }
+  if reaction.contains(|ch: char| ch.is_control() || ch.is_whitespace()) {
+    return Err("Reaction must not contain whitespace or control characters"
+               .to_string());
+  }
+  Ok(())
+}
+
+/// If the client has established its identity as `identified`, sign it in
+/// under that nickname, and send it any direct messages queued while it was
+/// away. Return the reply to the request that identified it.
+async fn sign_in(identified: Result<Arc<String>, String>,
+                 users: &UserTable,
+                 outbound: &Arc<Outbound>,
+                 nickname: &mut Option<Arc<String>>,
+                 registration: &Registration)
+  -> ChatResult<Result<Option<FromServer>, String>>
+{
+  let signed_in = identified.and_then(|requested| {
+    validate_nickname(&requested)?;
+    let queued = users.sign_in(requested.clone(), outbound.clone())?;
+    Ok((requested, queued))
+  });
+  match signed_in {
+    Ok((requested, queued)) => {
+      registration.signed_in(requested.clone());
+      tracing::Span::current().record("user", requested.as_str());
+      info!("signed in");
+      *nickname = Some(requested);
+      for packet in queued {
+        outbound.send(packet).await?;
+      }
+      Ok(Ok(None))
+    }
+    Err(message) => Ok(Err(message)),
+  }
+}
+
+/// Check that `nickname` is something other users can refer to.
+pub fn validate_nickname(nickname: &str) -> Result<(), String> {
+  if nickname.is_empty() {
+    return Err("Nickname must not be empty".to_string());
+  }
+  if nickname.contains(char::is_whitespace) {
+    return Err(format!("Nickname '{}' must not contain whitespace", nickname));
+  }
+  Ok(())
+}
+
+use async_std::channel;
+use async_std::sync::Mutex;
+use async_tungstenite::tungstenite;
+use futures_util::Sink;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// How long we'll wait for a client to accept a packet before deciding it
+/// has stopped reading and hanging up on it.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Outbound {
+  to_client: Mutex<ToClient>,
+  /// Closed by `disconnect`; nothing is ever sent on it.
+  hang_up: channel::Sender<()>,
+  hung_up: channel::Receiver<()>,
+}
+
+impl Outbound {
+  pub fn new<W>(to_client: W, codec: Codec) -> Outbound
+  where
+    W: Write + Send + Unpin + 'static,
+  {
+    Outbound::with(ToClient::Stream(codec, Box::new(to_client)))
+  }
+
+  /// Return an `Outbound` that sends packets as WebSocket messages.
+  pub fn websocket<W>(to_client: W) -> Outbound
+  where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error>
+      + Send + Unpin + 'static,
+  {
+    Outbound::with(ToClient::WebSocket(Box::pin(to_client)))
+  }
+
+  fn with(to_client: ToClient) -> Outbound {
+    let (hang_up, hung_up) = channel::bounded(1);
+    Outbound { to_client: Mutex::new(to_client), hang_up, hung_up }
+  }
+
+  pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
+    if self.is_disconnected() {
+      return Err("connection closed by server".into());
+    }
+
+    let sent = async { self.to_client.lock().await.send(&packet).await };
+    let result = match async_std::future::timeout(SEND_TIMEOUT, sent).await {
+      Ok(result) => result,
+      Err(_) => {
+        self.disconnect();
+        Err("client stopped reading".into())
+      }
+    };
+    if result.is_err() {
+      METRICS.send_errors.increment();
+    }
+    result
+  }
+
+  /// Tell the client the server is going away, close our side of the
+  /// connection, and hang up.
+  pub async fn shut_down(&self) {
+    self.hang_up(FromServer::Shutdown).await;
+  }
+
+  /// Send the client `goodbye`, close our side of the connection, and hang
+  /// up. Since this waits its turn to send, any packet already being sent is
+  /// finished first, and none are sent afterwards.
+  pub async fn hang_up(&self, goodbye: FromServer) {
+    let mut guard = self.to_client.lock().await;
+    if self.is_disconnected() {
+      return;
+    }
+    self.disconnect();
+
+    let goodbye = async {
+      guard.send(&goodbye).await?;
+      guard.close().await
+    };
+    let _ = async_std::future::timeout(SEND_TIMEOUT, goodbye).await;
+  }
+
+  /// Ask the connection to hang up. Further sends fail, and `serve` stops
+  /// reading requests.
+  pub fn disconnect(&self) {
+    self.hang_up.close();
+  }
+
+  pub fn is_disconnected(&self) -> bool {
+    self.hang_up.is_closed()
+  }
+
+  /// Return once `disconnect` has been called.
+  pub async fn disconnected(&self) {
+    let _ = self.hung_up.recv().await;
+  }
+}
+
+/// The sending half of a client's connection.
+enum ToClient {
+  /// A byte stream, carrying packets in the given codec.
+  Stream(Codec, Box<dyn Write + Send + Unpin>),
+  WebSocket(Pin<Box<dyn Sink<tungstenite::Message, Error = tungstenite::Error>
+                    + Send>>),
+}
+
+impl ToClient {
+  async fn send(&mut self, packet: &FromServer) -> ChatResult<()> {
+    match self {
+      ToClient::Stream(codec, writer) => {
+        codec.send(writer, packet).await?;
+        writer.flush().await?;
+      }
+      ToClient::WebSocket(sink) => {
+        let message = websocket::encode(packet)?;
+        futures_util::SinkExt::send(sink, message).await?;
+      }
+    }
+    Ok(())
+  }
+
+  async fn close(&mut self) -> ChatResult<()> {
+    match self {
+      ToClient::Stream(_codec, writer) => {
+        futures_lite::AsyncWriteExt::close(writer).await?;
+      }
+      ToClient::WebSocket(sink) => {
+        futures_util::SinkExt::close(sink).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::access::AccessTable;
+  use crate::server::group_table::GroupSettings;
+  use async_std::net::{TcpListener, TcpStream};
+  use async_std::task;
+
+  fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+  }
+
+  /// Start serving one client with small limits, and return the address
+  /// to connect to.
+  async fn start_server() -> SocketAddr {
+    let server = Arc::new(Server {
+      groups: Arc::new(GroupTable::new(GroupSettings::default(),
+                                       AccessTable::in_memory())),
+      users: UserTable::new(0),
+      connections: Arc::new(ConnectionTable::new()),
+      limits: Limits {
+        connection: None,
+        max_strikes: 0,
+        max_message_size: 10,
+        max_frame_size: 100,
+        max_group_name_length: 10,
+      },
+      accounts: None,
+      federation: Arc::new(Federation::new("test", None)),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    task::spawn(async move {
+      let (socket, peer) = listener.accept().await.unwrap();
+      let _ = serve(socket, Some(peer), server).await;
+    });
+    address
+  }
+
+  #[test]
+  fn every_request_is_answered() {
+    task::block_on(async {
+      let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+      let post = |message: &str| FromClient::Post {
+        group_name: name("Crabs"),
+        message: name(message),
+      };
+      let bodies = [
+        post("too early"),
+        FromClient::Hello { nickname: name("ferris") },
+        FromClient::Join { group_name: name("Crabs"), replay: None },
+        post("hello"),
+        post("far too long to post"),
+      ];
+      Codec::Json.announce(&mut socket).await.unwrap();
+      for (id, body) in (1..).zip(bodies) {
+        Codec::Json.send(&mut socket, &Request { id, body }).await.unwrap();
+      }
+
+      let mut replies = BufReader::new(socket);
+      let mut received = vec![];
+      while received.len() < 6 {
+        let packet: FromServer = Codec::Json.receive(&mut replies).await
+          .unwrap()
+          .unwrap();
+        if packet.is_presence() {
+          continue;
+        }
+        received.push(match packet {
+          FromServer::Ack { id } => format!("ack {}", id),
+          FromServer::Nack { id, .. } => format!("nack {}", id),
+          FromServer::Message { message, .. } => message.to_string(),
+          other => panic!("unexpected packet {:?}", other),
+        });
+      }
+      // The group sends the message along on its own task, so it may arrive
+      // before or after the replies to the requests around it.
+      received[3..].sort();
+      assert_eq!(received, ["nack 1", "ack 2", "ack 3", "ack 4", "hello",
+                            "nack 5"]);
+    });
+  }
+
+  #[test]
+  fn malformed_names_and_messages_are_rejected() {
+    task::block_on(async {
+      let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+      let join = |group_name: &str| FromClient::Join {
+        group_name: name(group_name),
+        replay: None,
+      };
+      let bodies = [
+        FromClient::Hello { nickname: name("ferris") },
+        join(""),
+        join(" Crabs"),
+        join("Cr\u{7}bs"),
+        join("Crustaceans"),
+        join("Crabs"),
+        FromClient::Post { group_name: name("Crabs"), message: name("") },
+        FromClient::DirectMessage { to: name("ferris"), message: name("") },
+        FromClient::Peer { server_name: name(""), secret: "hush".to_string() },
+      ];
+      for (id, body) in (1..).zip(bodies) {
+        Codec::Json.send(&mut socket, &Request { id, body }).await.unwrap();
+      }
+
+      let mut replies = BufReader::new(socket);
+      let mut reasons = vec![];
+      let mut id = 0;
+      while id < 9 {
+        let packet: FromServer = Codec::Json.receive(&mut replies).await
+          .unwrap()
+          .unwrap();
+        if packet.is_presence() {
+          continue;
+        }
+        id += 1;
+        match packet {
+          FromServer::Ack { id: acked } => assert_eq!(acked, id),
+          FromServer::Nack { id: rejected, reason } => {
+            assert_eq!(rejected, id);
+            reasons.push(reason);
+          }
+          other => panic!("unexpected packet {:?}", other),
+        }
+      }
+      assert_eq!(reasons, [
+        "Group name must not be empty",
+        "Group name ' Crabs' must not begin or end with whitespace",
+        "Group name must not contain control characters",
+        "Group name is 11 characters long; the limit is 10",
+        "Message must not be empty",
+        "Message must not be empty",
+        "Server name must not be empty",
+      ]);
+    });
+  }
+
+  #[test]
+  fn relayed_message_ids_are_checked() {
+    let limits = Limits {
+      connection: None,
+      max_strikes: 0,
+      max_message_size: 1000,
+      max_frame_size: 10_000,
+      max_group_name_length: 10,
+    };
+    let relay = |id: &str| FromClient::Relay {
+      group_name: name("Crabs"),
+      id: name(id),
+      sender: name("ferris"),
+      timestamp: 0,
+      message: name("hello"),
+    };
+    assert_eq!(validate_request(&relay("peer:1:1"), &limits), Ok(()));
+    assert_eq!(validate_request(&relay(""), &limits),
+               Err("Message ID must not be empty".to_string()));
+    assert_eq!(validate_request(&relay(&"7".repeat(200)), &limits),
+               Err("Message ID is 200 characters long; the limit is 128"
+                   .to_string()));
+  }
+
+  #[test]
+  fn oversized_frames_end_the_connection() {
+    task::block_on(async {
+      let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+      let hello = Request {
+        id: 1,
+        body: FromClient::Hello { nickname: name(&"x".repeat(200)) },
+      };
+      Codec::Json.send(&mut socket, &hello).await.unwrap();
+
+      let mut replies = BufReader::new(socket);
+      let mut received: Vec<FromServer> = vec![];
+      while let Some(packet) = Codec::Json.receive(&mut replies).await.unwrap() {
+        received.push(packet);
+      }
+      match &received[..] {
+        [FromServer::Error { id: None, message }] => {
+          assert!(message.contains("limit of 100 bytes"), "{}", message);
+        }
+        other => panic!("unexpected packets {:?}", other),
+      }
+    });
+  }
+
+  #[test]
+  fn shut_down_says_goodbye_and_hangs_up() {
+    task::block_on(async {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+      let (client, accepted) = TcpStream::connect(address)
+        .join(listener.accept())
+        .await;
+      let outbound = Outbound::new(accepted.unwrap().0, Codec::Json);
+
+      let greeting = || FromServer::Error {
+        id: None,
+        message: "hello".to_string(),
+      };
+      outbound.send(greeting()).await.unwrap();
+      outbound.shut_down().await;
+      assert!(outbound.is_disconnected());
+      assert!(outbound.send(greeting()).await.is_err());
+
+      // For plain TCP, closing only flushes; the socket closes when `serve`
+      // drops the last reference to it.
+      drop(outbound);
+
+      let mut client = BufReader::new(client.unwrap());
+      let mut received: Vec<FromServer> = vec![];
+      while let Some(packet) = Codec::Json.receive(&mut client).await.unwrap() {
+        received.push(packet);
+      }
+      assert_eq!(received, [greeting(), FromServer::Shutdown]);
+    });
+  }
+}
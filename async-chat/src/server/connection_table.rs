@@ -0,0 +1,182 @@
+use crate::server::connection::Outbound;
+use crate::server::history::unix_time;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Every client currently connected, whether or not it has said hello, so
+/// that the server can reach them all when it shuts down, and so that
+/// administrators can see who is connected.
+pub struct ConnectionTable {
+  connections: Mutex<Connections>,
+  /// Notified when the last connection is removed.
+  emptied: Notify,
+}
+
+#[derive(Default)]
+struct Connections {
+  next_id: u64,
+  entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+  outbound: Arc<Outbound>,
+  info: ConnectionInfo,
+}
+
+/// What an administrator can learn about a connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+  pub id: u64,
+  /// The client's address, if we could tell.
+  pub peer: Option<SocketAddr>,
+  /// The nickname the client signed in with, if it has.
+  pub nickname: Option<Arc<String>>,
+  /// When the client connected, in seconds since the Unix epoch.
+  pub connected_at: u64,
+}
+
+impl Default for ConnectionTable {
+  fn default() -> ConnectionTable {
+    ConnectionTable::new()
+  }
+}
+
+impl ConnectionTable {
+  pub fn new() -> ConnectionTable {
+    ConnectionTable {
+      connections: Mutex::new(Connections::default()),
+      emptied: Notify::new(),
+    }
+  }
+
+  /// Add `outbound`, a connection to a client at `peer`, to the table until
+  /// the returned guard is dropped.
+  pub fn register(self: &Arc<Self>,
+                  outbound: Arc<Outbound>,
+                  peer: Option<SocketAddr>)
+    -> Registration
+  {
+    let mut connections = self.connections.lock().unwrap();
+    let id = connections.next_id;
+    connections.next_id += 1;
+    let info = ConnectionInfo {
+      id,
+      peer,
+      nickname: None,
+      connected_at: unix_time(),
+    };
+    connections.entries.insert(id, Entry { outbound, info });
+    Registration { table: self.clone(), id }
+  }
+
+  pub fn outbounds(&self) -> Vec<Arc<Outbound>> {
+    self.connections.lock()
+      .unwrap()
+      .entries
+      .values()
+      .map(|entry| entry.outbound.clone())
+      .collect()
+  }
+
+  /// Describe every connection, oldest first.
+  pub fn list(&self) -> Vec<ConnectionInfo> {
+    let mut list: Vec<_> = self.connections.lock()
+      .unwrap()
+      .entries
+      .values()
+      .map(|entry| entry.info.clone())
+      .collect();
+    list.sort_by_key(|info| info.id);
+    list
+  }
+
+  /// Return the connections of clients signed in as `nickname`.
+  pub fn signed_in_as(&self, nickname: &str) -> Vec<Arc<Outbound>> {
+    self.connections.lock()
+      .unwrap()
+      .entries
+      .values()
+      .filter(|entry| {
+        entry.info.nickname.as_ref().is_some_and(|name| **name == nickname)
+      })
+      .map(|entry| entry.outbound.clone())
+      .collect()
+  }
+
+  /// Wait until every registration has been dropped.
+  pub async fn emptied(&self) {
+    loop {
+      // As in `Group::wait_for_room`, register interest before checking.
+      let emptied = self.emptied.notified();
+      if self.connections.lock().unwrap().entries.is_empty() {
+        return;
+      }
+      emptied.await;
+    }
+  }
+}
+
+/// A connection's entry in a `ConnectionTable`, removed when this is dropped.
+pub struct Registration {
+  table: Arc<ConnectionTable>,
+  id: u64,
+}
+
+impl Registration {
+  /// Record that the client has signed in as `nickname`.
+  pub fn signed_in(&self, nickname: Arc<String>) {
+    let mut connections = self.table.connections.lock().unwrap();
+    if let Some(entry) = connections.entries.get_mut(&self.id) {
+      entry.info.nickname = Some(nickname);
+    }
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    let mut connections = self.table.connections.lock().unwrap();
+    connections.entries.remove(&self.id);
+    if connections.entries.is_empty() {
+      self.table.emptied.notify_waiters();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec::Codec;
+  use async_std::prelude::*;
+  use async_std::task;
+  use std::time::Duration;
+
+  #[test]
+  fn emptied_waits_for_last_registration() {
+    task::block_on(async {
+      let table = Arc::new(ConnectionTable::new());
+      table.emptied().await;
+
+      let outbound = Arc::new(Outbound::new(Vec::new(), Codec::Json));
+      let first = table.register(outbound.clone(), None);
+      let second = table.register(outbound, None);
+      assert_eq!(table.outbounds().len(), 2);
+
+      second.signed_in(Arc::new("ferris".to_string()));
+      assert_eq!(table.signed_in_as("ferris").len(), 1);
+      assert!(table.signed_in_as("crab").is_empty());
+
+      let waiter = task::spawn({
+        let table = table.clone();
+        async move { table.emptied().await }
+      });
+      drop(first);
+      task::sleep(Duration::from_millis(50)).await;
+      assert_eq!(table.outbounds().len(), 1);
+
+      drop(second);
+      waiter.timeout(Duration::from_secs(1)).await.unwrap();
+    });
+  }
+}
@@ -0,0 +1,417 @@
+//! Relaying posts between servers, so that the members of a group can be
+//! spread across several of them.
+//!
+//! Servers link up over the ordinary protocol. The server that dials (see
+//! `link_to_peer`) sends a `Peer` request bearing the secret the servers
+//! share, and then a `Relay` request for each post made on its side;
+//! the server that accepts answers with a `Message` packet for each post
+//! made on its side. Either way, a server delivers a relayed post to its
+//! own group of that name, if it has one, and passes it along to its other
+//! peers.
+//!
+//! Every message has an ID, assigned by the server it was posted to. Each
+//! server remembers the IDs it has seen recently and drops repeats, so that
+//! messages don't circulate forever when the links form a cycle.
+//!
+//! Only posts are relayed. Edits, deletions, reactions, and news of who is
+//! present or typing stay on the server where they happened.
+//!
+//! Given a `TlsConnector`, the dialing server links over TLS, checking the
+//! peer's certificate, so the peer must be listening with TLS at that
+//! address. Otherwise the link is plain TCP, and anyone on the network
+//! between the servers can read the peer secret and every relayed post, and
+//! forge posts of their own. That is only acceptable when the servers
+//! share a private network, or talk through a tunnel that already encrypts
+//! and authenticates their traffic, like a VPN.
+
+use crate::codec::Codec;
+use crate::server::connection::{Outbound, Server};
+use crate::server::group_table::GroupTable;
+use crate::server::history::Entry;
+use crate::tls;
+use crate::utils::ChatResult;
+use crate::{FromClient, FromServer, Request};
+use async_std::channel::{self, TrySendError};
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use futures_rustls::TlsConnector;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// How many posts we'll hold for a peer that isn't keeping up, before we
+/// start dropping them.
+const RELAY_QUEUE: usize = 1000;
+
+/// How many message IDs we remember, to recognize repeats.
+const SEEN_LIMIT: usize = 100_000;
+
+/// How long to wait before redialing a peer after the first failure. Each
+/// further failure doubles this, up to `MAX_RETRY_DELAY`.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// This server's place among its peers.
+pub struct Federation {
+  /// Our name, which begins every message ID we assign.
+  name: Arc<String>,
+  /// The secret peers must present, or `None` if we don't accept any.
+  secret: Option<String>,
+  /// Our name and when we started, so that IDs stay unique across restarts.
+  id_prefix: String,
+  next_id: AtomicU64,
+  peers: Mutex<Peers>,
+  seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Peers {
+  next_id: u64,
+  links: HashMap<u64, Peer>,
+}
+
+struct Peer {
+  name: Arc<String>,
+  relays: channel::Sender<Relayed>,
+}
+
+/// A post on its way to a peer: the name of its group, and the post.
+pub type Relayed = (Arc<String>, Arc<Entry>);
+
+/// The IDs of recently seen messages, oldest first in `order`.
+#[derive(Default)]
+struct Seen {
+  ids: HashSet<Arc<String>>,
+  order: VecDeque<Arc<String>>,
+}
+
+/// A linked peer's entry in the `Federation`, removed when this is dropped.
+pub struct PeerLink {
+  federation: Arc<Federation>,
+  id: u64,
+  name: Arc<String>,
+}
+
+impl Federation {
+  /// Return a federation for a server called `name`, which accepts peers
+  /// presenting `secret`, if given.
+  pub fn new(name: &str, secret: Option<String>) -> Federation {
+    let started = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|elapsed| elapsed.as_millis())
+      .unwrap_or(0);
+    Federation {
+      name: Arc::new(name.to_string()),
+      secret,
+      id_prefix: format!("{}:{:x}", name, started),
+      next_id: AtomicU64::new(1),
+      peers: Mutex::new(Peers::default()),
+      seen: Mutex::new(Seen::default()),
+    }
+  }
+
+  pub fn name(&self) -> &Arc<String> {
+    &self.name
+  }
+
+  /// Return a new message ID, different from any other server's, and from
+  /// any this server has assigned before.
+  pub fn next_id(&self) -> Arc<String> {
+    let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+    Arc::new(format!("{}:{}", self.id_prefix, n))
+  }
+
+  /// Check that a server introducing itself as a peer knows our secret.
+  pub fn check_secret(&self, secret: &str) -> Result<(), String> {
+    match &self.secret {
+      Some(ours) if constant_time_eq(ours.as_bytes(), secret.as_bytes()) => {
+        Ok(())
+      }
+      Some(_) => Err("Incorrect peer secret".to_string()),
+      None => Err("This server does not accept peers".to_string()),
+    }
+  }
+
+  /// Return the names of the peers we're linked to, in alphabetical order.
+  pub fn peer_names(&self) -> Vec<Arc<String>> {
+    let peers = self.peers.lock().unwrap();
+    let mut names: Vec<_> = peers.links.values()
+      .map(|peer| peer.name.clone())
+      .collect();
+    names.sort();
+    names
+  }
+
+  /// Add a peer called `name`. Return its link, and the channel on which
+  /// the posts to send it will arrive, until the link is dropped.
+  pub fn add_peer(self: &Arc<Self>, name: Arc<String>)
+    -> (PeerLink, channel::Receiver<Relayed>)
+  {
+    let (sender, receiver) = channel::bounded(RELAY_QUEUE);
+    let mut peers = self.peers.lock().unwrap();
+    let id = peers.next_id;
+    peers.next_id += 1;
+    peers.links.insert(id, Peer { name: name.clone(), relays: sender });
+    (PeerLink { federation: self.clone(), id, name }, receiver)
+  }
+
+  /// Pass `entry`, just posted to `group_name` on this server, along to
+  /// every peer.
+  pub fn relay(&self, group_name: &Arc<String>, entry: Arc<Entry>) {
+    self.remember(&entry.id);
+    self.forward(group_name, entry, None);
+  }
+
+  /// Deliver `entry`, relayed by the peer at `from` for `group_name`, to our
+  /// group of that name, and pass it along to our other peers. If we've
+  /// seen it before, do nothing.
+  pub fn receive(&self,
+                 from: &PeerLink,
+                 groups: &GroupTable,
+                 group_name: Arc<String>,
+                 entry: Arc<Entry>)
+  {
+    if !self.remember(&entry.id) {
+      debug!(id = %entry.id, "dropped a message we've already seen");
+      return;
+    }
+    if let Some(group) = groups.get(&group_name) {
+      group.publish_relayed(entry.clone());
+    }
+    self.forward(&group_name, entry, Some(from.id));
+  }
+
+  /// Note that we've seen the message with `id`, and return true if we
+  /// hadn't already.
+  fn remember(&self, id: &Arc<String>) -> bool {
+    let mut seen = self.seen.lock().unwrap();
+    if !seen.ids.insert(id.clone()) {
+      return false;
+    }
+    seen.order.push_back(id.clone());
+    if seen.order.len() > SEEN_LIMIT {
+      if let Some(oldest) = seen.order.pop_front() {
+        seen.ids.remove(&oldest);
+      }
+    }
+    true
+  }
+
+  /// Queue `entry` for every peer but the one with the link ID `except`.
+  fn forward(&self,
+             group_name: &Arc<String>,
+             entry: Arc<Entry>,
+             except: Option<u64>)
+  {
+    let peers = self.peers.lock().unwrap();
+    for (&id, peer) in &peers.links {
+      if Some(id) == except {
+        continue;
+      }
+      let relayed = (group_name.clone(), entry.clone());
+      if let Err(TrySendError::Full(_)) = peer.relays.try_send(relayed) {
+        warn!(peer = %peer.name, id = %entry.id,
+              "dropped a message for a peer that isn't keeping up");
+      }
+    }
+  }
+}
+
+/// Return true if `a` and `b` are equal. However much of them matches, this
+/// looks at every byte, so that the time it takes reveals nothing but the
+/// length of the secret.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len()
+    && a.iter().zip(b).fold(0, |differences, (x, y)| differences | (x ^ y)) == 0
+}
+
+impl PeerLink {
+  /// Return the peer's name: the one it gave, if it linked to us, or its
+  /// address, if we linked to it.
+  pub fn name(&self) -> &Arc<String> {
+    &self.name
+  }
+}
+
+impl Drop for PeerLink {
+  fn drop(&mut self) {
+    self.federation.peers.lock().unwrap().links.remove(&self.id);
+  }
+}
+
+/// Send each post that arrives on `relays` to a peer that linked to us, via
+/// `outbound`, until the link is dropped or the connection fails.
+pub async fn send_relays(relays: channel::Receiver<Relayed>,
+                         outbound: Arc<Outbound>)
+{
+  while let Ok((group_name, entry)) = relays.recv().await {
+    if outbound.send(entry.to_packet(&group_name)).await.is_err() {
+      break;
+    }
+  }
+}
+
+/// Keep a link to the peer server at `address` until `stop` is closed,
+/// redialing whenever the link fails. Link over TLS if `connector` is given.
+pub async fn link_to_peer(address: String,
+                          connector: Option<TlsConnector>,
+                          server: Arc<Server>,
+                          stop: channel::Receiver<()>)
+{
+  let mut delay = INITIAL_RETRY_DELAY;
+  loop {
+    let started = Instant::now();
+    // Nothing is ever sent on `stop`; the caller closes it.
+    let linked = async {
+      Some(link(&address, connector.as_ref(), &server).await)
+    };
+    let stopped = async {
+      let _ = stop.recv().await;
+      None
+    };
+    match linked.race(stopped).await {
+      Some(Ok(())) => info!(peer = %address, "peer closed the link"),
+      Some(Err(error)) => warn!(peer = %address, %error, "peer link failed"),
+      None => return,
+    }
+
+    // A link that lasted a while is worth redialing right away.
+    if started.elapsed() > MAX_RETRY_DELAY {
+      delay = INITIAL_RETRY_DELAY;
+    }
+    let waited = async {
+      task::sleep(delay).await;
+      true
+    };
+    let stopped = async {
+      let _ = stop.recv().await;
+      false
+    };
+    if !waited.race(stopped).await {
+      return;
+    }
+    delay = (delay * 2).min(MAX_RETRY_DELAY);
+  }
+}
+
+/// Link to the peer at `address`, using TLS if `connector` is given, and
+/// relay posts both ways until the connection ends.
+async fn link(address: &str,
+              connector: Option<&TlsConnector>,
+              server: &Server)
+  -> ChatResult<()>
+{
+  let socket = TcpStream::connect(address).await?;
+  socket.set_nodelay(true)?;
+  match connector {
+    Some(connector) => {
+      let stream = connector.connect(tls::server_name(address)?, socket)
+        .await?;
+      relay(stream, address, server).await
+    }
+    None => relay(socket, address, server).await,
+  }
+}
+
+/// Introduce ourselves to the peer at `address` over `socket`, and relay
+/// posts both ways until the connection ends.
+async fn relay<S>(socket: S, address: &str, server: &Server) -> ChatResult<()>
+where
+  S: Read + Write + Unpin,
+{
+  let federation = &server.federation;
+  let secret = federation.secret.clone()
+    .ok_or("linking to peers requires a peer secret")?;
+
+  let (from_peer, mut to_peer) = futures_lite::io::split(socket);
+  let from_peer = BufReader::new(from_peer);
+  let mut from_peer = Box::pin(
+    Codec::Json.packets_within(from_peer, server.limits.max_frame_size)
+  );
+
+  let greeting = Request {
+    id: 0,
+    body: FromClient::Peer { server_name: federation.name.clone(), secret },
+  };
+  Codec::Json.send(&mut to_peer, &greeting).await?;
+  to_peer.flush().await?;
+  // The peer may start relaying before its `Ack` reaches us; hold on to
+  // anything that arrives first.
+  let mut early = vec![];
+  loop {
+    match from_peer.next().await.transpose()? {
+      Some(FromServer::Ack { id: 0 }) => break,
+      Some(FromServer::Nack { reason, .. }) => {
+        return Err(format!("peer refused the link: {}", reason).into());
+      }
+      Some(packet @ FromServer::Message { .. }) => early.push(packet),
+      other => {
+        return Err(format!("unexpected answer from peer: {:?}", other).into());
+      }
+    }
+  }
+  info!(peer = %address, "linked to peer");
+
+  let (link, relays) = federation.add_peer(Arc::new(address.to_string()));
+  for packet in early {
+    receive(federation, &link, &server.groups, packet);
+  }
+  let sending = async {
+    for id in 1.. {
+      let Ok((group_name, entry)) = relays.recv().await else { break };
+      let body = FromClient::Relay {
+        group_name,
+        id: entry.id.clone(),
+        sender: entry.sender.clone(),
+        timestamp: entry.timestamp,
+        message: entry.message.clone(),
+      };
+      Codec::Json.send(&mut to_peer, &Request { id, body }).await?;
+      to_peer.flush().await?;
+    }
+    Ok(())
+  };
+  let receiving = async {
+    while let Some(packet) = from_peer.next().await {
+      match packet? {
+        packet @ FromServer::Message { .. } => {
+          receive(federation, &link, &server.groups, packet);
+        }
+        FromServer::Nack { reason, .. } => {
+          warn!(peer = %address, %reason, "peer rejected a relayed message");
+        }
+        FromServer::Error { message, .. } => {
+          warn!(peer = %address, %message, "error from peer");
+        }
+        FromServer::Shutdown => break,
+        _ => {}
+      }
+    }
+    Ok(())
+  };
+  sending.race(receiving).await
+}
+
+/// Deliver `packet`, a `Message` relayed by the peer at `from`.
+fn receive(federation: &Federation,
+           from: &PeerLink,
+           groups: &GroupTable,
+           packet: FromServer)
+{
+  if let FromServer::Message { group_name, sender, timestamp, message, id } =
+    packet
+  {
+    let entry = Entry {
+      timestamp,
+      sender,
+      message,
+      id,
+      relayed_from: Some(from.name().clone()),
+    };
+    federation.receive(from, groups, group_name, Arc::new(entry));
+  }
+}
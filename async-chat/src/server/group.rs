@@ -0,0 +1,809 @@
+use async_std::channel;
+use async_std::prelude::*;
+use async_std::task;
+use crate::server::connection::Outbound;
+use crate::server::group_table::{GroupSettings, GroupTable};
+use crate::server::history::{Entry, History};
+use crate::server::metrics::METRICS;
+use crate::server::rate_limit::TokenBucket;
+use crate::Replay;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Notify};
+use tracing::{info_span, warn, Instrument};
+
+/// How often we tell a group that a member is typing, at most.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// What a group does about a member who has fallen so far behind that the
+/// group can't buffer any more messages for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+  /// Discard the oldest messages the member hasn't seen, and tell them how
+  /// many they missed.
+  DropOldest,
+  /// Tell the member they fell behind, and hang up on them.
+  Disconnect,
+  /// Make posters wait until every member has room for another message.
+  Block,
+}
+
+impl std::str::FromStr for SlowConsumerPolicy {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<SlowConsumerPolicy, String> {
+    match name {
+      "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+      "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+      "block" => Ok(SlowConsumerPolicy::Block),
+      _ => Err(format!("unknown slow-consumer policy '{}'", name)),
+    }
+  }
+}
+
+/// Something that happened in a group, for its members to hear about.
+#[derive(Clone, Debug)]
+pub enum Event {
+  Posted(Arc<Entry>),
+  Edited { id: Arc<String>, editor: Arc<String>, message: Arc<String> },
+  Deleted { id: Arc<String>, by: Arc<String> },
+  Reacted { id: Arc<String>, sender: Arc<String>, reaction: Arc<String> },
+  Joined { nickname: Arc<String> },
+  Left { nickname: Arc<String> },
+  Typing { nickname: Arc<String> },
+}
+
+impl Event {
+  /// Return the packet that tells members of `group_name` about this event.
+  pub fn to_packet(&self, group_name: &Arc<String>) -> FromServer {
+    let group_name = group_name.clone();
+    match self.clone() {
+      Event::Posted(entry) => entry.to_packet(&group_name),
+      Event::Edited { id, editor, message } => {
+        FromServer::Edited { group_name, id, editor, message }
+      }
+      Event::Deleted { id, by } => FromServer::Deleted { group_name, id, by },
+      Event::Reacted { id, sender, reaction } => {
+        FromServer::Reacted { group_name, id, sender, reaction }
+      }
+      Event::Joined { nickname } => FromServer::Joined { group_name, nickname },
+      Event::Left { nickname } => FromServer::Left { group_name, nickname },
+      Event::Typing { nickname } => FromServer::Typing { group_name, nickname },
+    }
+  }
+}
+
+pub struct Group {
+  name: Arc<String>,
+  sender: broadcast::Sender<Event>,
+  /// How many messages `sender` holds for members who haven't seen them.
+  capacity: usize,
+  policy: SlowConsumerPolicy,
+  /// Under `Block`, notified whenever a member catches up a little.
+  room: Notify,
+  /// Under `Block`, held while waiting for room, so that posts are
+  /// published in the order they arrived.
+  posting: async_std::sync::Mutex<()>,
+  history: Mutex<History>,
+  members: Mutex<Members>,
+  /// Throttles posts from all members together, if limited.
+  limiter: Option<Mutex<TokenBucket>>,
+  /// The table to notify when this group loses its last member.
+  table: Weak<GroupTable>,
+}
+
+struct Members {
+  /// Each member, by nickname.
+  nicknames: HashMap<Arc<String>, Member>,
+  /// The number to give the next member, so that memberships can tell
+  /// whether the member they belong to has been expelled.
+  next_member: u64,
+  /// When the last member left, if the group is empty.
+  idle_since: Option<Instant>,
+  /// When we last told the group that each member was typing.
+  typing: HashMap<Arc<String>, Instant>,
+}
+
+struct Member {
+  number: u64,
+  /// How many subscriptions the member has.
+  subscriptions: usize,
+  /// Dropped to tell the member's subscriptions that they were expelled.
+  /// Nothing is ever sent on it.
+  _expel: channel::Sender<()>,
+  expelled: channel::Receiver<()>,
+}
+
+impl Members {
+  /// Return the members' nicknames, in alphabetical order.
+  fn list(&self) -> Vec<Arc<String>> {
+    let mut nicknames: Vec<_> = self.nicknames.keys().cloned().collect();
+    nicknames.sort();
+    nicknames
+  }
+
+  /// Note that `nickname`, a member of `group` until just now, has left it,
+  /// and return true if the group is now empty.
+  fn left(&mut self, group: &Group, nickname: &Arc<String>) -> bool {
+    self.typing.remove(nickname);
+    group.announce(Event::Left { nickname: nickname.clone() });
+    if self.nicknames.is_empty() {
+      self.idle_since = Some(Instant::now());
+      true
+    } else {
+      false
+    }
+  }
+}
+
+impl Group {
+  pub fn new(name: Arc<String>,
+             history: History,
+             settings: &GroupSettings,
+             table: Weak<GroupTable>)
+    -> Group
+  {
+    let capacity = settings.capacity.max(1);
+    let (sender, _receiver) = broadcast::channel(capacity);
+    let policy = settings.policy_for(&name);
+    Group {
+      name,
+      sender,
+      capacity,
+      policy,
+      room: Notify::new(),
+      posting: async_std::sync::Mutex::new(()),
+      history: Mutex::new(history),
+      members: Mutex::new(Members {
+        nicknames: HashMap::new(),
+        next_member: 0,
+        idle_since: Some(Instant::now()),
+        typing: HashMap::new(),
+      }),
+      limiter: settings.rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+      table,
+    }
+  }
+
+  pub fn name(&self) -> &Arc<String> {
+    &self.name
+  }
+
+  /// Subscribe `outbound` to this group on behalf of `nickname`, first
+  /// sending it the history selected by `replay`, and then a `Presence`
+  /// packet saying who is here. The member remains in the group until
+  /// the returned subscription is cancelled, or sending to `outbound`
+  /// fails.
+  pub fn join(self: &Arc<Self>,
+              nickname: Arc<String>,
+              outbound: Arc<Outbound>,
+              replay: Option<Replay>)
+    -> Subscription
+  {
+    // Subscribe while holding the history lock, so that every message ends
+    // up either in the backlog or in the receiver, but never both. Likewise,
+    // hold the member list's lock, so that every later join or leave shows
+    // up in the receiver, and none is already in the snapshot. We subscribe
+    // after announcing our own join, since we don't need to hear of it.
+    let (backlog, membership, present, receiver) = {
+      let history = self.history.lock().unwrap();
+      let mut members = self.members.lock().unwrap();
+      let membership = Membership::new(self.clone(), nickname, &mut members);
+      (history.replay(replay), membership, members.list(),
+       self.sender.subscribe())
+    };
+
+    let (stop, stopped) = channel::bounded(1);
+    let span = info_span!("member", group = %self.name);
+    let task = task::spawn(handle_subscriber(membership, backlog, present,
+                                             receiver, stopped, outbound)
+                             .instrument(span));
+    Subscription { stop, task }
+  }
+
+  /// Tell the members that `nickname` is typing, unless we did so less than
+  /// `TYPING_INTERVAL` ago.
+  pub fn typing(&self, nickname: &Arc<String>) {
+    let mut members = self.members.lock().unwrap();
+    let now = Instant::now();
+    if let Some(last) = members.typing.get(nickname) {
+      if now.duration_since(*last) < TYPING_INTERVAL {
+        return;
+      }
+    }
+    members.typing.insert(nickname.clone(), now);
+    self.announce(Event::Typing { nickname: nickname.clone() });
+  }
+
+  /// Send the members `event`, which says who is here or typing. Unlike a
+  /// post, this never waits: under the `Block` policy, if a member has no
+  /// room for it, we drop it rather than push out a message they haven't
+  /// read.
+  fn announce(&self, event: Event) {
+    if self.policy == SlowConsumerPolicy::Block
+      && self.sender.len() >= self.capacity
+    {
+      return;
+    }
+    let _ignored = self.sender.send(event);
+  }
+
+  /// Post `entry` to the group, unless the group is receiving messages
+  /// faster than its rate limit allows. Under the `Block` policy, this waits
+  /// until every member has room for the message.
+  pub async fn post(&self, entry: Arc<Entry>) -> Result<(), String> {
+    self.broadcast(|history| Ok(self.record(history, entry))).await
+  }
+
+  /// On behalf of `editor`, change the text of the message `id` to
+  /// `message`. Only the message's author may, unless `moderator` is true.
+  /// This is subject to the same limits as `post`.
+  pub async fn edit(&self,
+                    id: Arc<String>,
+                    editor: Arc<String>,
+                    moderator: bool,
+                    message: Arc<String>)
+    -> Result<(), String>
+  {
+    self.broadcast(|history| {
+      let original = self.authored(history, &id, &editor, moderator)?;
+      let edited = Entry { message: message.clone(), ..(**original).clone() };
+      history.replace(Arc::new(edited));
+      Ok(Event::Edited { id, editor, message })
+    }).await
+  }
+
+  /// On behalf of `by`, remove the message `id`, on the same terms as
+  /// `edit`.
+  pub async fn delete(&self,
+                      id: Arc<String>,
+                      by: Arc<String>,
+                      moderator: bool)
+    -> Result<(), String>
+  {
+    self.broadcast(|history| {
+      self.authored(history, &id, &by, moderator)?;
+      history.remove(&id);
+      Ok(Event::Deleted { id, by })
+    }).await
+  }
+
+  /// Tell the members that `sender` reacted to the message `id` with
+  /// `reaction`. Reactions aren't kept in the history, so members who join
+  /// later don't see them.
+  pub async fn react(&self,
+                     id: Arc<String>,
+                     sender: Arc<String>,
+                     reaction: Arc<String>)
+    -> Result<(), String>
+  {
+    self.broadcast(|history| {
+      self.find(history, &id)?;
+      Ok(Event::Reacted { id, sender, reaction })
+    }).await
+  }
+
+  /// Return the message `id` from `history`, if `by` wrote it or is a
+  /// `moderator`.
+  fn authored<'h>(&self,
+                  history: &'h History,
+                  id: &str,
+                  by: &Arc<String>,
+                  moderator: bool)
+    -> Result<&'h Arc<Entry>, String>
+  {
+    let entry = self.find(history, id)?;
+    if moderator {
+      return Ok(entry);
+    }
+    if let Some(peer) = &entry.relayed_from {
+      return Err(format!("Only an owner of '{}' may change a message \
+                          relayed from {}", self.name, peer));
+    }
+    if entry.sender != *by {
+      return Err(format!("Only its author or an owner of '{}' may change \
+                          that message", self.name));
+    }
+    Ok(entry)
+  }
+
+  fn find<'h>(&self, history: &'h History, id: &str)
+    -> Result<&'h Arc<Entry>, String>
+  {
+    history.get(id).ok_or_else(|| {
+      format!("No message '{}' in '{}', or it is too old to change",
+              id, self.name)
+    })
+  }
+
+  /// Apply `change` to the history, and send the members the event it
+  /// returns, unless the group is busier than its rate limit allows. Under
+  /// the `Block` policy, this first waits until every member has room.
+  async fn broadcast<F>(&self, change: F) -> Result<(), String>
+  where
+    F: FnOnce(&mut History) -> Result<Event, String>,
+  {
+    if let Some(limiter) = &self.limiter {
+      if !limiter.lock().unwrap().try_take() {
+        return Err(format!("Group '{}' is busy; try again shortly",
+                           self.name));
+      }
+    }
+
+    if self.policy == SlowConsumerPolicy::Block {
+      let _posting = self.posting.lock().await;
+      self.wait_for_room().await;
+      self.apply(change)
+    } else {
+      self.apply(change)
+    }
+  }
+
+  /// Apply `change` and send its event while holding the history lock, so
+  /// that a member joining meanwhile sees its effect exactly once: either
+  /// in the history, or as an event.
+  fn apply<F>(&self, change: F) -> Result<(), String>
+  where
+    F: FnOnce(&mut History) -> Result<Event, String>,
+  {
+    let mut history = self.history.lock().unwrap();
+    let event = change(&mut history)?;
+    let _ignored = self.sender.send(event);
+    Ok(())
+  }
+
+  /// Wait until the slowest member has fewer than `capacity` messages
+  /// waiting, so that sending another won't push any out.
+  async fn wait_for_room(&self) {
+    loop {
+      // Register interest before checking, so we can't miss a wakeup that
+      // arrives in between.
+      let room = self.room.notified();
+      if self.sender.len() < self.capacity {
+        return;
+      }
+      room.await;
+    }
+  }
+
+  /// Deliver `entry`, relayed from a peer server, to the group's members.
+  /// This skips the rate limit and, under `Block`, the wait for room: the
+  /// server it was posted to has already applied its own.
+  pub fn publish_relayed(&self, entry: Arc<Entry>) {
+    let _posted = self.apply(|history| Ok(self.record(history, entry)));
+  }
+
+  /// Add `entry` to `history`, and return the event that announces it.
+  fn record(&self, history: &mut History, entry: Arc<Entry>) -> Event {
+    history.append(entry.clone());
+    METRICS.posts.increment();
+    Event::Posted(entry)
+  }
+
+  /// Make sure this group's history has reached the disk, if it keeps one.
+  pub async fn sync_history(&self) -> std::io::Result<()> {
+    let synced = self.history.lock().unwrap().sync();
+    synced.await
+  }
+
+  /// Return up to `limit` of the retained messages containing every word of
+  /// `query`, newest first.
+  pub fn search(&self, query: &str, limit: usize) -> Vec<Arc<Entry>> {
+    self.history.lock().unwrap().search(query, limit)
+  }
+
+  /// End all of `nickname`'s subscriptions to this group, telling them with
+  /// a `Left` packet. This is for members who may no longer read it.
+  pub fn expel(self: &Arc<Self>, nickname: &Arc<String>) {
+    let now_idle = {
+      let mut members = self.members.lock().unwrap();
+      if members.nicknames.remove(nickname).is_none() {
+        return;
+      }
+      members.left(self, nickname)
+    };
+    if now_idle {
+      if let Some(table) = self.table.upgrade() {
+        table.release(self);
+      }
+    }
+  }
+
+  /// Return the nicknames of this group's members, in alphabetical order.
+  pub fn members(&self) -> Vec<Arc<String>> {
+    self.members.lock().unwrap().list()
+  }
+
+  /// If this group has no members, return when the last one left.
+  pub fn idle_since(&self) -> Option<Instant> {
+    self.members.lock().unwrap().idle_since
+  }
+}
+
+/// A connection's subscription to a group, as returned by `Group::join`.
+pub struct Subscription {
+  stop: channel::Sender<()>,
+  task: task::JoinHandle<()>,
+}
+
+impl Subscription {
+  /// Stop delivering the group's messages, and leave the group. This waits
+  /// for any packet already being sent to finish, so the connection never
+  /// sees half a packet.
+  pub async fn cancel(self) {
+    drop(self.stop);
+    self.task.await;
+  }
+
+  /// Return false if the subscription has ended by itself: because the
+  /// member was expelled, say, or sending to them failed.
+  pub fn is_active(&self) -> bool {
+    !self.stop.is_closed()
+  }
+}
+
+/// A member's presence in a group, which ends when this is dropped.
+struct Membership {
+  group: Arc<Group>,
+  nickname: Arc<String>,
+  /// The number of the member this belongs to.
+  member: u64,
+  /// Closed if the member is expelled.
+  expelled: channel::Receiver<()>,
+}
+
+impl Membership {
+  /// Add `nickname` to `members`, the locked member list of `group`, and
+  /// tell the group if they weren't already a member.
+  fn new(group: Arc<Group>, nickname: Arc<String>, members: &mut Members)
+    -> Membership
+  {
+    if !members.nicknames.contains_key(&nickname) {
+      let (expel, expelled) = channel::bounded(1);
+      let member = Member {
+        number: members.next_member,
+        subscriptions: 0,
+        _expel: expel,
+        expelled,
+      };
+      members.next_member += 1;
+      members.nicknames.insert(nickname.clone(), member);
+      group.announce(Event::Joined { nickname: nickname.clone() });
+    }
+    let member = members.nicknames.get_mut(&nickname).unwrap();
+    member.subscriptions += 1;
+    let (member, expelled) = (member.number, member.expelled.clone());
+    members.idle_since = None;
+    Membership { group, nickname, member, expelled }
+  }
+
+  /// Return true if the member was expelled from the group.
+  fn expelled(&self) -> bool {
+    self.expelled.is_closed()
+  }
+}
+
+impl Drop for Membership {
+  fn drop(&mut self) {
+    let now_idle = {
+      let mut members = self.group.members.lock().unwrap();
+      // If the member was expelled, their entry is already gone, and
+      // perhaps replaced by a later one.
+      match members.nicknames.get_mut(&self.nickname) {
+        Some(member) if member.number == self.member => {
+          member.subscriptions -= 1;
+          if member.subscriptions == 0 {
+            members.nicknames.remove(&self.nickname);
+            members.left(&self.group, &self.nickname)
+          } else {
+            false
+          }
+        }
+        _ => false,
+      }
+    };
+
+    // The table locks its map before our member list, so we must release
+    // the latter before telling the table.
+    if now_idle {
+      if let Some(table) = self.group.table.upgrade() {
+        table.release(&self.group);
+      }
+    }
+  }
+}
+
+use crate::FromServer;
+use tokio::sync::broadcast::error::RecvError;
+
+async fn handle_subscriber(membership: Membership,
+                            backlog: Vec<Arc<Entry>>,
+                            present: Vec<Arc<String>>,
+                            mut receiver: broadcast::Receiver<Event>,
+                            stopped: channel::Receiver<()>,
+                            outbound: Arc<Outbound>)
+{
+  deliver(&membership, backlog, present, &mut receiver, &stopped, &outbound)
+    .await;
+
+  // Tell an expelled member why the group's messages stopped.
+  if membership.expelled() && !stopped.is_closed() {
+    let _ = outbound.send(FromServer::Left {
+      group_name: membership.group.name.clone(),
+      nickname: membership.nickname.clone(),
+    }).await;
+  }
+
+  // Our unread messages no longer count against the channel's capacity, so
+  // a blocked poster may be able to proceed.
+  drop(receiver);
+  membership.group.room.notify_waiters();
+}
+
+async fn deliver(membership: &Membership,
+                 backlog: Vec<Arc<Entry>>,
+                 present: Vec<Arc<String>>,
+                 receiver: &mut broadcast::Receiver<Event>,
+                 stopped: &channel::Receiver<()>,
+                 outbound: &Outbound)
+{
+  let group = &membership.group;
+  let group_name = &group.name;
+
+  let presence = FromServer::Presence {
+    group_name: group_name.clone(),
+    members: present,
+  };
+  let packets = backlog.iter()
+    .map(|entry| entry.to_packet(group_name))
+    .chain(std::iter::once(presence));
+  for packet in packets {
+    if stopped.is_closed() || membership.expelled() {
+      return;
+    }
+    if outbound.send(packet).await.is_err() {
+      return;
+    }
+  }
+
+  loop {
+    // Nothing is ever sent on `stopped`; `Subscription::cancel` closes it.
+    // Likewise, `Group::expel` closes `membership.expelled`.
+    let received = async { Some(receiver.recv().await) };
+    let cancelled = async {
+      let _ = stopped.recv().race(membership.expelled.recv()).await;
+      None
+    };
+
+    let received = received.race(cancelled).await;
+    group.room.notify_waiters();
+
+    let packet = match received {
+      // Members know when they're typing, and `handle_subscriber` tells
+      // them if they were expelled.
+      Some(Ok(Event::Typing { nickname } | Event::Left { nickname }))
+        if nickname == membership.nickname => {
+        continue;
+      }
+      Some(Ok(event)) => event.to_packet(group_name),
+
+      Some(Err(RecvError::Lagged(n))) => {
+        METRICS.lagged_messages.add(n);
+        match group.policy {
+          SlowConsumerPolicy::DropOldest | SlowConsumerPolicy::Block => {
+            FromServer::Error {
+              id: None,
+              message: format!("Dropped {} messages from {}.", n, group_name),
+            }
+          }
+          SlowConsumerPolicy::Disconnect => {
+            let notice = FromServer::Error {
+              id: None,
+              message: format!("Disconnected for falling {} messages behind \
+                                in {}.", n, group_name),
+            };
+            let _ = outbound.send(notice).await;
+            outbound.disconnect();
+            warn!(behind = n, "disconnected a member for falling behind");
+            return;
+          }
+        }
+      }
+
+      Some(Err(RecvError::Closed)) | None => return,
+    };
+
+    if outbound.send(packet).await.is_err() {
+      return;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec::Codec;
+  use std::io;
+  use std::pin::Pin;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::task::{Context, Poll, Waker};
+  use std::time::Duration;
+
+  /// A connection whose client may stop reading: once `stall` is called,
+  /// writes stay pending until `open` is called.
+  #[derive(Clone, Default)]
+  struct StalledReader(Arc<Mutex<Stall>>);
+
+  #[derive(Default)]
+  struct Stall {
+    open: bool,
+    waker: Option<Waker>,
+    written: Vec<u8>,
+  }
+
+  impl StalledReader {
+    fn stall(&self) {
+      self.0.lock().unwrap().open = false;
+    }
+
+    fn open(&self) {
+      let mut stall = self.0.lock().unwrap();
+      stall.open = true;
+      if let Some(waker) = stall.waker.take() {
+        waker.wake();
+      }
+    }
+
+    /// Return the messages and errors written so far, as strings, skipping
+    /// the presence snapshot sent on joining.
+    fn received(&self) -> Vec<String> {
+      let stall = self.0.lock().unwrap();
+      stall.written
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match serde_json::from_slice(line).unwrap() {
+          FromServer::Message { message, .. } => Some(message.to_string()),
+          FromServer::Error { message, .. } => Some(message),
+          FromServer::Presence { .. } => None,
+          other => panic!("unexpected packet {:?}", other),
+        })
+        .collect()
+    }
+  }
+
+  impl async_std::io::Write for StalledReader {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+      -> Poll<io::Result<usize>>
+    {
+      let mut stall = self.0.lock().unwrap();
+      if !stall.open {
+        stall.waker = Some(cx.waker().clone());
+        return Poll::Pending;
+      }
+      stall.written.extend_from_slice(buf);
+      Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+      -> Poll<io::Result<()>>
+    {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context)
+      -> Poll<io::Result<()>>
+    {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  const PAUSE: Duration = Duration::from_millis(100);
+
+  fn group(policy: SlowConsumerPolicy) -> Arc<Group> {
+    let settings = GroupSettings {
+      capacity: 4,
+      policy,
+      ..GroupSettings::default()
+    };
+    Arc::new(Group::new(Arc::new("Snails".to_string()),
+                        History::in_memory(0), &settings, Weak::new()))
+  }
+
+  /// Join a reader to `group`, let it take the presence snapshot, and then
+  /// stall it and post message 0, which its subscriber picks up and gets
+  /// stuck trying to send.
+  async fn join_stalled(group: &Arc<Group>)
+    -> (StalledReader, Arc<Outbound>, Subscription)
+  {
+    let reader = StalledReader::default();
+    reader.open();
+    let outbound = Arc::new(Outbound::new(reader.clone(), Codec::Json));
+    let subscription = group.join(Arc::new("slowpoke".to_string()),
+                                  outbound.clone(), None);
+    task::sleep(PAUSE).await;
+    reader.stall();
+    post(group, 0).await;
+    task::sleep(PAUSE).await;
+    (reader, outbound, subscription)
+  }
+
+  async fn post(group: &Group, n: usize) {
+    let entry = Entry::new(Arc::new(n.to_string()),
+                           Arc::new("ferris".to_string()),
+                           Arc::new(n.to_string()));
+    group.post(Arc::new(entry)).await.unwrap();
+  }
+
+  #[test]
+  fn drop_oldest_skips_ahead_with_notice() {
+    task::block_on(async {
+      let group = group(SlowConsumerPolicy::DropOldest);
+      let (reader, _outbound, subscription) = join_stalled(&group).await;
+
+      for n in 1..10 {
+        post(&group, n).await;
+      }
+      reader.open();
+      task::sleep(PAUSE).await;
+
+      assert_eq!(reader.received(),
+                 ["0", "Dropped 5 messages from Snails.", "6", "7", "8", "9"]);
+      subscription.cancel().await;
+    });
+  }
+
+  #[test]
+  fn disconnect_hangs_up_on_slow_member() {
+    task::block_on(async {
+      let group = group(SlowConsumerPolicy::Disconnect);
+      let (reader, outbound, subscription) = join_stalled(&group).await;
+
+      for n in 1..10 {
+        post(&group, n).await;
+      }
+      reader.open();
+      task::sleep(PAUSE).await;
+
+      assert_eq!(reader.received(),
+                 ["0", "Disconnected for falling 5 messages behind in Snails."]);
+      assert!(outbound.is_disconnected());
+      subscription.cancel().await;
+      assert!(group.members().is_empty());
+    });
+  }
+
+  #[test]
+  fn block_holds_posters_until_slow_member_catches_up() {
+    task::block_on(async {
+      let group = group(SlowConsumerPolicy::Block);
+      let (reader, _outbound, subscription) = join_stalled(&group).await;
+
+      let posted = Arc::new(AtomicUsize::new(0));
+      let poster = task::spawn({
+        let group = group.clone();
+        let posted = posted.clone();
+        async move {
+          for n in 1..10 {
+            post(&group, n).await;
+            posted.fetch_add(1, Ordering::SeqCst);
+          }
+        }
+      });
+
+      // Messages 1 through 4 fill the channel; message 5 has to wait.
+      task::sleep(PAUSE).await;
+      assert_eq!(posted.load(Ordering::SeqCst), 4);
+
+      reader.open();
+      poster.await;
+      task::sleep(PAUSE).await;
+
+      let expected: Vec<String> = (0..10).map(|n| n.to_string()).collect();
+      assert_eq!(reader.received(), expected);
+      subscription.cancel().await;
+    });
+  }
+}
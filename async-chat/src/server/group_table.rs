@@ -0,0 +1,435 @@
+use async_std::task;
+use crate::server::access::AccessTable;
+use crate::server::connection::Outbound;
+use crate::server::group::{Group, SlowConsumerPolicy, Subscription};
+use crate::server::history::{Entry, History};
+use crate::server::rate_limit::Rate;
+use crate::{Replay, Role};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+use std::time::Duration;
+
+pub struct GroupTable {
+  groups: Mutex<HashMap<Arc<String>, Arc<Group>>>,
+  settings: GroupSettings,
+  access: AccessTable,
+}
+
+/// How the table sets up each group it creates.
+#[derive(Clone, Debug)]
+pub struct GroupSettings {
+  /// Where to keep each group's history, if it should outlive the server.
+  pub history_directory: Option<PathBuf>,
+  /// The number of past messages each group keeps for replay.
+  pub history_limit: usize,
+  /// How long a group with no members lingers before it is dropped.
+  pub idle_timeout: Duration,
+  /// How fast messages may be posted to a group, if limited.
+  pub rate: Option<Rate>,
+  /// How many messages a member may fall behind before `policy` applies.
+  pub capacity: usize,
+  /// What to do about members who fall `capacity` messages behind.
+  pub policy: SlowConsumerPolicy,
+  /// Groups that should use some other policy, by name.
+  pub policies: HashMap<String, SlowConsumerPolicy>,
+  /// The most groups the table will hold at once, if limited.
+  pub max_groups: Option<usize>,
+}
+
+impl Default for GroupSettings {
+  fn default() -> GroupSettings {
+    GroupSettings {
+      history_directory: None,
+      history_limit: 1000,
+      idle_timeout: Duration::from_secs(300),
+      rate: None,
+      capacity: 1000,
+      policy: SlowConsumerPolicy::DropOldest,
+      policies: HashMap::new(),
+      max_groups: None,
+    }
+  }
+}
+
+impl GroupSettings {
+  /// Return the slow-consumer policy for the group called `name`.
+  pub fn policy_for(&self, name: &str) -> SlowConsumerPolicy {
+    self.policies.get(name).copied().unwrap_or(self.policy)
+  }
+}
+
+impl GroupTable {
+  /// Return a new, empty table, whose groups are created according to
+  /// `settings`, and whose members may do what `access` says.
+  pub fn new(settings: GroupSettings, access: AccessTable) -> GroupTable {
+    GroupTable {
+      groups: Mutex::new(HashMap::new()),
+      settings,
+      access,
+    }
+  }
+
+  pub fn get(&self, name: &String) -> Option<Arc<Group>> {
+    self.groups.lock()
+      .unwrap()
+      .get(name)
+      .cloned()
+  }
+
+  /// Join `nickname` to the group called `name`, creating it if necessary,
+  /// if its access list allows. Whoever first creates a group owns it. See
+  /// `Group::join` for details.
+  pub async fn join(self: &Arc<Self>,
+                    name: Arc<String>,
+                    nickname: Arc<String>,
+                    outbound: Arc<Outbound>,
+                    replay: Option<Replay>)
+    -> Result<Subscription, String>
+  {
+    // Loading a group's history may block, so we do it without the table
+    // lock, and then try again, in case someone else created the group
+    // meanwhile.
+    let mut history = None;
+    loop {
+      if let Some(subscription) = self.try_join(&name, &nickname, &outbound,
+                                                replay, &mut history)? {
+        return Ok(subscription);
+      }
+      let opened = self.open_history(&name).await
+        .map_err(|error| {
+          format!("Unable to open group '{}': {}", name, error)
+        })?;
+      history = Some(opened);
+    }
+  }
+
+  /// Join `nickname` to the group called `name`. If there is no such group,
+  /// create it with `history`, or return `None` if we haven't loaded that.
+  fn try_join(self: &Arc<Self>,
+              name: &Arc<String>,
+              nickname: &Arc<String>,
+              outbound: &Arc<Outbound>,
+              replay: Option<Replay>,
+              history: &mut Option<History>)
+    -> Result<Option<Subscription>, String>
+  {
+    // Join while holding the table lock, so that `remove_if_idle` can't
+    // drop the group between our finding it and our joining it.
+    let mut groups = self.groups.lock().unwrap();
+    if let Some(group) = groups.get(name) {
+      self.access.check_join(name, nickname)?;
+      return Ok(Some(group.join(nickname.clone(), outbound.clone(), replay)));
+    }
+
+    let settings = &self.settings;
+    if let Some(max_groups) = settings.max_groups {
+      if groups.len() >= max_groups {
+        return Err(format!("Unable to open group '{}': \
+                            the server already has {} groups",
+                           name, max_groups));
+      }
+    }
+    self.access.check_join(name, nickname)?;
+
+    let history = match history.take() {
+      Some(history) => history,
+      None => return Ok(None),
+    };
+    let group = Arc::new(Group::new(name.clone(), history, settings,
+                                    Arc::downgrade(self)));
+    groups.insert(name.clone(), group.clone());
+    let subscription = group.join(nickname.clone(), outbound.clone(), replay);
+    self.access.claim(name, nickname);
+    drop(groups);
+
+    info!(group = %name, live = self.count(), "created group");
+    Ok(Some(subscription))
+  }
+
+  async fn open_history(&self, name: &str) -> io::Result<History> {
+    let settings = &self.settings;
+    match &settings.history_directory {
+      Some(directory) => {
+        let (directory, name) = (directory.clone(), name.to_string());
+        let limit = settings.history_limit;
+        task::spawn_blocking(move || History::open(&directory, &name, limit))
+          .await
+      }
+      None => Ok(History::in_memory(settings.history_limit)),
+    }
+  }
+
+  /// Post `entry` to the group called `name`, if its access list allows
+  /// the entry's sender to. See `Group::post`.
+  pub async fn post(&self, name: &Arc<String>, entry: Arc<Entry>)
+    -> Result<(), String>
+  {
+    let group = self.get(name)
+      .ok_or_else(|| format!("Group '{}' does not exist", name))?;
+    self.access.check_post(name, &entry.sender)?;
+    group.post(entry).await
+  }
+
+  /// On behalf of `by`, change the text of the message `id` in the group
+  /// called `name`. See `Group::edit`.
+  pub async fn edit(&self,
+                    name: &Arc<String>,
+                    id: Arc<String>,
+                    by: &Arc<String>,
+                    message: Arc<String>)
+    -> Result<(), String>
+  {
+    let group = self.get(name)
+      .ok_or_else(|| format!("Group '{}' does not exist", name))?;
+    self.access.check_post(name, by)?;
+    let moderator = self.access.is_owner(name, by);
+    group.edit(id, by.clone(), moderator, message).await
+  }
+
+  /// On behalf of `by`, remove the message `id` from the group called
+  /// `name`. See `Group::delete`.
+  pub async fn delete(&self,
+                      name: &Arc<String>,
+                      id: Arc<String>,
+                      by: &Arc<String>)
+    -> Result<(), String>
+  {
+    let group = self.get(name)
+      .ok_or_else(|| format!("Group '{}' does not exist", name))?;
+    self.access.check_post(name, by)?;
+    let moderator = self.access.is_owner(name, by);
+    group.delete(id, by.clone(), moderator).await
+  }
+
+  /// On behalf of `sender`, react to the message `id` in the group called
+  /// `name`, if they may post there. See `Group::react`.
+  pub async fn react(&self,
+                     name: &Arc<String>,
+                     id: Arc<String>,
+                     sender: &Arc<String>,
+                     reaction: Arc<String>)
+    -> Result<(), String>
+  {
+    let group = self.get(name)
+      .ok_or_else(|| format!("Group '{}' does not exist", name))?;
+    self.access.check_post(name, sender)?;
+    group.react(id, sender.clone(), reaction).await
+  }
+
+  /// On behalf of `by`, change `nickname`'s role in the group called `name`.
+  /// Members left with no role are expelled. See `FromClient::SetRole`.
+  pub fn set_role(self: &Arc<Self>,
+                  name: &Arc<String>,
+                  by: &Arc<String>,
+                  nickname: Option<Arc<String>>,
+                  role: Option<Role>)
+    -> Result<(), String>
+  {
+    // Hold the table lock, so that everyone who joined under the old roles
+    // is among the members we check below.
+    let group = {
+      let groups = self.groups.lock().unwrap();
+      self.access.set_role(name, by, nickname, role)?;
+      groups.get(name).cloned()
+    };
+
+    if let Some(group) = group {
+      for member in group.members() {
+        if self.access.check_join(name, &member).is_err() {
+          info!(group = %name, %member, "expelled member");
+          group.expel(&member);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Return the names of all groups, in alphabetical order.
+  pub fn names(&self) -> Vec<Arc<String>> {
+    let mut names: Vec<_> = self.groups.lock()
+      .unwrap()
+      .keys()
+      .cloned()
+      .collect();
+    names.sort();
+    names
+  }
+
+  /// Return the number of live groups.
+  pub fn count(&self) -> usize {
+    self.groups.lock().unwrap().len()
+  }
+
+  /// Make sure every group's history, and the access lists, have reached
+  /// the disk, logging any failures.
+  pub async fn sync_to_disk(&self) {
+    let groups: Vec<_> = self.groups.lock().unwrap().values().cloned().collect();
+    for group in groups {
+      if let Err(error) = group.sync_history().await {
+        error!(group = %group.name(), %error, "failed to save history");
+      }
+    }
+    self.access.sync().await;
+  }
+
+  /// Called by `group` when its last member leaves. Drop it from the table,
+  /// either now or once the idle timeout has passed.
+  pub fn release(self: &Arc<Self>, group: &Arc<Group>) {
+    if self.settings.idle_timeout.is_zero() {
+      self.remove_if_idle(group);
+      return;
+    }
+
+    let table = self.clone();
+    let group = group.clone();
+    task::spawn(async move {
+      task::sleep(table.settings.idle_timeout).await;
+      table.remove_if_idle(&group);
+    });
+  }
+
+  fn remove_if_idle(&self, group: &Arc<Group>) {
+    let removed = {
+      let mut groups = self.groups.lock().unwrap();
+      let idle = group.idle_since()
+        .is_some_and(|since| since.elapsed() >= self.settings.idle_timeout);
+      let current = groups.get(group.name())
+        .is_some_and(|entry| Arc::ptr_eq(entry, group));
+      let removed = idle && current && groups.remove(group.name()).is_some();
+
+      // A group with no history on disk leaves nothing behind for its owners
+      // to control, so its access list can go too. Do this while holding
+      // the table lock, so that nobody can recreate the group meanwhile.
+      if removed && self.settings.history_directory.is_none() {
+        self.access.forget(group.name());
+      }
+      removed
+    };
+
+    if removed {
+      info!(group = %group.name(), live = self.count(), "removed idle group");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec::Codec;
+
+  fn outbound() -> Arc<Outbound> {
+    Arc::new(Outbound::new(Vec::new(), Codec::Json))
+  }
+
+  fn table(idle_timeout: Duration) -> Arc<GroupTable> {
+    Arc::new(GroupTable::new(GroupSettings {
+      idle_timeout,
+      ..GroupSettings::default()
+    }, AccessTable::in_memory()))
+  }
+
+  fn names(table: &GroupTable) -> Vec<String> {
+    table.names().iter().map(|name| name.to_string()).collect()
+  }
+
+  async fn join(table: &Arc<GroupTable>, name: &str, outbound: &Arc<Outbound>)
+    -> Subscription
+  {
+    table.join(Arc::new(name.to_string()), Arc::new("tester".to_string()),
+               outbound.clone(), None)
+      .await
+      .unwrap()
+  }
+
+  #[test]
+  fn empty_groups_are_removed() {
+    task::block_on(async {
+      let table = table(Duration::ZERO);
+      let outbound = outbound();
+
+      let first = join(&table, "Dogs", &outbound).await;
+      let second = join(&table, "Dogs", &outbound).await;
+      let _cats = join(&table, "Cats", &outbound).await;
+      assert_eq!(names(&table), ["Cats", "Dogs"]);
+
+      first.cancel().await;
+      assert_eq!(names(&table), ["Cats", "Dogs"]);
+
+      second.cancel().await;
+      assert_eq!(names(&table), ["Cats"]);
+      assert_eq!(table.count(), 1);
+    });
+  }
+
+  #[test]
+  fn access_lists_go_with_groups_kept_in_memory() {
+    task::block_on(async {
+      let table = table(Duration::ZERO);
+      let (dogs, ann, bob) = (Arc::new("Dogs".to_string()),
+                              Arc::new("ann".to_string()),
+                              Arc::new("bob".to_string()));
+      let join = |nickname: &Arc<String>| {
+        table.join(dogs.clone(), nickname.clone(), outbound(), None)
+      };
+
+      join(&ann).await.unwrap().cancel().await;
+      assert!(names(&table).is_empty());
+
+      // Whoever recreates the group owns it now.
+      let _bob = join(&bob).await.unwrap();
+      table.set_role(&dogs, &bob, None, None).unwrap();
+      assert!(join(&ann).await.is_err());
+    });
+  }
+
+  #[test]
+  fn group_count_is_limited() {
+    task::block_on(async {
+      let table = Arc::new(GroupTable::new(GroupSettings {
+        idle_timeout: Duration::ZERO,
+        max_groups: Some(2),
+        ..GroupSettings::default()
+      }, AccessTable::in_memory()));
+      let outbound = outbound();
+      let tester = Arc::new("tester".to_string());
+      let join = |name: &str| {
+        table.join(Arc::new(name.to_string()), tester.clone(),
+                   outbound.clone(), None)
+      };
+
+      let dogs = join("Dogs").await.unwrap();
+      let _cats = join("Cats").await.unwrap();
+      assert!(join("Eels").await.is_err());
+
+      // Existing groups can still be joined, and removing one makes room.
+      join("Cats").await.unwrap().cancel().await;
+      dogs.cancel().await;
+      assert!(join("Eels").await.is_ok());
+    });
+  }
+
+  #[test]
+  fn idle_groups_are_removed_after_timeout() {
+    task::block_on(async {
+      let timeout = Duration::from_millis(100);
+      let table = table(timeout);
+      let outbound = outbound();
+
+      join(&table, "Dogs", &outbound).await.cancel().await;
+      assert_eq!(names(&table), ["Dogs"]);
+
+      // Rejoining resets the clock.
+      task::sleep(timeout / 2).await;
+      let dogs = join(&table, "Dogs", &outbound).await;
+      task::sleep(timeout).await;
+      assert_eq!(names(&table), ["Dogs"]);
+
+      dogs.cancel().await;
+      task::sleep(timeout * 2).await;
+      assert!(names(&table).is_empty());
+    });
+  }
+}
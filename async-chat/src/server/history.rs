@@ -0,0 +1,423 @@
+use async_std::channel;
+use async_std::task;
+use crate::server::search::SearchIndex;
+use crate::{FromServer, Replay, SearchResult};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// A single message as recorded in a group's history.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Entry {
+  pub timestamp: u64,
+  #[serde(default)]
+  pub sender: Arc<String>,
+  pub message: Arc<String>,
+  /// The message's ID; see `Federation::next_id`. Entries recorded before
+  /// messages had IDs have an empty one.
+  #[serde(default)]
+  pub id: Arc<String>,
+  /// The peer that relayed the message to us, or `None` if it was posted
+  /// here. A user elsewhere may share its sender's nickname, so only owners
+  /// may change relayed messages.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub relayed_from: Option<Arc<String>>,
+}
+
+impl Entry {
+  /// Return an entry for `message`, posted now by `sender`, with the ID
+  /// `id`.
+  pub fn new(id: Arc<String>, sender: Arc<String>, message: Arc<String>)
+    -> Entry
+  {
+    Entry { timestamp: unix_time(), sender, message, id, relayed_from: None }
+  }
+
+  /// Return the packet that delivers this entry to members of `group_name`.
+  pub fn to_packet(&self, group_name: &Arc<String>) -> FromServer {
+    FromServer::Message {
+      group_name: group_name.clone(),
+      sender: self.sender.clone(),
+      timestamp: self.timestamp,
+      message: self.message.clone(),
+      id: self.id.clone(),
+    }
+  }
+
+  pub fn to_search_result(&self) -> SearchResult {
+    SearchResult {
+      id: self.id.clone(),
+      sender: self.sender.clone(),
+      timestamp: self.timestamp,
+      message: self.message.clone(),
+    }
+  }
+}
+
+/// The most recent `limit` messages posted to a group, optionally backed by
+/// an append-only log file so that they survive a server restart.
+///
+/// The log file is allowed to grow to twice `limit` lines before it is
+/// rewritten to hold only the retained entries. The retained entries are
+/// also indexed for searching.
+///
+/// Groups change their history while holding a lock, so the file itself is
+/// written by a separate task, on a thread that may block. Changes reach it
+/// in order, but may not have reached the disk when a method returns; use
+/// `sync` to wait for them.
+pub struct History {
+  entries: VecDeque<Arc<Entry>>,
+  limit: usize,
+  log: Option<Log>,
+  index: SearchIndex,
+}
+
+/// Our end of the task writing a history's log file.
+struct Log {
+  changes: channel::Sender<Change>,
+  /// How many lines the file will hold once the queued writes are done.
+  lines: usize,
+}
+
+/// A change for `write_log` to make to a log file.
+enum Change {
+  Append(Arc<Entry>),
+  /// Replace the file's contents with these entries.
+  Rewrite(Vec<Arc<Entry>>),
+  /// Flush everything written so far to the disk, and report how it went.
+  Sync(channel::Sender<io::Result<()>>),
+}
+
+/// A history's log file, owned by the task that writes it.
+struct LogFile {
+  path: PathBuf,
+  file: File,
+}
+
+impl History {
+  /// Return an empty history that is kept in memory only.
+  pub fn in_memory(limit: usize) -> History {
+    History {
+      entries: VecDeque::new(),
+      limit,
+      log: None,
+      index: SearchIndex::new(),
+    }
+  }
+
+  /// Open the history of `group_name` stored in `directory`, loading any
+  /// entries a previous server left behind. This reads the file, so call it
+  /// where blocking is allowed.
+  pub fn open(directory: &Path, group_name: &str, limit: usize)
+    -> io::Result<History>
+  {
+    let path = directory.join(file_name(group_name));
+    let mut history = History::in_memory(limit);
+    let mut lines = 0;
+
+    match File::open(&path) {
+      Ok(file) => {
+        for line in BufReader::new(file).lines() {
+          lines += 1;
+          // A torn final line from a crash shouldn't make the whole
+          // history unreadable, so skip anything we can't parse.
+          if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+            history.push(Arc::new(entry));
+          }
+        }
+      }
+      Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+      Err(error) => return Err(error),
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let (changes, queued) = channel::unbounded();
+    task::spawn(write_log(LogFile { path, file }, queued));
+    history.log = Some(Log { changes, lines });
+    Ok(history)
+  }
+
+  /// Add `entry` to the history, and queue it to be written to the log
+  /// file. Failures to write are logged; the entry is retained in memory
+  /// regardless.
+  pub fn append(&mut self, entry: Arc<Entry>) {
+    self.push(entry.clone());
+
+    if let Some(log) = &mut self.log {
+      let _ = log.changes.try_send(Change::Append(entry));
+      log.lines += 1;
+
+      if log.lines >= 2 * self.limit.max(1) {
+        self.rewrite_log();
+      }
+    }
+  }
+
+  /// Return the retained entry with the ID `id`, if any.
+  pub fn get(&self, id: &str) -> Option<&Arc<Entry>> {
+    if id.is_empty() {
+      return None;
+    }
+    self.entries.iter().rev().find(|entry| *entry.id == id)
+  }
+
+  /// Put `entry` in place of the retained entry with the same ID. Since the
+  /// log file is append-only, this rewrites it.
+  pub fn replace(&mut self, entry: Arc<Entry>) {
+    if let Some(slot) = self.entries.iter_mut().find(|old| old.id == entry.id) {
+      *slot = entry.clone();
+      self.index.replace(entry);
+      self.rewrite_log();
+    }
+  }
+
+  /// Forget the entry with the ID `id`. Like `replace`, this rewrites the
+  /// log file.
+  pub fn remove(&mut self, id: &str) {
+    if let Some(index) = self.entries.iter().position(|old| *old.id == id) {
+      self.entries.remove(index);
+      self.index.remove(id);
+      self.rewrite_log();
+    }
+  }
+
+  /// Queue a rewrite of the log file to hold only the retained entries.
+  fn rewrite_log(&mut self) {
+    if let Some(log) = &mut self.log {
+      let entries = self.entries.iter().cloned().collect();
+      let _ = log.changes.try_send(Change::Rewrite(entries));
+      log.lines = self.entries.len();
+    }
+  }
+
+  /// Return the retained entries selected by `replay`, oldest first.
+  pub fn replay(&self, replay: Option<Replay>) -> Vec<Arc<Entry>> {
+    match replay {
+      None => vec![],
+      Some(Replay::LastN(n)) => {
+        let skip = self.entries.len().saturating_sub(n);
+        self.entries.iter().skip(skip).cloned().collect()
+      }
+      // Entries relayed from peers keep the time they were posted there, so
+      // the history isn't necessarily in timestamp order.
+      Some(Replay::Since(time)) => {
+        self.entries.iter()
+          .filter(|entry| entry.timestamp >= time)
+          .cloned()
+          .collect()
+      }
+    }
+  }
+
+  /// Return up to `limit` of the retained entries that contain every word
+  /// of `query`, newest first.
+  pub fn search(&self, query: &str, limit: usize) -> Vec<Arc<Entry>> {
+    self.index.search(query, limit)
+  }
+
+  /// Return a future that resolves once every change made so far has
+  /// reached the disk. The future doesn't borrow the history, so callers
+  /// can release their lock on it before waiting.
+  pub fn sync(&self) -> impl Future<Output = io::Result<()>> + 'static {
+    let reply = self.log.as_ref().map(|log| {
+      let (reply, synced) = channel::bounded(1);
+      let _ = log.changes.try_send(Change::Sync(reply));
+      synced
+    });
+    async move {
+      match reply {
+        Some(synced) => synced.recv().await.unwrap_or_else(|_| {
+          Err(io::Error::other("the history's log writer has stopped"))
+        }),
+        None => Ok(()),
+      }
+    }
+  }
+
+  fn push(&mut self, entry: Arc<Entry>) {
+    if self.entries.len() >= self.limit {
+      self.entries.pop_front();
+      self.index.remove_oldest();
+    }
+    if self.limit > 0 {
+      self.index.add(entry.clone());
+      self.entries.push_back(entry);
+    }
+  }
+}
+
+/// Make the changes queued on `changes` to `log`, until the history is
+/// dropped. Whatever has queued up while we were writing is done as one
+/// batch, on a thread where blocking is allowed.
+async fn write_log(mut log: LogFile, changes: channel::Receiver<Change>) {
+  while let Ok(change) = changes.recv().await {
+    let mut batch = vec![change];
+    while let Ok(change) = changes.try_recv() {
+      batch.push(change);
+    }
+    log = task::spawn_blocking(move || {
+      for change in batch {
+        log.apply(change);
+      }
+      log
+    }).await;
+  }
+}
+
+impl LogFile {
+  /// Make `change`. Failing to record a message isn't any
+  /// client's fault, so we just log it.
+  fn apply(&mut self, change: Change) {
+    let written = match change {
+      Change::Append(entry) => self.append(&entry),
+      Change::Rewrite(entries) => self.compact(&entries),
+      Change::Sync(reply) => {
+        let _ = reply.try_send(self.file.sync_data());
+        Ok(())
+      }
+    };
+    if let Err(error) = written {
+      error!(path = %self.path.display(), %error, "failed to write history");
+    }
+  }
+
+  fn append(&mut self, entry: &Entry) -> io::Result<()> {
+    let mut json = serde_json::to_string(entry)?;
+    json.push('\n');
+    self.file.write_all(json.as_bytes())
+  }
+
+  /// Rewrite the log file so that it holds only `entries`.
+  fn compact(&mut self, entries: &[Arc<Entry>]) -> io::Result<()> {
+    let temporary = self.path.with_extension("jsonl.tmp");
+    {
+      let mut file = io::BufWriter::new(File::create(&temporary)?);
+      for entry in entries {
+        serde_json::to_writer(&mut file, &**entry)?;
+        file.write_all(b"\n")?;
+      }
+      file.into_inner()?.sync_all()?;
+    }
+    fs::rename(&temporary, &self.path)?;
+
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    Ok(())
+  }
+}
+
+/// Return a file name for `group_name`'s log that is safe on any platform,
+/// escaping everything but ASCII letters, digits, `-` and `_`.
+fn file_name(group_name: &str) -> String {
+  let mut name = String::new();
+  for byte in group_name.bytes() {
+    if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+      name.push(byte as char);
+    } else {
+      name.push_str(&format!("%{:02X}", byte));
+    }
+  }
+  name.push_str(".jsonl");
+  name
+}
+
+/// Return the current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_secs())
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(message: String) -> Arc<Entry> {
+    Arc::new(Entry::new(Arc::new(message.clone()),
+                        Arc::new("tester".to_string()), Arc::new(message)))
+  }
+
+  fn messages(entries: &[Arc<Entry>]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.message.as_str()).collect()
+  }
+
+  #[test]
+  fn history_is_bounded_and_replays_last_n() {
+    let mut history = History::in_memory(3);
+    for i in 0..5 {
+      history.append(entry(format!("m{}", i)));
+    }
+
+    assert!(history.replay(None).is_empty());
+    assert_eq!(messages(&history.replay(Some(Replay::LastN(2)))),
+               ["m3", "m4"]);
+    assert_eq!(messages(&history.replay(Some(Replay::LastN(10)))),
+               ["m2", "m3", "m4"]);
+    assert_eq!(history.replay(Some(Replay::Since(u64::MAX))).len(), 0);
+    assert_eq!(history.replay(Some(Replay::Since(0))).len(), 3);
+  }
+
+  #[test]
+  fn replaying_since_a_time_allows_for_relayed_entries() {
+    let mut history = History::in_memory(10);
+    for (message, timestamp) in [("local", 100), ("relayed", 90),
+                                 ("late", 110), ("old", 50)] {
+      let mut entry = (*entry(message.to_string())).clone();
+      entry.timestamp = timestamp;
+      history.append(Arc::new(entry));
+    }
+
+    assert_eq!(messages(&history.replay(Some(Replay::Since(95)))),
+               ["local", "late"]);
+    assert_eq!(messages(&history.replay(Some(Replay::Since(90)))),
+               ["local", "relayed", "late"]);
+  }
+
+  #[test]
+  fn history_survives_reopening() {
+    let directory = tempfile::tempdir().unwrap();
+
+    {
+      let mut history = History::open(directory.path(), "Dogs/Cats", 2)
+        .unwrap();
+      for i in 0..7 {
+        history.append(entry(format!("m{}", i)));
+      }
+      task::block_on(history.sync()).unwrap();
+    }
+
+    let history = History::open(directory.path(), "Dogs/Cats", 2).unwrap();
+    assert_eq!(messages(&history.replay(Some(Replay::LastN(5)))),
+               ["m5", "m6"]);
+    assert!(directory.path().join("Dogs%2FCats.jsonl").exists());
+  }
+
+  #[test]
+  fn edits_and_removals_survive_reopening() {
+    let directory = tempfile::tempdir().unwrap();
+
+    {
+      let mut history = History::open(directory.path(), "Crabs", 10).unwrap();
+      for i in 0..3 {
+        history.append(entry(format!("m{}", i)));
+      }
+      let mut edited = (**history.get("m1").unwrap()).clone();
+      edited.message = Arc::new("m1, edited".to_string());
+      history.replace(Arc::new(edited));
+      history.remove("m2");
+      history.remove("m9");
+      assert!(history.get("m2").is_none());
+      task::block_on(history.sync()).unwrap();
+    }
+
+    let history = History::open(directory.path(), "Crabs", 10).unwrap();
+    assert_eq!(messages(&history.replay(Some(Replay::LastN(10)))),
+               ["m0", "m1, edited"]);
+  }
+}
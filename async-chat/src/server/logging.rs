@@ -0,0 +1,170 @@
+//! Structured logging to standard error, via `tracing`.
+//!
+//! Each connection is served inside a `connection` span carrying the peer's
+//! address and, once the client signs in, its nickname; each group
+//! membership adds a `member` span naming the group. Events logged while
+//! serving a client carry those fields, so a line about a failed send says
+//! whose it was. Output is plain text for people watching a terminal, or
+//! one JSON object per line for log pipelines.
+
+use serde::Deserialize;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How much to log, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+}
+
+/// How to write each log line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  /// Human-readable text.
+  #[default]
+  Text,
+  /// One JSON object per line, with span fields included.
+  Json,
+}
+
+/// Log events at `level` and below to standard error, in `format`. Call
+/// this once, before anything is logged; later calls have no effect.
+pub fn init(level: Level, format: Format) {
+  let subscriber = subscriber(level, format, io::stderr().is_terminal(),
+                              io::stderr);
+  let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+/// Return a subscriber that writes events at `level` and below to
+/// `writer`, in `format`. Text is colored only if `color` is true.
+pub fn subscriber<W>(level: Level, format: Format, color: bool, writer: W)
+  -> Box<dyn Subscriber + Send + Sync>
+where
+  W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+  let builder = tracing_subscriber::fmt()
+    .with_max_level(LevelFilter::from(level))
+    .with_ansi(color)
+    .with_writer(writer);
+  match format {
+    Format::Text => Box::new(builder.finish()),
+    Format::Json => Box::new(builder.json().with_current_span(false).finish()),
+  }
+}
+
+impl From<Level> for LevelFilter {
+  fn from(level: Level) -> LevelFilter {
+    match level {
+      Level::Error => LevelFilter::ERROR,
+      Level::Warn => LevelFilter::WARN,
+      Level::Info => LevelFilter::INFO,
+      Level::Debug => LevelFilter::DEBUG,
+    }
+  }
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+    })
+  }
+}
+
+impl FromStr for Level {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Level, String> {
+    match name {
+      "error" => Ok(Level::Error),
+      "warn" => Ok(Level::Warn),
+      "info" => Ok(Level::Info),
+      "debug" => Ok(Level::Debug),
+      _ => Err(format!("unknown log level '{}'", name)),
+    }
+  }
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Format, String> {
+    match name {
+      "text" => Ok(Format::Text),
+      "json" => Ok(Format::Json),
+      _ => Err(format!("unknown log format '{}'", name)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+  use tracing::{debug, info, info_span};
+
+  /// A `MakeWriter` that collects everything written into one buffer.
+  #[derive(Clone, Default)]
+  struct Captured(Arc<Mutex<Vec<u8>>>);
+
+  impl io::Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(bytes);
+      Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Captured {
+      self.clone()
+    }
+  }
+
+  #[test]
+  fn json_lines_carry_span_fields() {
+    let captured = Captured::default();
+    let subscriber = subscriber(Level::Info, Format::Json, false,
+                                captured.clone());
+    tracing::subscriber::with_default(subscriber, || {
+      let connection = info_span!("connection", peer = "127.0.0.1:5000",
+                                  user = tracing::field::Empty);
+      let _entered = connection.enter();
+      connection.record("user", "ferris");
+      info_span!("member", group = "Crabs").in_scope(|| {
+        info!(bytes = 12, "posted");
+        debug!("not logged at info");
+      });
+    });
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 1);
+
+    let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["fields"]["message"], "posted");
+    assert_eq!(line["fields"]["bytes"], 12);
+    assert_eq!(line["spans"][0]["name"], "connection");
+    assert_eq!(line["spans"][0]["peer"], "127.0.0.1:5000");
+    assert_eq!(line["spans"][0]["user"], "ferris");
+    assert_eq!(line["spans"][1]["group"], "Crabs");
+  }
+}
@@ -0,0 +1,133 @@
+//! Counters describing what the server has been doing, reported by the
+//! admin endpoint in Prometheus's text format.
+//!
+//! Like the log level, the counters are global, so that code anywhere in the
+//! server can bump them without having them passed in.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+  const fn new() -> Counter {
+    Counter(AtomicU64::new(0))
+  }
+
+  pub fn increment(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, n: u64) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+pub struct Metrics {
+  pub connections_accepted: Counter,
+  pub requests: Counter,
+  /// Requests answered with `Nack`.
+  pub rejected_requests: Counter,
+  /// Messages accepted for posting to a group. Prometheus's `rate` turns
+  /// this into posts per second.
+  pub posts: Counter,
+  /// Messages that group members missed by falling too far behind.
+  pub lagged_messages: Counter,
+  /// Packets we failed to send to a client, or gave up sending.
+  pub send_errors: Counter,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+  const fn new() -> Metrics {
+    Metrics {
+      connections_accepted: Counter::new(),
+      requests: Counter::new(),
+      rejected_requests: Counter::new(),
+      posts: Counter::new(),
+      lagged_messages: Counter::new(),
+      send_errors: Counter::new(),
+    }
+  }
+}
+
+/// Values that aren't counted as they change, but looked up when reported.
+pub struct Gauges {
+  pub connections: usize,
+  pub signed_in: usize,
+  pub groups: usize,
+}
+
+/// Return the counters in `metrics`, along with `gauges`, in Prometheus's
+/// text exposition format.
+pub fn render(metrics: &Metrics, gauges: &Gauges) -> String {
+  let counters = [
+    ("connections_accepted_total", "Connections accepted.",
+     &metrics.connections_accepted),
+    ("requests_total", "Requests received from clients.", &metrics.requests),
+    ("rejected_requests_total", "Requests answered with Nack.",
+     &metrics.rejected_requests),
+    ("posts_total", "Messages posted to groups.", &metrics.posts),
+    ("lagged_messages_total", "Messages dropped for slow group members.",
+     &metrics.lagged_messages),
+    ("send_errors_total", "Failures sending packets to clients.",
+     &metrics.send_errors),
+  ];
+  let gauges = [
+    ("connections", "Connections currently open.", gauges.connections),
+    ("signed_in", "Connections signed in as some user.", gauges.signed_in),
+    ("groups", "Groups currently live.", gauges.groups),
+  ];
+
+  let mut text = String::new();
+  for (name, help, counter) in counters {
+    write_metric(&mut text, name, help, "counter", counter.get());
+  }
+  for (name, help, value) in gauges {
+    write_metric(&mut text, name, help, "gauge", value as u64);
+  }
+  text
+}
+
+fn write_metric(text: &mut String, name: &str, help: &str, kind: &str,
+                value: u64) {
+  // Writing to a `String` can't fail.
+  let _ = writeln!(text, "# HELP async_chat_{} {}", name, help);
+  let _ = writeln!(text, "# TYPE async_chat_{} {}", name, kind);
+  let _ = writeln!(text, "async_chat_{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metrics_are_rendered_for_prometheus() {
+    let metrics = Metrics::new();
+    metrics.posts.increment();
+    metrics.lagged_messages.add(5);
+    let gauges = Gauges { connections: 3, signed_in: 2, groups: 1 };
+
+    let text = render(&metrics, &gauges);
+    let samples: Vec<&str> = text.lines()
+      .filter(|line| !line.starts_with('#'))
+      .collect();
+    assert_eq!(samples, [
+      "async_chat_connections_accepted_total 0",
+      "async_chat_requests_total 0",
+      "async_chat_rejected_requests_total 0",
+      "async_chat_posts_total 1",
+      "async_chat_lagged_messages_total 5",
+      "async_chat_send_errors_total 0",
+      "async_chat_connections 3",
+      "async_chat_signed_in 2",
+      "async_chat_groups 1",
+    ]);
+    assert!(text.contains("# TYPE async_chat_posts_total counter\n"));
+  }
+}
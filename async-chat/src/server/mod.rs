@@ -0,0 +1,186 @@
+//! The chat server, as a library.
+//!
+//! The `server` binary reads its configuration, builds a `Server`, and runs
+//! an `accept_loop` for each address it listens on; tests can do the same
+//! on an ephemeral port. Everything a connection needs is shared through
+//! the `Server`, so any number of servers can run in one process.
+
+pub mod access;
+pub mod accounts;
+pub mod admin;
+pub mod connection;
+pub mod connection_table;
+pub mod federation;
+pub mod group;
+pub mod group_table;
+pub mod history;
+pub mod logging;
+pub mod metrics;
+pub mod rate_limit;
+pub mod search;
+pub mod user_table;
+pub mod websocket;
+
+pub use connection::Server;
+
+use crate::utils::ChatResult;
+use async_std::channel;
+use async_std::io::{Read, Write};
+use async_std::net::TcpListener;
+use async_std::prelude::*;
+use async_std::task;
+use connection_table::ConnectionTable;
+use futures_rustls::TlsAcceptor;
+use metrics::METRICS;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// How long to spend saying goodbye to clients at shutdown before giving up
+/// on the ones that aren't reading.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again when we've run out of file
+/// descriptors or memory, to give other connections a chance to close.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Accept connections on `listener` until `stop` is closed, using TLS if
+/// `acceptor` is given, and expecting WebSocket clients if `websocket` is
+/// true.
+pub async fn accept_loop(listener: TcpListener,
+                         acceptor: Option<TlsAcceptor>,
+                         websocket: bool,
+                         server: Arc<Server>,
+                         stop: channel::Receiver<()>)
+  -> ChatResult<()>
+{
+  let mut new_connections = listener.incoming();
+  loop {
+    // Nothing is ever sent on `stop`; the caller closes it.
+    let accepted = new_connections.next();
+    let stopped = async {
+      let _ = stop.recv().await;
+      None
+    };
+    let socket = match accepted.race(stopped).await {
+      Some(Ok(socket)) => socket,
+      Some(Err(error)) => {
+        survive_accept_error(error).await?;
+        continue;
+      }
+      None => break,
+    };
+    METRICS.connections_accepted.increment();
+    // We often send two small packets in a row, like a message and the
+    // `Ack` for posting it; don't let Nagle's algorithm hold back the second.
+    if let Err(error) = socket.set_nodelay(true) {
+      debug!(%error, "unable to set TCP_NODELAY");
+    }
+    let peer = socket.peer_addr().ok();
+    // `user` is filled in when the client signs in.
+    let span = info_span!("connection",
+                          peer = peer.map(tracing::field::display),
+                          user = tracing::field::Empty);
+    let server = server.clone();
+    let acceptor = acceptor.clone();
+    task::spawn(async move {
+      debug!("accepted connection");
+      let result = match acceptor {
+        Some(acceptor) => match acceptor.accept(socket).await {
+          Ok(stream) => serve(stream, peer, websocket, server).await,
+          Err(error) => Err(error.into()),
+        },
+        None => serve(socket, peer, websocket, server).await,
+      };
+      match result {
+        Ok(()) => info!("disconnected"),
+        Err(error) => warn!(%error, "disconnected with an error"),
+      }
+    }.instrument(span));
+  }
+  Ok(())
+}
+
+/// Deal with `error`, from accepting a connection. If it concerns only that
+/// connection, or a shortage that may pass, log it and return `Ok`, waiting
+/// a moment in the latter case; otherwise, return it, since accepting again
+/// would just fail again.
+pub(crate) async fn survive_accept_error(error: io::Error) -> io::Result<()> {
+  use io::ErrorKind::*;
+  // Out of file descriptors, for this process (`EMFILE`) or the whole
+  // system (`ENFILE`). The standard library gives these no `ErrorKind`.
+  const ENFILE: i32 = 23;
+  const EMFILE: i32 = 24;
+
+  let exhausted = error.kind() == OutOfMemory
+    || cfg!(unix) && matches!(error.raw_os_error(), Some(ENFILE | EMFILE));
+  match error.kind() {
+    ConnectionAborted | ConnectionReset | Interrupted | WouldBlock
+      | TimedOut => {
+      debug!(%error, "a connection failed before we could accept it");
+      Ok(())
+    }
+    _ if exhausted => {
+      warn!(%error, "unable to accept connections; retrying shortly");
+      task::sleep(ACCEPT_RETRY_DELAY).await;
+      Ok(())
+    }
+    _ => Err(error),
+  }
+}
+
+async fn serve<S>(socket: S,
+                  peer: Option<SocketAddr>,
+                  websocket: bool,
+                  server: Arc<Server>)
+  -> ChatResult<()>
+where
+  S: Read + Write + Send + Unpin + 'static,
+{
+  if websocket {
+    websocket::serve(socket, peer, server).await
+  } else {
+    connection::serve(socket, peer, server).await
+  }
+}
+
+/// Send every client a `Shutdown` packet and close its connection, waiting
+/// at most `SHUTDOWN_DEADLINE` for them all to finish.
+pub async fn shut_down(connections: &ConnectionTable) {
+  let goodbyes: Vec<_> = connections.outbounds()
+    .into_iter()
+    .map(|outbound| task::spawn(async move { outbound.shut_down().await }))
+    .collect();
+  let finished = async {
+    for goodbye in goodbyes {
+      goodbye.await;
+    }
+    connections.emptied().await;
+  };
+
+  if finished.timeout(SHUTDOWN_DEADLINE).await.is_err() {
+    warn!("gave up on {} connections that did not close in time",
+          connections.outbounds().len());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_fatal_accept_errors_stop_the_loop() {
+    task::block_on(async {
+      let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+      assert!(survive_accept_error(aborted).await.is_ok());
+      if cfg!(unix) {
+        let exhausted = io::Error::from_raw_os_error(24);
+        assert!(survive_accept_error(exhausted).await.is_ok());
+      }
+      let fatal = io::Error::from(io::ErrorKind::InvalidInput);
+      assert!(survive_accept_error(fatal).await.is_err());
+    });
+  }
+}
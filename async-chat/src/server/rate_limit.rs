@@ -0,0 +1,90 @@
+use serde::Deserialize;
+use std::time::Instant;
+
+/// A sustained rate of requests, allowing short bursts above it.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+  pub per_second: f64,
+  pub burst: u32,
+}
+
+/// The flood protection and size limits the server applies.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+  /// How fast a single connection may send requests of any kind.
+  pub connection: Option<Rate>,
+  /// How many throttled requests in a row a connection may send before we
+  /// hang up on it.
+  pub max_strikes: u32,
+  /// The longest message a client may post or send, in bytes.
+  pub max_message_size: usize,
+  /// The longest frame a client may send, in bytes. A client that sends a
+  /// longer one is disconnected.
+  pub max_frame_size: usize,
+  /// The longest group name a client may use, in characters.
+  pub max_group_name_length: usize,
+}
+
+/// A classic token bucket: holds up to `burst` tokens, refilled at
+/// `per_second`, and each request spends one.
+pub struct TokenBucket {
+  rate: Rate,
+  tokens: f64,
+  refilled: Instant,
+}
+
+impl TokenBucket {
+  /// Return a full bucket.
+  pub fn new(rate: Rate) -> TokenBucket {
+    TokenBucket { rate, tokens: rate.burst as f64, refilled: Instant::now() }
+  }
+
+  /// Spend a token if one is available, and say whether we did.
+  pub fn try_take(&mut self) -> bool {
+    self.try_take_at(Instant::now())
+  }
+
+  fn try_take_at(&mut self, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.refilled);
+    self.refilled = now;
+    self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate.per_second)
+      .min(self.rate.burst as f64);
+
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn bucket_allows_burst_then_refills() {
+    let mut bucket = TokenBucket::new(Rate { per_second: 2.0, burst: 3 });
+    let start = bucket.refilled;
+
+    assert!(bucket.try_take_at(start));
+    assert!(bucket.try_take_at(start));
+    assert!(bucket.try_take_at(start));
+    assert!(!bucket.try_take_at(start));
+
+    // Half a second at two per second buys exactly one more.
+    let later = start + Duration::from_millis(500);
+    assert!(bucket.try_take_at(later));
+    assert!(!bucket.try_take_at(later));
+
+    // A long pause refills only up to the burst size.
+    let much_later = later + Duration::from_secs(60);
+    for _ in 0..3 {
+      assert!(bucket.try_take_at(much_later));
+    }
+    assert!(!bucket.try_take_at(much_later));
+  }
+}
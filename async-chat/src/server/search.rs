@@ -0,0 +1,166 @@
+//! Finding messages in a group's history by the words they contain.
+//!
+//! Each group's `History` keeps a `SearchIndex` of the entries it retains,
+//! updating it as messages are posted, edited, deleted, or age out. The
+//! index maps each word to the entries containing it, so a search only
+//! looks at entries containing its rarest word, rather than reading the
+//! whole history.
+//!
+//! Words are runs of letters and digits, compared without regard to case.
+//! A message matches a query if it contains every word of the query.
+
+use crate::server::history::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// The most results we'll return for one search, whatever the client asks.
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+/// An inverted index over a group's retained history.
+#[derive(Default)]
+pub struct SearchIndex {
+  /// Every indexed entry, by the sequence number we gave it. Later entries
+  /// get higher numbers, so this is oldest first.
+  entries: BTreeMap<u64, Arc<Entry>>,
+  /// The sequence numbers of the entries containing each word.
+  postings: HashMap<String, BTreeSet<u64>>,
+  /// The sequence numbers of entries with IDs, so that edits and deletions
+  /// can find them. Entries recorded before messages had IDs aren't here.
+  ids: HashMap<String, u64>,
+  next: u64,
+}
+
+impl SearchIndex {
+  pub fn new() -> SearchIndex {
+    SearchIndex::default()
+  }
+
+  /// Index `entry`, which is newer than every entry indexed so far.
+  pub fn add(&mut self, entry: Arc<Entry>) {
+    let sequence = self.next;
+    self.next += 1;
+    self.insert(sequence, entry);
+  }
+
+  /// Put `entry` in place of the indexed entry with the same ID, keeping its
+  /// place in the order.
+  pub fn replace(&mut self, entry: Arc<Entry>) {
+    if let Some(&sequence) = self.ids.get(entry.id.as_str()) {
+      self.unindex(sequence);
+      self.insert(sequence, entry);
+    }
+  }
+
+  /// Forget the entry with the ID `id`.
+  pub fn remove(&mut self, id: &str) {
+    if id.is_empty() {
+      return;
+    }
+    if let Some(sequence) = self.ids.remove(id) {
+      self.unindex(sequence);
+    }
+  }
+
+  /// Forget the oldest entry, which has just aged out of the history.
+  pub fn remove_oldest(&mut self) {
+    if let Some((&sequence, _)) = self.entries.first_key_value() {
+      if let Some(entry) = self.unindex(sequence) {
+        self.ids.remove(entry.id.as_str());
+      }
+    }
+  }
+
+  /// Return up to `limit` of the entries containing every word of `query`,
+  /// newest first. A query with no words matches nothing.
+  pub fn search(&self, query: &str, limit: usize) -> Vec<Arc<Entry>> {
+    let mut sets = vec![];
+    for word in words(query) {
+      match self.postings.get(&word) {
+        Some(set) => sets.push(set),
+        None => return vec![],
+      }
+    }
+    // Walk the smallest set, checking the others for each candidate.
+    sets.sort_by_key(|set| set.len());
+    let (rarest, others) = match sets.split_first() {
+      Some(split) => split,
+      None => return vec![],
+    };
+    rarest.iter()
+      .rev()
+      .filter(|sequence| others.iter().all(|set| set.contains(sequence)))
+      .take(limit)
+      .map(|sequence| self.entries[sequence].clone())
+      .collect()
+  }
+
+  fn insert(&mut self, sequence: u64, entry: Arc<Entry>) {
+    for word in words(&entry.message) {
+      self.postings.entry(word).or_default().insert(sequence);
+    }
+    if !entry.id.is_empty() {
+      self.ids.insert(entry.id.to_string(), sequence);
+    }
+    self.entries.insert(sequence, entry);
+  }
+
+  /// Remove the entry numbered `sequence` from `entries` and `postings`,
+  /// and return it.
+  fn unindex(&mut self, sequence: u64) -> Option<Arc<Entry>> {
+    let entry = self.entries.remove(&sequence)?;
+    for word in words(&entry.message) {
+      if let Some(set) = self.postings.get_mut(&word) {
+        set.remove(&sequence);
+        if set.is_empty() {
+          self.postings.remove(&word);
+        }
+      }
+    }
+    Some(entry)
+  }
+}
+
+/// Return the distinct words of `text`, in lower case.
+fn words(text: &str) -> BTreeSet<String> {
+  text.split(|ch: char| !ch.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(|word| word.to_lowercase())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(id: &str, message: &str) -> Arc<Entry> {
+    Arc::new(Entry::new(Arc::new(id.to_string()),
+                        Arc::new("tester".to_string()),
+                        Arc::new(message.to_string())))
+  }
+
+  fn ids(entries: &[Arc<Entry>]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.id.as_str()).collect()
+  }
+
+  #[test]
+  fn searches_match_every_word_newest_first() {
+    let mut index = SearchIndex::new();
+    index.add(entry("1", "The crab walked sideways."));
+    index.add(entry("2", "Crabs, unlike the crab, are plural"));
+    index.add(entry("3", "a CRAB in a bucket"));
+    index.add(entry("4", "no crustaceans here"));
+
+    assert_eq!(ids(&index.search("crab", 10)), ["3", "2", "1"]);
+    assert_eq!(ids(&index.search("Crab the", 10)), ["2", "1"]);
+    assert_eq!(ids(&index.search("crab", 2)), ["3", "2"]);
+    assert!(index.search("crab lobster", 10).is_empty());
+    assert!(index.search(" ?! ", 10).is_empty());
+
+    index.replace(entry("3", "a lobster in a bucket"));
+    index.remove("1");
+    index.remove_oldest();
+    assert_eq!(ids(&index.search("crab", 10)), Vec::<&str>::new());
+    assert_eq!(ids(&index.search("bucket", 10)), ["3"]);
+    assert_eq!(index.postings.get("crab"), None);
+  }
+}
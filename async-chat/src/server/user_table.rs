@@ -0,0 +1,201 @@
+use crate::server::connection::Outbound;
+use crate::FromServer;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// How many offline users the table remembers by default.
+pub const REMEMBERED_USERS: usize = 10_000;
+
+/// Everyone online, the users who have most recently gone offline, and the
+/// direct messages waiting for the latter.
+pub struct UserTable {
+  users: Mutex<Users>,
+  /// How many direct messages to hold for each offline user. Zero disables
+  /// offline delivery altogether.
+  queue_limit: usize,
+  /// How many offline users to remember. Once more than this have signed
+  /// out, we forget the one who left longest ago, and their queue.
+  remembered: usize,
+}
+
+#[derive(Default)]
+struct Users {
+  online: HashMap<Arc<String>, Arc<Outbound>>,
+  /// The users who have signed out, each with the number of their latest
+  /// departure. We only queue messages for these, so that typos don't
+  /// accumulate queues forever.
+  offline: HashMap<Arc<String>, u64>,
+  /// Departures, oldest first, some superseded by a later departure of the
+  /// same user, or by their return.
+  departures: VecDeque<(u64, Arc<String>)>,
+  next_departure: u64,
+  queued: HashMap<Arc<String>, VecDeque<FromServer>>,
+}
+
+/// What `UserTable::deliver` did with a direct message.
+pub enum Delivery {
+  /// The recipient is online; the caller should send the packet to them.
+  Now(Arc<Outbound>, FromServer),
+  /// The recipient is offline, and will get the packet when they return.
+  Queued,
+}
+
+impl UserTable {
+  pub fn new(queue_limit: usize) -> UserTable {
+    UserTable {
+      users: Mutex::new(Users::default()),
+      queue_limit,
+      remembered: REMEMBERED_USERS,
+    }
+  }
+
+  /// Record `nickname` as online at `outbound`, and return any direct
+  /// messages queued for them while they were away.
+  pub fn sign_in(&self, nickname: Arc<String>, outbound: Arc<Outbound>)
+    -> Result<Vec<FromServer>, String>
+  {
+    let mut users = self.users.lock().unwrap();
+    if users.online.contains_key(&nickname) {
+      return Err(format!("Nickname '{}' is already in use", nickname));
+    }
+
+    users.offline.remove(&nickname);
+    let queued = users.queued.remove(&nickname).unwrap_or_default();
+    users.online.insert(nickname, outbound);
+    Ok(queued.into())
+  }
+
+  /// Record that `nickname`, signed in at `outbound`, has gone offline.
+  pub fn sign_out(&self, nickname: &Arc<String>, outbound: &Arc<Outbound>) {
+    let mut users = self.users.lock().unwrap();
+    if users.online.get(nickname).is_some_and(|current| Arc::ptr_eq(current, outbound)) {
+      users.online.remove(nickname);
+
+      let departure = users.next_departure;
+      users.next_departure += 1;
+      users.offline.insert(nickname.clone(), departure);
+      users.departures.push_back((departure, nickname.clone()));
+      while users.departures.len() > self.remembered {
+        let (departure, nickname) = users.departures.pop_front().unwrap();
+        if users.offline.get(&nickname) == Some(&departure) {
+          users.offline.remove(&nickname);
+          users.queued.remove(&nickname);
+        }
+      }
+    }
+  }
+
+  /// Route `packet`, a `FromServer::DirectMessage`, to the user called `to`.
+  pub fn deliver(&self, to: &Arc<String>, packet: FromServer)
+    -> Result<Delivery, String>
+  {
+    let mut users = self.users.lock().unwrap();
+    if let Some(outbound) = users.online.get(to) {
+      return Ok(Delivery::Now(outbound.clone(), packet));
+    }
+
+    if !users.offline.contains_key(to) {
+      return Err(format!("No user named '{}' is online", to));
+    }
+    if self.queue_limit == 0 {
+      return Err(format!("'{}' is offline", to));
+    }
+
+    let queue = users.queued.entry(to.clone()).or_default();
+    if queue.len() >= self.queue_limit {
+      return Err(format!("'{}' is offline, and has too many messages waiting",
+                         to));
+    }
+    queue.push_back(packet);
+    Ok(Delivery::Queued)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec::Codec;
+
+  fn outbound() -> Arc<Outbound> {
+    Arc::new(Outbound::new(Vec::new(), Codec::Json))
+  }
+
+  fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+  }
+
+  fn direct(message: &str) -> FromServer {
+    FromServer::DirectMessage {
+      from: name("ann"),
+      timestamp: 0,
+      message: name(message),
+    }
+  }
+
+  #[test]
+  fn nicknames_are_unique_while_online() {
+    let users = UserTable::new(10);
+    let first = outbound();
+    assert!(users.sign_in(name("bob"), first.clone()).is_ok());
+    assert!(users.sign_in(name("bob"), outbound()).is_err());
+
+    // Signing out a stale connection doesn't evict the current one.
+    users.sign_out(&name("bob"), &outbound());
+    assert!(users.sign_in(name("bob"), outbound()).is_err());
+
+    users.sign_out(&name("bob"), &first);
+    assert!(users.sign_in(name("bob"), outbound()).is_ok());
+  }
+
+  #[test]
+  fn messages_for_offline_users_are_queued() {
+    let users = UserTable::new(2);
+    assert!(users.deliver(&name("bob"), direct("hi")).is_err());
+
+    let bob = outbound();
+    users.sign_in(name("bob"), bob.clone()).unwrap();
+    assert!(matches!(users.deliver(&name("bob"), direct("now")),
+                     Ok(Delivery::Now(..))));
+    users.sign_out(&name("bob"), &bob);
+
+    assert!(matches!(users.deliver(&name("bob"), direct("one")),
+                     Ok(Delivery::Queued)));
+    assert!(matches!(users.deliver(&name("bob"), direct("two")),
+                     Ok(Delivery::Queued)));
+    assert!(users.deliver(&name("bob"), direct("three")).is_err());
+
+    let queued = users.sign_in(name("bob"), outbound()).unwrap();
+    assert_eq!(queued, [direct("one"), direct("two")]);
+  }
+
+  #[test]
+  fn only_recent_departures_are_remembered() {
+    let users = UserTable { remembered: 2, ..UserTable::new(10) };
+    let visit = |nickname: &str| {
+      let outbound = outbound();
+      users.sign_in(name(nickname), outbound.clone()).unwrap();
+      users.sign_out(&name(nickname), &outbound);
+    };
+
+    visit("ann");
+    visit("bob");
+    assert!(users.deliver(&name("ann"), direct("hi")).is_ok());
+    visit("ann");
+    visit("cat");
+
+    // Bob left longest ago, so the table forgets them, and their queue.
+    assert!(users.deliver(&name("bob"), direct("hi")).is_err());
+    assert!(users.deliver(&name("ann"), direct("hi")).is_ok());
+    assert!(users.deliver(&name("cat"), direct("hi")).is_ok());
+    assert!(users.users.lock().unwrap().departures.len() <= 2);
+  }
+
+  #[test]
+  fn queueing_can_be_disabled() {
+    let users = UserTable::new(0);
+    let bob = outbound();
+    users.sign_in(name("bob"), bob.clone()).unwrap();
+    users.sign_out(&name("bob"), &bob);
+    assert!(users.deliver(&name("bob"), direct("hi")).is_err());
+  }
+}
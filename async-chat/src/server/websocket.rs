@@ -0,0 +1,244 @@
+//! Serving clients over WebSocket, for the benefit of browsers.
+//!
+//! Each text message from the client holds one `Request` packet as JSON,
+//! and each `FromServer` packet goes back the same way. Apart from that, a
+//! WebSocket client is served exactly like any other: it shares the same
+//! groups and users, so it can chat with clients connected over plain TCP.
+
+use crate::{FromServer, Request};
+use crate::utils::ChatResult;
+use async_std::io::{Read, Write};
+use async_std::sync::Arc;
+use async_tungstenite::tungstenite::{self, Message};
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use futures_util::StreamExt;
+use std::net::SocketAddr;
+
+use crate::server::connection::{self, Outbound, Server};
+
+/// Serve a client at `peer` connected via `socket`, which may be a plain
+/// `TcpStream` or a TLS stream wrapping one, once it has completed the
+/// WebSocket handshake.
+pub async fn serve<S>(socket: S, peer: Option<SocketAddr>, server: Arc<Server>)
+  -> ChatResult<()>
+where
+  S: Read + Write + Send + Unpin + 'static,
+{
+  // tungstenite refuses longer messages itself, and `decode` passes the
+  // error along, so the client hears why it's being disconnected.
+  let max_size = server.limits.max_frame_size;
+  let config = WebSocketConfig::default()
+    .max_message_size(Some(max_size))
+    .max_frame_size(Some(max_size));
+  let websocket = async_tungstenite::accept_async_with_config(socket,
+                                                              Some(config))
+    .await?;
+  let (to_client, from_client) = websocket.split();
+
+  // `decode` leaves out control messages, which tungstenite answers for us.
+  let requests = futures_lite::StreamExt::filter_map(from_client, decode);
+  let outbound = Arc::new(Outbound::websocket(to_client));
+  connection::serve_requests(requests, outbound, peer, &server).await
+}
+
+/// Return `packet` as a WebSocket message.
+pub fn encode(packet: &FromServer) -> ChatResult<Message> {
+  Ok(Message::text(serde_json::to_string(packet)?))
+}
+
+fn decode(message: tungstenite::Result<Message>)
+  -> Option<ChatResult<Request>>
+{
+  match message {
+    Ok(Message::Text(text)) => {
+      Some(serde_json::from_str(&text).map_err(Into::into))
+    }
+    Ok(Message::Binary(_)) => {
+      Some(Err("binary WebSocket messages are not supported".into()))
+    }
+    Ok(_) => None,
+    Err(error) => Some(Err(error.into())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::access::AccessTable;
+  use crate::server::connection_table::ConnectionTable;
+  use crate::server::federation::Federation;
+  use crate::server::group_table::{GroupSettings, GroupTable};
+  use crate::server::rate_limit::Limits;
+  use crate::server::user_table::UserTable;
+  use crate::FromClient;
+  use crate::codec::Codec;
+  use async_std::io::BufReader;
+  use async_std::net::{TcpListener, TcpStream};
+  use async_std::prelude::FutureExt;
+  use async_std::task;
+  use std::time::Duration;
+
+  fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+  }
+
+  /// Start a server that accepts plain clients on one port and WebSocket
+  /// clients on another, and return their addresses.
+  async fn start_server() -> (String, String) {
+    let server = Arc::new(Server {
+      groups: Arc::new(GroupTable::new(GroupSettings::default(),
+                                       AccessTable::in_memory())),
+      users: UserTable::new(0),
+      connections: Arc::new(ConnectionTable::new()),
+      limits: Limits {
+        connection: None,
+        max_strikes: 0,
+        max_message_size: 100,
+        max_frame_size: 1000,
+        max_group_name_length: 10,
+      },
+      accounts: None,
+      federation: Arc::new(Federation::new("test", None)),
+    });
+
+    let plain = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let websocket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addresses = (plain.local_addr().unwrap().to_string(),
+                     websocket.local_addr().unwrap().to_string());
+
+    let plain_server = server.clone();
+    task::spawn(async move {
+      let (socket, peer) = plain.accept().await.unwrap();
+      let _ = connection::serve(socket, Some(peer), plain_server).await;
+    });
+    task::spawn(async move {
+      let (socket, peer) = websocket.accept().await.unwrap();
+      let _ = serve(socket, Some(peer), server).await;
+    });
+    addresses
+  }
+
+  #[test]
+  fn websocket_and_plain_clients_share_groups() {
+    task::block_on(async {
+      let (plain_address, websocket_address) = start_server().await;
+
+      let socket = TcpStream::connect(&websocket_address).await.unwrap();
+      let url = format!("ws://{}/", websocket_address);
+      let (mut browser, _) = async_tungstenite::client_async(url, socket)
+        .await
+        .unwrap();
+
+      let socket = TcpStream::connect(&plain_address).await.unwrap();
+      let replies = Codec::Json.packets(BufReader::new(socket.clone()));
+      let mut terminal_replies = Box::pin(replies);
+      let mut terminal = socket;
+
+      let request = |id, body| Request { id, body };
+      let send_from_browser = |packet: Request| {
+        Message::text(serde_json::to_string(&packet).unwrap())
+      };
+      browser.send(send_from_browser(request(1, FromClient::Hello {
+        nickname: name("web"),
+      }))).await.unwrap();
+      browser.send(send_from_browser(request(2, FromClient::Join {
+        group_name: name("Crabs"),
+        replay: None,
+      }))).await.unwrap();
+
+      for packet in [
+        request(1, FromClient::Hello { nickname: name("tty") }),
+        request(2, FromClient::Join { group_name: name("Crabs"), replay: None }),
+      ] {
+        Codec::Json.send(&mut terminal, &packet).await.unwrap();
+      }
+      task::sleep(Duration::from_millis(100)).await;
+
+      let post = |message: &str| request(3, FromClient::Post {
+        group_name: name("Crabs"),
+        message: name(message),
+      });
+      Codec::Json.send(&mut terminal, &post("from tty")).await.unwrap();
+      task::sleep(Duration::from_millis(100)).await;
+      browser.send(send_from_browser(post("from web"))).await.unwrap();
+
+      let mut browser_received = vec![];
+      while browser_received.len() < 2 {
+        let message = browser.next()
+          .timeout(Duration::from_secs(5))
+          .await
+          .unwrap()
+          .unwrap()
+          .unwrap();
+        if let Message::Text(text) = message {
+          match serde_json::from_str(&text).unwrap() {
+            FromServer::Ack { .. } => {}
+            packet if packet.is_presence() => {}
+            packet => browser_received.push(packet),
+          }
+        }
+      }
+
+      let mut terminal_received: Vec<FromServer> = vec![];
+      while terminal_received.len() < 2 {
+        let packet = terminal_replies.next()
+          .timeout(Duration::from_secs(5))
+          .await
+          .unwrap()
+          .unwrap()
+          .unwrap();
+        match packet {
+          FromServer::Ack { .. } => {}
+          packet if packet.is_presence() => {}
+          packet => terminal_received.push(packet),
+        }
+      }
+
+      let messages = |packets: Vec<FromServer>| -> Vec<(String, String)> {
+        packets.into_iter()
+          .map(|packet| match packet {
+            FromServer::Message { sender, message, .. } => {
+              (sender.to_string(), message.to_string())
+            }
+            other => panic!("unexpected packet {:?}", other),
+          })
+          .collect()
+      };
+      let expected = [("tty".to_string(), "from tty".to_string()),
+                      ("web".to_string(), "from web".to_string())];
+      assert_eq!(messages(browser_received), expected);
+      assert_eq!(messages(terminal_received), expected);
+    });
+  }
+
+  #[test]
+  fn oversized_websocket_messages_end_the_connection() {
+    task::block_on(async {
+      let (_, websocket_address) = start_server().await;
+      let socket = TcpStream::connect(&websocket_address).await.unwrap();
+      let url = format!("ws://{}/", websocket_address);
+      let (mut browser, _) = async_tungstenite::client_async(url, socket)
+        .await
+        .unwrap();
+
+      browser.send(Message::text("x".repeat(2000))).await.unwrap();
+
+      let mut received = vec![];
+      while let Some(Ok(message)) = browser.next()
+        .timeout(Duration::from_secs(5))
+        .await
+        .unwrap()
+      {
+        if let Message::Text(text) = message {
+          received.push(serde_json::from_str::<FromServer>(&text).unwrap());
+        }
+      }
+      match &received[..] {
+        [FromServer::Error { id: None, message }] => {
+          assert!(message.contains("disconnecting"), "{}", message);
+        }
+        other => panic!("unexpected packets {:?}", other),
+      }
+    });
+  }
+}
@@ -0,0 +1,179 @@
+//! Helpers for carrying the chat protocol over TLS.
+//!
+//! The server and client load their certificates and keys from PEM files.
+//! The resulting `TlsStream`s implement the same `async_std::io` traits as
+//! `TcpStream`, so `utils::send_as_json` and `utils::receive_as_json` work
+//! on either.
+
+use crate::utils::ChatResult;
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use futures_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Return an acceptor for server connections that presents the certificate
+/// chain in `certificate_path`, signed with the key in `key_path`.
+pub fn acceptor(certificate_path: &Path, key_path: &Path)
+  -> ChatResult<TlsAcceptor>
+{
+  let certificates = load_certificates(certificate_path)?;
+  let key = load_private_key(key_path)?;
+  let config = ServerConfig::builder()
+    .with_no_client_auth()
+    .with_single_cert(certificates, key)?;
+  Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Return a connector for client connections that trusts only the
+/// certificate authorities in `ca_path`.
+pub fn connector(ca_path: &Path) -> ChatResult<TlsConnector> {
+  let mut roots = RootCertStore::empty();
+  for certificate in load_certificates(ca_path)? {
+    roots.add(certificate)?;
+  }
+  let config = ClientConfig::builder()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+  Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Return the name to verify the server's certificate against when
+/// connecting to `address`, a `HOST:PORT` string.
+pub fn server_name(address: &str) -> ChatResult<ServerName<'static>> {
+  let host = match address.rsplit_once(':') {
+    Some((host, _port)) => host,
+    None => address,
+  };
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  Ok(ServerName::try_from(host.to_string())?)
+}
+
+fn load_certificates(path: &Path) -> ChatResult<Vec<CertificateDer<'static>>> {
+  let mut reader = BufReader::new(File::open(path)?);
+  let certificates = rustls_pemfile::certs(&mut reader)
+    .collect::<Result<Vec<_>, _>>()?;
+  if certificates.is_empty() {
+    return Err(format!("no certificates found in {}", path.display()).into());
+  }
+  Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> ChatResult<PrivateKeyDer<'static>> {
+  let mut reader = BufReader::new(File::open(path)?);
+  match rustls_pemfile::private_key(&mut reader)? {
+    Some(key) => Ok(key),
+    None => Err(format!("no private key found in {}", path.display()).into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils;
+  use crate::{FromClient, FromServer};
+  use async_std::io::BufReader;
+  use async_std::net::{TcpListener, TcpStream};
+  use async_std::prelude::*;
+  use async_std::task;
+  use std::path::PathBuf;
+
+  /// Write a fresh self-signed certificate for `localhost` and its key
+  /// into `directory`, and return their paths.
+  fn self_signed(directory: &Path) -> (PathBuf, PathBuf) {
+    let generated = rcgen::generate_simple_self_signed(
+      vec!["localhost".to_string()]
+    ).unwrap();
+    let certificate_path = directory.join("cert.pem");
+    let key_path = directory.join("key.pem");
+    std::fs::write(&certificate_path, generated.cert.pem()).unwrap();
+    std::fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+    (certificate_path, key_path)
+  }
+
+  #[test]
+  fn test_json_over_tls() {
+    let directory = tempfile::tempdir().unwrap();
+    let (certificate_path, key_path) = self_signed(directory.path());
+    let acceptor = acceptor(&certificate_path, &key_path).unwrap();
+    let connector = connector(&certificate_path).unwrap();
+
+    task::block_on(async {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+
+      let server = task::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(socket).await.unwrap();
+        let (reader, mut writer) = futures_lite::io::split(stream);
+
+        let mut requests = utils::receive_as_json(BufReader::new(reader));
+        let request: FromClient = requests.next().await.unwrap().unwrap();
+        let reply = FromServer::Error {
+          id: None,
+          message: format!("{:?}", request),
+        };
+        utils::send_as_json(&mut writer, &reply).await.unwrap();
+        writer.flush().await.unwrap();
+      });
+
+      let socket = TcpStream::connect(address).await.unwrap();
+      let name = server_name(&format!("localhost:{}", address.port()))
+        .unwrap();
+      let stream = connector.connect(name, socket).await.unwrap();
+      let (reader, mut writer) = futures_lite::io::split(stream);
+
+      let request = FromClient::ListGroups;
+      utils::send_as_json(&mut writer, &request).await.unwrap();
+      writer.flush().await.unwrap();
+
+      let mut replies = utils::receive_as_json(BufReader::new(reader));
+      let reply: FromServer = replies.next().await.unwrap().unwrap();
+      assert_eq!(reply, FromServer::Error {
+        id: None,
+        message: "ListGroups".to_string(),
+      });
+
+      server.await;
+    });
+  }
+
+  #[test]
+  fn test_untrusted_certificate_is_rejected() {
+    let server_directory = tempfile::tempdir().unwrap();
+    let (certificate_path, key_path) = self_signed(server_directory.path());
+    let acceptor = acceptor(&certificate_path, &key_path).unwrap();
+
+    let other_directory = tempfile::tempdir().unwrap();
+    let (other_certificate_path, _) = self_signed(other_directory.path());
+    let connector = connector(&other_certificate_path).unwrap();
+
+    task::block_on(async {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+
+      let server = task::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        assert!(acceptor.accept(socket).await.is_err());
+      });
+
+      let socket = TcpStream::connect(address).await.unwrap();
+      let name = server_name("localhost").unwrap();
+      assert!(connector.connect(name, socket).await.is_err());
+
+      server.await;
+    });
+  }
+
+  #[test]
+  fn test_server_name() {
+    assert_eq!(server_name("example.com:8088").unwrap(),
+               ServerName::try_from("example.com").unwrap());
+    assert_eq!(server_name("[::1]:8088").unwrap(),
+               ServerName::try_from("::1").unwrap());
+    assert_eq!(server_name("127.0.0.1:8088").unwrap(),
+               ServerName::try_from("127.0.0.1").unwrap());
+  }
+}
@@ -3,6 +3,7 @@ use async_std::prelude::*;
 use serde::Serialize;
 use std::marker::Unpin;
 use serde::de::DeserializeOwned;
+use crate::codec::Codec;
 
 pub type ChatError = Box<dyn Error + Send + Sync + 'static>;
 pub type ChatResult<T> = Result<T, ChatError>;
@@ -18,15 +19,14 @@ where
   Ok(())
 }
 
-pub fn receive_as_json<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
+/// Return a stream of the JSON packets read from `inbound`, one per line.
+/// Lines longer than `codec::MAX_FRAME_LENGTH` are an error, rather than
+/// something to buffer without limit.
+pub fn receive_as_json<S, P>(inbound: S)
+  -> impl Stream<Item = ChatResult<P>> + Unpin
 where
   S: async_std::io::BufRead + Unpin,
   P: DeserializeOwned,
 {
-  inbound.lines()
-    .map(|line_result| -> ChatResult<P> {
-      let line = line_result?;
-      let parsed = serde_json::from_str::<P>(&line)?;
-      Ok(parsed)
-    })
+  Box::pin(Codec::Json.packets(inbound))
 }
\ No newline at end of file
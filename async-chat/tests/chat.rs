@@ -0,0 +1,457 @@
+//! End-to-end tests: scripted clients talking to an in-process server.
+
+mod common;
+
+use async_chat::server::group::SlowConsumerPolicy;
+use async_chat::server::group_table::GroupSettings;
+use async_chat::{FromClient, FromServer, Replay, Role};
+use async_std::task;
+use common::{limits, name, Client, TestServer};
+use std::sync::Arc;
+
+fn join(group_name: &str) -> FromClient {
+    FromClient::Join { group_name: name(group_name), replay: None }
+}
+
+fn post(group_name: &str, message: &str) -> FromClient {
+    FromClient::Post { group_name: name(group_name), message: name(message) }
+}
+
+/// Return the ID of the next group message `client` receives, failing on
+/// any other packet.
+async fn message_id(client: &mut Client) -> Arc<String> {
+    match client.receive().await {
+        Some(FromServer::Message { id, .. }) => id,
+        other => panic!("expected a message, got {:?}", other),
+    }
+}
+
+fn message(group: &str, sender: &str, text: &str) -> (String, String, String) {
+    (group.to_string(), sender.to_string(), text.to_string())
+}
+
+#[test]
+fn members_see_posts_in_order() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+
+        alice.request(post("Crabs", "one")).await.unwrap();
+        alice.request(post("Crabs", "two")).await.unwrap();
+        assert_eq!(bob.messages(2).await,
+                   [message("Crabs", "alice", "one"),
+                    message("Crabs", "alice", "two")]);
+        bob.request(post("Crabs", "three")).await.unwrap();
+
+        let expected = [message("Crabs", "alice", "one"),
+                        message("Crabs", "alice", "two"),
+                        message("Crabs", "bob", "three")];
+        assert_eq!(alice.messages(3).await, expected);
+        assert_eq!(bob.messages(1).await, expected[2..]);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn groups_are_separate() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        alice.request(join("Gophers")).await.unwrap();
+        bob.request(join("Gophers")).await.unwrap();
+
+        alice.request(post("Crabs", "for crabs")).await.unwrap();
+        alice.request(post("Gophers", "for gophers")).await.unwrap();
+        assert_eq!(bob.messages(1).await,
+                   [message("Gophers", "alice", "for gophers")]);
+
+        let groups = alice.request(FromClient::ListGroups).await.unwrap();
+        match groups {
+            Some(FromServer::Groups { mut group_names }) => {
+                group_names.sort();
+                assert_eq!(group_names, [name("Crabs"), name("Gophers")]);
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        server.stop().await;
+    });
+}
+
+#[test]
+fn late_joiners_can_replay_history() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        alice.request(join("Crabs")).await.unwrap();
+        for text in ["one", "two", "three"] {
+            alice.request(post("Crabs", text)).await.unwrap();
+        }
+
+        let mut bob = server.connect("bob").await;
+        bob.request(FromClient::Join {
+            group_name: name("Crabs"),
+            replay: Some(Replay::LastN(2)),
+        }).await.unwrap();
+        assert_eq!(bob.messages(2).await,
+                   [message("Crabs", "alice", "two"),
+                    message("Crabs", "alice", "three")]);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn authors_and_owners_may_edit_and_delete() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+        // Alice creates the group, and so owns it.
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.request(join("Crabs")).await.unwrap();
+        }
+
+        bob.request(post("Crabs", "helo")).await.unwrap();
+        let id = message_id(&mut alice).await;
+        assert_eq!(message_id(&mut bob).await, id);
+        assert_eq!(message_id(&mut carol).await, id);
+
+        let edit = |message: &str| FromClient::Edit {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            message: name(message),
+        };
+        assert!(carol.request(edit("hijacked")).await.is_err());
+        bob.request(edit("hello")).await.unwrap();
+        let edited = FromServer::Edited {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            editor: name("bob"),
+            message: name("hello"),
+        };
+        for client in [&mut alice, &mut bob, &mut carol] {
+            assert_eq!(client.receive().await.as_ref(), Some(&edited));
+        }
+
+        // Latecomers get the edited text.
+        let mut dave = server.connect("dave").await;
+        dave.request(FromClient::Join {
+            group_name: name("Crabs"),
+            replay: Some(Replay::LastN(10)),
+        }).await.unwrap();
+        assert_eq!(dave.messages(1).await, [message("Crabs", "bob", "hello")]);
+
+        let delete = FromClient::Delete { group_name: name("Crabs"),
+                                          id: id.clone() };
+        assert!(carol.request(delete.clone()).await.is_err());
+        alice.request(delete.clone()).await.unwrap();
+        let deleted = FromServer::Deleted {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            by: name("alice"),
+        };
+        for client in [&mut alice, &mut bob, &mut carol, &mut dave] {
+            assert_eq!(client.receive().await.as_ref(), Some(&deleted));
+        }
+        assert!(bob.request(edit("too late")).await.is_err());
+
+        // ... and don't see deleted messages at all.
+        let mut erin = server.connect("erin").await;
+        erin.request(FromClient::Join {
+            group_name: name("Crabs"),
+            replay: Some(Replay::LastN(10)),
+        }).await.unwrap();
+        bob.request(post("Crabs", "after")).await.unwrap();
+        assert_eq!(erin.messages(1).await, [message("Crabs", "bob", "after")]);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn members_see_reactions() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+        alice.request(post("Crabs", "shipped it")).await.unwrap();
+        let id = message_id(&mut alice).await;
+        assert_eq!(message_id(&mut bob).await, id);
+
+        let react = |id: &Arc<String>, reaction: &str| FromClient::React {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            reaction: name(reaction),
+        };
+        bob.request(react(&id, "🎉")).await.unwrap();
+        let reacted = FromServer::Reacted {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            sender: name("bob"),
+            reaction: name("🎉"),
+        };
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(client.receive().await.as_ref(), Some(&reacted));
+        }
+
+        assert!(bob.request(react(&name("no-such-id"), "🎉")).await.is_err());
+        assert!(bob.request(react(&id, "")).await.is_err());
+        assert!(bob.request(react(&id, "far too long a reaction")).await
+                   .is_err());
+        server.stop().await;
+    });
+}
+
+#[test]
+fn members_see_presence_and_typing() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let presence = |members: &[&str]| FromServer::Presence {
+            group_name: name("Crabs"),
+            members: members.iter().map(|member| name(member)).collect(),
+        };
+        let joined = |nickname: &str| FromServer::Joined {
+            group_name: name("Crabs"),
+            nickname: name(nickname),
+        };
+        let left = |nickname: &str| FromServer::Left {
+            group_name: name("Crabs"),
+            nickname: name(nickname),
+        };
+
+        alice.request(join("Crabs")).await.unwrap();
+        assert_eq!(alice.presence().await, presence(&["alice"]));
+        bob.request(join("Crabs")).await.unwrap();
+        assert_eq!(bob.presence().await, presence(&["alice", "bob"]));
+        assert_eq!(alice.presence().await, joined("bob"));
+
+        // Typing reaches the others once; more keystrokes soon after are
+        // not worth telling them about.
+        let typing = FromClient::Typing { group_name: name("Crabs") };
+        alice.request(typing.clone()).await.unwrap();
+        alice.request(typing.clone()).await.unwrap();
+        assert_eq!(bob.presence().await, FromServer::Typing {
+            group_name: name("Crabs"),
+            nickname: name("alice"),
+        });
+
+        // Members are told both when someone leaves and when someone's
+        // connection goes away.
+        let mut carol = server.connect("carol").await;
+        carol.request(join("Crabs")).await.unwrap();
+        assert_eq!(carol.presence().await,
+                   presence(&["alice", "bob", "carol"]));
+        drop(carol);
+        bob.request(FromClient::Leave { group_name: name("Crabs") }).await
+            .unwrap();
+        // Had alice been told of her own typing, that would come first.
+        assert_eq!(alice.presence().await, joined("carol"));
+        let mut departures = vec![alice.presence().await,
+                                  alice.presence().await];
+        departures.sort_by_key(|packet| format!("{:?}", packet));
+        assert_eq!(departures, [left("bob"), left("carol")]);
+
+        assert!(bob.request(typing).await.is_err());
+        server.stop().await;
+    });
+}
+
+#[test]
+fn members_who_lose_their_role_are_expelled() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let set_role = |nickname: Option<&str>, role| FromClient::SetRole {
+            group_name: name("Crabs"),
+            nickname: nickname.map(name),
+            role,
+        };
+        let left = |nickname: &str| FromServer::Left {
+            group_name: name("Crabs"),
+            nickname: name(nickname),
+        };
+
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+        bob.presence().await;
+
+        // Closing the group to everyone without a role of their own shuts
+        // bob out at once.
+        alice.request(set_role(None, None)).await.unwrap();
+        assert_eq!(bob.presence().await, left("bob"));
+        assert!(bob.request(join("Crabs")).await.is_err());
+        alice.request(post("Crabs", "members only")).await.unwrap();
+
+        // Nor may bob still act as a member through the subscription that
+        // ended.
+        let group_name = name("Crabs");
+        assert!(bob.request(FromClient::Typing { group_name: group_name.clone() })
+                    .await.is_err());
+        assert!(bob.request(FromClient::ListMembers { group_name }).await
+                    .is_err());
+
+        // Once given a role, bob may join again, and sees only what was
+        // posted since.
+        alice.request(set_role(Some("bob"), Some(Role::ReadOnly))).await
+            .unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+        alice.request(post("Crabs", "welcome back")).await.unwrap();
+        assert_eq!(bob.messages(1).await,
+                   [message("Crabs", "alice", "welcome back")]);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn direct_messages_wait_for_absent_users() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        // We only hold messages for users we've seen before.
+        drop(server.connect("bob").await);
+        server.signed_out("bob").await;
+
+        alice.request(FromClient::DirectMessage {
+            to: name("bob"),
+            message: name("are you there?"),
+        }).await.unwrap();
+
+        let mut bob = server.connect("bob").await;
+        match bob.receive().await {
+            Some(FromServer::DirectMessage { from, message, .. }) => {
+                assert_eq!((from.as_str(), message.as_str()),
+                           ("alice", "are you there?"));
+            }
+            other => panic!("unexpected packet {:?}", other),
+        }
+        server.stop().await;
+    });
+}
+
+#[test]
+fn members_who_disconnect_leave_their_groups() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+        drop(bob);
+        server.signed_out("bob").await;
+
+        let members = alice.request(FromClient::ListMembers {
+            group_name: name("Crabs"),
+        }).await.unwrap();
+        match members {
+            Some(FromServer::Members { members, .. }) => {
+                assert_eq!(members, [name("alice")]);
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        // Bob's nickname is free again.
+        let mut bob = server.connect("bob").await;
+        bob.request(join("Crabs")).await.unwrap();
+        server.stop().await;
+    });
+}
+
+/// The number of posts, and their size, for the slow-member tests: enough
+/// to fill the kernel's socket buffers, so that a member who isn't reading
+/// falls behind.
+const FLOOD_POSTS: usize = 400;
+const FLOOD_SIZE: usize = 60_000;
+
+/// Start a server whose groups can hold only a few messages for each
+/// member, have `slow` join a group and not read, and post `FLOOD_POSTS`
+/// messages to it from another client.
+async fn flood(policy: SlowConsumerPolicy) -> (TestServer, common::Client) {
+    let settings = GroupSettings {
+        capacity: 4,
+        policy,
+        ..GroupSettings::default()
+    };
+    let server = TestServer::with(settings, limits(), 0).await;
+    let mut slow = server.connect("slow").await;
+    slow.request(join("Crabs")).await.unwrap();
+
+    // Posting doesn't require membership, so the poster needn't read its
+    // own flood.
+    let mut poster = server.connect("poster").await;
+    let text = "x".repeat(FLOOD_SIZE);
+    for _ in 0..FLOOD_POSTS {
+        poster.send(post("Crabs", &text)).await;
+    }
+    poster.request(FromClient::ListGroups).await.unwrap();
+    (server, slow)
+}
+
+#[test]
+fn slow_members_are_told_what_they_missed() {
+    task::block_on(async {
+        let (server, mut slow) = flood(SlowConsumerPolicy::DropOldest).await;
+
+        let mut received = 0;
+        let mut dropped = 0;
+        while received + dropped < FLOOD_POSTS {
+            match slow.receive().await {
+                Some(FromServer::Message { .. }) => received += 1,
+                Some(FromServer::Error { message, .. }) => {
+                    let count = message.strip_prefix("Dropped ")
+                        .and_then(|rest| rest.split(' ').next())
+                        .and_then(|count| count.parse::<usize>().ok())
+                        .unwrap_or_else(|| panic!("unexpected notice: {}", message));
+                    dropped += count;
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+        assert_eq!(received + dropped, FLOOD_POSTS);
+        assert!(dropped > 0);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn slow_members_are_disconnected_when_the_policy_says_so() {
+    task::block_on(async {
+        let (server, mut slow) = flood(SlowConsumerPolicy::Disconnect).await;
+
+        let mut received = 0;
+        let notice = loop {
+            match slow.receive().await {
+                Some(FromServer::Message { .. }) => received += 1,
+                Some(FromServer::Error { message, .. }) => break message,
+                other => panic!("unexpected packet {:?}", other),
+            }
+        };
+        assert!(notice.starts_with("Disconnected for falling"), "{}", notice);
+        assert!(received < FLOOD_POSTS);
+        assert_eq!(slow.receive().await, None);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn shutting_down_says_goodbye() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        server.stop().await;
+
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(client.receive().await, Some(FromServer::Shutdown));
+            assert_eq!(client.receive().await, None);
+        }
+    });
+}
@@ -0,0 +1,94 @@
+//! End-to-end tests of the client library, against an in-process server.
+
+mod common;
+
+use async_chat::client::{ChatClient, Dispatcher, Rejected};
+use async_chat::{FromClient, FromServer};
+use async_std::prelude::*;
+use async_std::task;
+use common::{name, TestServer};
+
+#[test]
+fn clients_post_and_receive() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let address = server.address.to_string();
+        let mut alice = ChatClient::connect(&address, "alice").await.unwrap();
+        let mut bob = ChatClient::connect(&address, "bob").await.unwrap();
+        alice.join("Crabs").await.unwrap();
+        bob.join("Crabs").await.unwrap();
+
+        alice.typing("Crabs").await.unwrap();
+        let typing = bob
+            .find(|packet| matches!(packet, Ok(FromServer::Typing { .. })))
+            .await;
+        match typing {
+            Some(Ok(FromServer::Typing { nickname, .. })) => {
+                assert_eq!(nickname, name("alice"));
+            }
+            other => panic!("expected typing, got {:?}", other),
+        }
+
+        alice.post("Crabs", "hello").await.unwrap();
+        let mut packets = (&mut bob)
+            .filter(|packet| !matches!(packet, Ok(packet) if packet.is_presence()));
+        match packets.next().await {
+            Some(Ok(FromServer::Message { sender, message, .. })) => {
+                assert_eq!((sender, message), (name("alice"), name("hello")));
+            }
+            other => panic!("expected a message, got {:?}", other),
+        }
+
+        let error = bob.post("Gophers", "anyone?").await.unwrap_err();
+        let rejected = error.downcast_ref::<Rejected>()
+            .expect("refusals should be reported as `Rejected`");
+        assert!(rejected.reason.contains("Gophers"), "{}", rejected.reason);
+        server.stop().await;
+    });
+}
+
+#[test]
+fn bots_answer_commands() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let address = server.address.to_string();
+        let mut bot = ChatClient::connect(&address, "bot").await.unwrap();
+        bot.join("Crabs").await.unwrap();
+        let commands = Dispatcher::new("!")
+            .command("echo", "repeat", |call| Some(call.args.clone()));
+        let running = task::spawn(async move {
+            commands.run(&mut bot, |_packet| {}).await
+        });
+
+        let mut alice = server.connect("alice").await;
+        alice.request(FromClient::Join {
+            group_name: name("Crabs"),
+            replay: None,
+        }).await.unwrap();
+
+        let post = |message: &str| FromClient::Post {
+            group_name: name("Crabs"),
+            message: name(message),
+        };
+        let from = |sender: &str, message: &str| {
+            ("Crabs".to_string(), sender.to_string(), message.to_string())
+        };
+        alice.request(post("!echo !echo hi")).await.unwrap();
+        assert_eq!(alice.messages(2).await,
+                   [from("alice", "!echo !echo hi"), from("bot", "!echo hi")]);
+
+        // The bot's answer looks like a command, but the bot ignores its
+        // own posts, so the next thing it says answers this.
+        // A reply the server refuses, like this empty one, doesn't stop the
+        // bot answering the next command.
+        alice.request(post("!echo")).await.unwrap();
+        assert_eq!(alice.messages(1).await, [from("alice", "!echo")]);
+
+        alice.request(post("!echo done")).await.unwrap();
+        assert_eq!(alice.messages(2).await,
+                   [from("alice", "!echo done"), from("bot", "done")]);
+
+        server.stop().await;
+        running.await.unwrap();
+    });
+}
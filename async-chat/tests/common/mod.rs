@@ -0,0 +1,321 @@
+//! A harness for testing the server from the outside: start one in-process
+//! on an ephemeral port, connect scripted clients to it, and look at what
+//! each of them receives.
+
+// Each test file compiles its own copy of this module, and none uses all of it.
+#![allow(dead_code)]
+
+use async_chat::codec::Codec;
+use async_chat::server::{self, Server};
+use async_chat::server::access::AccessTable;
+use async_chat::server::connection_table::ConnectionTable;
+use async_chat::server::federation::{self, Federation};
+use async_chat::server::group_table::{GroupSettings, GroupTable};
+use async_chat::server::rate_limit::Limits;
+use async_chat::server::user_table::UserTable;
+use async_chat::utils::ChatResult;
+use async_chat::{FromClient, FromServer, Request};
+use async_std::channel;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a client waits for a packet before the test fails.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits loose enough to stay out of the way of tests about other things.
+pub fn limits() -> Limits {
+    Limits {
+        connection: None,
+        max_strikes: 0,
+        max_message_size: 64 * 1024,
+        max_frame_size: 1024 * 1024,
+        max_group_name_length: 64,
+    }
+}
+
+pub fn name(name: &str) -> Arc<String> {
+    Arc::new(name.to_string())
+}
+
+/// The secret every `TestServer::named` server shares with its peers.
+const PEER_SECRET: &str = "open sesame";
+
+/// A server running in this process, until `stop` is called.
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub server: Arc<Server>,
+    stop: channel::Sender<()>,
+    stopped: channel::Receiver<()>,
+    accepting: Vec<task::JoinHandle<ChatResult<()>>>,
+    links: Vec<task::JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        TestServer::with(GroupSettings::default(), limits(), 100).await
+    }
+
+    /// Start a server with the given group settings and limits, holding up
+    /// to `offline_queue_limit` direct messages for each absent user.
+    pub async fn with(settings: GroupSettings,
+                      limits: Limits,
+                      offline_queue_limit: usize)
+                      -> TestServer {
+        let federation = Federation::new("test", None);
+        TestServer::with_federation(settings, limits, offline_queue_limit,
+                                    federation).await
+    }
+
+    /// Start a server called `server_name`, which accepts other named
+    /// servers as peers.
+    pub async fn named(server_name: &str) -> TestServer {
+        let federation = Federation::new(server_name,
+                                         Some(PEER_SECRET.to_string()));
+        TestServer::with_federation(GroupSettings::default(), limits(), 100,
+                                    federation).await
+    }
+
+    async fn with_federation(settings: GroupSettings,
+                             limits: Limits,
+                             offline_queue_limit: usize,
+                             federation: Federation)
+                             -> TestServer {
+        let server = Arc::new(Server {
+            groups: Arc::new(GroupTable::new(settings, AccessTable::in_memory())),
+            users: UserTable::new(offline_queue_limit),
+            connections: Arc::new(ConnectionTable::new()),
+            limits,
+            accounts: None,
+            federation: Arc::new(federation),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop, stopped) = channel::bounded(1);
+        let accepting = task::spawn(server::accept_loop(listener, None, false,
+                                                        server.clone(),
+                                                        stopped.clone()));
+        TestServer {
+            address,
+            server,
+            stop,
+            stopped,
+            accepting: vec![accepting],
+            links: vec![],
+        }
+    }
+
+    /// Accept TLS connections too, on another port, and return its address.
+    pub async fn listen_with_tls(&mut self, acceptor: TlsAcceptor)
+                                 -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        self.accepting.push(task::spawn(server::accept_loop(
+            listener,
+            Some(acceptor),
+            false,
+            self.server.clone(),
+            self.stopped.clone(),
+        )));
+        address
+    }
+
+    /// Link this server to `other` as peers, and wait until both sides have
+    /// the link in place.
+    pub async fn link_to(&mut self, other: &TestServer) {
+        self.link_over(other, other.address.to_string(), None).await;
+    }
+
+    /// Like `link_to`, but dial `other` at `address`, using TLS if
+    /// `connector` is given.
+    pub async fn link_over(&mut self,
+                           other: &TestServer,
+                           address: String,
+                           connector: Option<TlsConnector>) {
+        let ours = self.server.federation.peer_names().len();
+        let theirs = other.server.federation.peer_names().len();
+        self.links.push(task::spawn(federation::link_to_peer(
+            address,
+            connector,
+            self.server.clone(),
+            self.stopped.clone(),
+        )));
+        async {
+            while self.server.federation.peer_names().len() == ours
+                || other.server.federation.peer_names().len() == theirs
+            {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        .timeout(RECEIVE_TIMEOUT)
+        .await
+        .expect("timed out linking servers");
+    }
+
+    /// Connect a client and say hello as `nickname`.
+    pub async fn connect(&self, nickname: &str) -> Client {
+        let mut client = Client::connect(self.address).await;
+        client.request(FromClient::Hello { nickname: name(nickname) })
+            .await
+            .unwrap();
+        client
+    }
+
+    /// Wait until no connection is signed in as `nickname`. The server
+    /// notices hang-ups on its own time, so tests that care must wait.
+    pub async fn signed_out(&self, nickname: &str) {
+        let connections = &self.server.connections;
+        while !connections.signed_in_as(nickname).is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Stop accepting connections, and shut down the ones that are open.
+    pub async fn stop(self) {
+        self.stop.close();
+        for accepting in self.accepting {
+            accepting.await.unwrap();
+        }
+        for link in self.links {
+            link.await;
+        }
+        server::shut_down(&self.server.connections).await;
+    }
+}
+
+type Replies = Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>;
+
+/// A client connected to a `TestServer`, speaking JSON.
+pub struct Client {
+    socket: TcpStream,
+    replies: Replies,
+    /// Packets that arrived while `request` was waiting for its answer.
+    pending: VecDeque<FromServer>,
+    /// News of who is present or typing, kept apart so that tests about
+    /// other things needn't wade through it.
+    presence: VecDeque<FromServer>,
+    next_id: u64,
+}
+
+impl Client {
+    pub async fn connect(address: SocketAddr) -> Client {
+        let socket = TcpStream::connect(address).await.unwrap();
+        socket.set_nodelay(true).unwrap();
+        let replies = Codec::Json.packets(BufReader::new(socket.clone()));
+        Client {
+            socket,
+            replies: Box::pin(replies),
+            pending: VecDeque::new(),
+            presence: VecDeque::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Send `body` without waiting for an answer, and return its ID.
+    pub async fn send(&mut self, body: FromClient) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        Codec::Json.send(&mut self.socket, &Request { id, body }).await.unwrap();
+        id
+    }
+
+    /// Send `body` and wait for the server's answer: `Ok` with any reply
+    /// that came before the `Ack`, or `Err` with the `Nack`'s reason. Other
+    /// packets that arrive meanwhile are kept for `receive`.
+    pub async fn request(&mut self, body: FromClient)
+                         -> Result<Option<FromServer>, String> {
+        let id = self.send(body).await;
+        let mut reply = None;
+        loop {
+            match self.next_packet().await {
+                Some(FromServer::Ack { id: acked }) if acked == id => {
+                    return Ok(reply);
+                }
+                Some(FromServer::Nack { id: rejected, reason }) if rejected == id => {
+                    return Err(reason);
+                }
+                Some(packet @ FromServer::Groups { .. }) |
+                Some(packet @ FromServer::Members { .. }) |
+                Some(packet @ FromServer::SearchResults { .. }) => {
+                    reply = Some(packet)
+                }
+                Some(packet) => self.pending.push_back(packet),
+                None => panic!("connection closed awaiting answer to request {}", id),
+            }
+        }
+    }
+
+    /// Return the next packet that isn't an answer to a request, or `None`
+    /// if the server closed the connection.
+    pub async fn receive(&mut self) -> Option<FromServer> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Some(packet);
+        }
+        loop {
+            match self.next_packet().await {
+                Some(FromServer::Ack { .. }) => continue,
+                other => return other,
+            }
+        }
+    }
+
+    /// Return the next `count` group messages, as (group, sender, message)
+    /// triples, failing on any other packet.
+    pub async fn messages(&mut self, count: usize) -> Vec<(String, String, String)> {
+        let mut messages = vec![];
+        while messages.len() < count {
+            match self.receive().await {
+                Some(FromServer::Message { group_name, sender, message, .. }) => {
+                    messages.push((group_name.to_string(), sender.to_string(),
+                                   message.to_string()));
+                }
+                other => panic!("expected a message, got {:?}", other),
+            }
+        }
+        messages
+    }
+
+    /// Return the next presence, join, leave or typing packet, keeping
+    /// anything else that arrives meanwhile for `receive`.
+    pub async fn presence(&mut self) -> FromServer {
+        loop {
+            if let Some(packet) = self.presence.pop_front() {
+                return packet;
+            }
+            match self.next_any().await {
+                Some(packet) if packet.is_presence() => return packet,
+                Some(FromServer::Ack { .. }) => {}
+                Some(packet) => self.pending.push_back(packet),
+                None => panic!("connection closed awaiting presence"),
+            }
+        }
+    }
+
+    /// Return the next packet from the server, setting aside presence
+    /// packets for `presence`.
+    async fn next_packet(&mut self) -> Option<FromServer> {
+        loop {
+            match self.next_any().await {
+                Some(packet) if packet.is_presence() => {
+                    self.presence.push_back(packet);
+                }
+                other => return other,
+            }
+        }
+    }
+
+    async fn next_any(&mut self) -> Option<FromServer> {
+        self.replies.next()
+            .timeout(RECEIVE_TIMEOUT)
+            .await
+            .expect("timed out waiting for a packet")
+            .map(Result::unwrap)
+    }
+}
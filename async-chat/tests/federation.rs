@@ -0,0 +1,208 @@
+//! End-to-end tests of federation: servers in one process, linked as peers
+//! over localhost, relaying posts between their clients.
+
+mod common;
+
+use async_chat::{tls, FromClient, FromServer};
+use async_std::task;
+use common::{name, Client, TestServer};
+use std::sync::Arc;
+
+fn join(group_name: &str) -> FromClient {
+    FromClient::Join { group_name: name(group_name), replay: None }
+}
+
+fn post(group_name: &str, message: &str) -> FromClient {
+    FromClient::Post { group_name: name(group_name), message: name(message) }
+}
+
+/// Return the next group message `client` receives, as (sender, message,
+/// ID), failing on any other packet.
+async fn next_message(client: &mut Client) -> (String, String, Arc<String>) {
+    match client.receive().await {
+        Some(FromServer::Message { sender, message, id, .. }) => {
+            (sender.to_string(), message.to_string(), id)
+        }
+        other => panic!("expected a message, got {:?}", other),
+    }
+}
+
+#[test]
+fn peers_relay_posts_both_ways() {
+    task::block_on(async {
+        let mut east = TestServer::named("east").await;
+        let west = TestServer::named("west").await;
+        east.link_to(&west).await;
+
+        let mut alice = east.connect("alice").await;
+        let mut bob = west.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+
+        alice.request(post("Crabs", "from the east")).await.unwrap();
+        let seen_by_alice = next_message(&mut alice).await;
+        let seen_by_bob = next_message(&mut bob).await;
+        assert_eq!(seen_by_alice.0, "alice");
+        assert_eq!(seen_by_alice.1, "from the east");
+        assert_eq!(seen_by_bob, seen_by_alice,
+                   "both servers should deliver the post under the same ID");
+
+        // A post to a group the other server doesn't have goes nowhere
+        // there, and doesn't create it.
+        bob.request(join("Gophers")).await.unwrap();
+        bob.request(post("Gophers", "anyone?")).await.unwrap();
+        bob.request(post("Crabs", "from the west")).await.unwrap();
+        let (sender, text, id) = next_message(&mut alice).await;
+        assert_eq!((sender.as_str(), text.as_str()), ("bob", "from the west"));
+        assert_ne!(id, seen_by_alice.2);
+        assert!(east.server.groups.get(&"Gophers".to_string()).is_none());
+
+        west.stop().await;
+        east.stop().await;
+    });
+}
+
+#[test]
+fn relayed_posts_arrive_once_around_a_cycle() {
+    task::block_on(async {
+        let mut first = TestServer::named("first").await;
+        let mut second = TestServer::named("second").await;
+        let mut third = TestServer::named("third").await;
+        first.link_to(&second).await;
+        second.link_to(&third).await;
+        third.link_to(&first).await;
+
+        let mut alice = first.connect("alice").await;
+        let mut bob = second.connect("bob").await;
+        let mut carol = third.connect("carol").await;
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.request(join("Crabs")).await.unwrap();
+        }
+
+        alice.request(post("Crabs", "one")).await.unwrap();
+        alice.request(post("Crabs", "two")).await.unwrap();
+        carol.request(post("Crabs", "three")).await.unwrap();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            let mut texts = vec![];
+            for _ in 0..3 {
+                texts.push(next_message(client).await.1);
+            }
+            texts.sort();
+            assert_eq!(texts, ["one", "three", "two"]);
+        }
+
+        // Had any of those come around the cycle a second time, it would
+        // arrive before this.
+        bob.request(post("Crabs", "four")).await.unwrap();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            assert_eq!(next_message(client).await.1, "four");
+        }
+
+        third.stop().await;
+        second.stop().await;
+        first.stop().await;
+    });
+}
+
+#[test]
+fn relayed_posts_may_only_be_changed_by_owners() {
+    task::block_on(async {
+        let mut east = TestServer::named("east").await;
+        let west = TestServer::named("west").await;
+        east.link_to(&west).await;
+
+        // Alice creates the group on the east, so she owns it there. Each
+        // server has a bob of its own.
+        let mut alice = east.connect("alice").await;
+        let mut impostor = east.connect("bob").await;
+        let mut bob = west.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        impostor.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+
+        bob.request(post("Crabs", "the real bob")).await.unwrap();
+        let (_, _, id) = next_message(&mut alice).await;
+        assert_eq!(next_message(&mut impostor).await.2, id);
+        next_message(&mut bob).await;
+
+        // The east's bob shares the author's nickname, but not their server.
+        let edit = FromClient::Edit {
+            group_name: name("Crabs"),
+            id: id.clone(),
+            message: name("the fake bob"),
+        };
+        let delete = FromClient::Delete { group_name: name("Crabs"), id };
+        assert!(impostor.request(edit.clone()).await.is_err());
+        assert!(impostor.request(delete.clone()).await.is_err());
+
+        // On the west, where bob posted it, bob may change it; on the east,
+        // only an owner may.
+        bob.request(edit).await.unwrap();
+        alice.request(delete).await.unwrap();
+
+        west.stop().await;
+        east.stop().await;
+    });
+}
+
+#[test]
+fn peers_may_link_over_tls() {
+    task::block_on(async {
+        let directory = tempfile::tempdir().unwrap();
+        let generated = rcgen::generate_simple_self_signed(
+            vec!["localhost".to_string()]
+        ).unwrap();
+        let certificate_path = directory.path().join("cert.pem");
+        let key_path = directory.path().join("key.pem");
+        std::fs::write(&certificate_path, generated.cert.pem()).unwrap();
+        std::fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+
+        let mut east = TestServer::named("east").await;
+        let mut west = TestServer::named("west").await;
+        let acceptor = tls::acceptor(&certificate_path, &key_path).unwrap();
+        let address = west.listen_with_tls(acceptor).await;
+        let connector = tls::connector(&certificate_path).unwrap();
+        east.link_over(&west, format!("localhost:{}", address.port()),
+                       Some(connector)).await;
+
+        let mut alice = east.connect("alice").await;
+        let mut bob = west.connect("bob").await;
+        alice.request(join("Crabs")).await.unwrap();
+        bob.request(join("Crabs")).await.unwrap();
+        bob.request(post("Crabs", "sealed")).await.unwrap();
+        let (sender, text, _) = next_message(&mut alice).await;
+        assert_eq!((sender.as_str(), text.as_str()), ("bob", "sealed"));
+
+        west.stop().await;
+        east.stop().await;
+    });
+}
+
+#[test]
+fn only_peers_may_relay() {
+    task::block_on(async {
+        let server = TestServer::named("east").await;
+        let mut mallory = Client::connect(server.address).await;
+        let peer = |secret: &str| FromClient::Peer {
+            server_name: name("west"),
+            secret: secret.to_string(),
+        };
+        assert!(mallory.request(peer("guess")).await.is_err());
+
+        let relay = FromClient::Relay {
+            group_name: name("Crabs"),
+            id: name("west:1:1"),
+            sender: name("mallory"),
+            timestamp: 0,
+            message: name("forged"),
+        };
+        assert!(mallory.request(relay).await.is_err());
+        server.stop().await;
+
+        // A server without a peer secret accepts no peers at all.
+        let closed = TestServer::start().await;
+        let mut mallory = Client::connect(closed.address).await;
+        assert!(mallory.request(peer("")).await.is_err());
+        closed.stop().await;
+    });
+}
@@ -0,0 +1,150 @@
+//! End-to-end tests of searching group history.
+
+mod common;
+
+use async_chat::server::group_table::GroupSettings;
+use async_chat::{FromClient, FromServer, Replay, SearchResult};
+use async_std::task;
+use common::{limits, name, Client, TestServer};
+
+/// How many messages the test group retains.
+const RETAINED: usize = 2000;
+
+/// The text of synthetic post `n`. Each of its three words recurs on its own
+/// cycle, so that any two of them appear together in a predictable subset
+/// of the posts.
+fn text(n: usize) -> String {
+    let colors = ["red", "green", "blue", "amber", "violet"];
+    let animals = ["crab", "gopher", "ferris", "snail", "eel", "owl", "yak"];
+    format!("{}: the {} {}!", n, colors[n % 5], animals[n % 7].to_uppercase())
+}
+
+async fn search(client: &mut Client, query: &str, limit: usize)
+                -> Result<Vec<SearchResult>, String> {
+    let reply = client.request(FromClient::Search {
+        group_name: name("Crabs"),
+        query: name(query),
+        limit,
+    }).await?;
+    match reply {
+        Some(FromServer::SearchResults { group_name, results }) => {
+            assert_eq!(*group_name, "Crabs");
+            Ok(results)
+        }
+        other => panic!("expected search results, got {:?}", other),
+    }
+}
+
+fn texts(results: &[SearchResult]) -> Vec<String> {
+    results.iter().map(|result| result.message.to_string()).collect()
+}
+
+#[test]
+fn searches_find_retained_messages_newest_first() {
+    task::block_on(async {
+        let server = TestServer::with(GroupSettings {
+            history_limit: RETAINED,
+            ..GroupSettings::default()
+        }, limits(), 100).await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let join = FromClient::Join { group_name: name("Crabs"), replay: None };
+        alice.request(join.clone()).await.unwrap();
+
+        // Post more than the group retains, so that the oldest age out.
+        let posts = RETAINED + 1000;
+        for n in 0..posts {
+            alice.request(FromClient::Post {
+                group_name: name("Crabs"),
+                message: name(&text(n)),
+            }).await.unwrap();
+        }
+        let messages = alice.messages(posts).await;
+        assert_eq!(messages.len(), posts);
+
+        // Working through the retained posts from the newest, as the index
+        // should.
+        let expected = |matches: &dyn Fn(usize) -> bool, limit: usize| {
+            (posts - RETAINED..posts).rev()
+                .filter(|&n| matches(n))
+                .take(limit)
+                .map(text)
+                .collect::<Vec<_>>()
+        };
+        let found = search(&mut alice, "crab", 100).await.unwrap();
+        assert_eq!(texts(&found), expected(&|n| n % 7 == 0, 100));
+        let found = search(&mut alice, "Blue crab", 1000).await.unwrap();
+        assert_eq!(texts(&found), expected(&|n| n % 35 == 7, 1000));
+        assert!(!found.is_empty());
+        let found = search(&mut alice, &format!("{}", posts - 1), 10).await
+            .unwrap();
+        assert_eq!(texts(&found), [text(posts - 1)]);
+
+        // A message that aged out can't be found; nor can one that never was.
+        assert!(search(&mut alice, "0", 10).await.unwrap().is_empty());
+        assert!(search(&mut alice, "crab lobster", 10).await.unwrap()
+                    .is_empty());
+
+        // Results carry the same IDs and timestamps as the messages did.
+        let newest = search(&mut alice, "the", 1).await.unwrap();
+        bob.request(FromClient::Join {
+            group_name: name("Crabs"),
+            replay: Some(Replay::LastN(1)),
+        }).await.unwrap();
+        match bob.receive().await {
+            Some(FromServer::Message { id, timestamp, message, .. }) => {
+                assert_eq!(newest, [SearchResult {
+                    id,
+                    sender: name("alice"),
+                    timestamp,
+                    message,
+                }]);
+            }
+            other => panic!("expected a message, got {:?}", other),
+        }
+
+        // The server caps how many results it returns, and only members
+        // may search.
+        assert_eq!(search(&mut bob, "the", 1_000_000).await.unwrap().len(),
+                   100);
+        let mut carol = server.connect("carol").await;
+        assert!(search(&mut carol, "crab", 10).await.is_err());
+        carol.request(join).await.unwrap();
+        assert!(search(&mut carol, " ?! ", 10).await.unwrap().is_empty());
+        assert!(search(&mut carol, "  ", 10).await.is_err());
+        server.stop().await;
+    });
+}
+
+#[test]
+fn expelled_members_may_not_search() {
+    task::block_on(async {
+        let server = TestServer::start().await;
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let join = FromClient::Join { group_name: name("Crabs"), replay: None };
+        alice.request(join.clone()).await.unwrap();
+        bob.request(join).await.unwrap();
+        bob.presence().await;
+
+        alice.request(FromClient::SetRole {
+            group_name: name("Crabs"),
+            nickname: None,
+            role: None,
+        }).await.unwrap();
+        assert_eq!(bob.presence().await, FromServer::Left {
+            group_name: name("Crabs"),
+            nickname: name("bob"),
+        });
+
+        // Nothing posted after bob was expelled can be found by searching.
+        alice.request(FromClient::Post {
+            group_name: name("Crabs"),
+            message: name("secret plans"),
+        }).await.unwrap();
+        assert_eq!(texts(&search(&mut alice, "secret", 10).await.unwrap()),
+                   ["secret plans"]);
+        assert!(search(&mut bob, "secret", 10).await.is_err());
+        server.stop().await;
+    });
+}